@@ -5,7 +5,7 @@ use {
 
 fn stdout(reference: &str) -> String {
   let output = Command::new(executable_path("prerelease"))
-    .args(&["--reference", reference])
+    .args(["--reference", reference])
     .output()
     .unwrap();
 
@@ -40,8 +40,7 @@ fn main() {
   let version = metadata
     .packages
     .into_iter()
-    .filter(|package| package.name == "opuza")
-    .next()
+    .find(|package| package.name == "opuza")
     .unwrap()
     .version;
 
@@ -37,6 +37,7 @@ impl From<SubaddressData> for OpuzaInvoice {
 #[derive(Debug, Clone)]
 pub struct MoneroRpcClient {
   inner: String,
+  account: u32,
   #[cfg(test)]
   _lnd_test_context: Arc<LndTestContext>,
 }
@@ -50,11 +51,31 @@ impl MoneroRpcClient {
 
     MoneroRpcClient {
       inner,
+      account: 0,
       #[cfg(test)]
       _lnd_test_context: Arc::new(lnd_test_context),
     }
   }
 
+  /// A client that creates invoices in, and scans payments to, the wallet
+  /// account with index `account`, instead of the primary account.
+  pub fn with_account(self, account: u32) -> MoneroRpcClient {
+    MoneroRpcClient { account, ..self }
+  }
+
+  pub fn account(&self) -> u32 {
+    self.account
+  }
+
+  /// The wallet attribute that the last scanned block height of the account
+  /// is stored in.
+  fn block_height_attribute(&self) -> String {
+    match self.account {
+      0 => "block_height".to_string(),
+      account => format!("block_height_{}", account),
+    }
+  }
+
   pub async fn ping(&self) -> Result<(), OpuzaRpcError> {
     let daemon_client = monero_rpc::RpcClientBuilder::new()
       .build(self.inner.clone())
@@ -87,7 +108,10 @@ impl MoneroRpcClient {
     monero_invoice.memo = memo.to_owned();
 
     let (address, index) = wallet_rpc
-      .create_address(0, Some(serde_json::to_string(&monero_invoice).unwrap()))
+      .create_address(
+        self.account,
+        Some(serde_json::to_string(&monero_invoice).unwrap()),
+      )
       .await
       .map_err(|_| OpuzaRpcError)?;
 
@@ -116,7 +140,7 @@ impl MoneroRpcClient {
     wallet_rpc
       .label_address(
         Index {
-          major: 0,
+          major: self.account,
           minor: index,
         },
         label,
@@ -178,9 +202,10 @@ impl MoneroRpcClient {
 
     let mut transfer_selector = GetTransfersSelector::default();
     transfer_selector.category_selector = category_selector;
+    transfer_selector.account_index = Some(self.account);
 
     let last_block_height = wallet_rpc
-      .get_attribute("block_height".to_string())
+      .get_attribute(self.block_height_attribute())
       .await
       .map_err(|_| OpuzaRpcError);
 
@@ -213,7 +238,9 @@ impl MoneroRpcClient {
     for (_transfer_category, transfers) in transfers.unwrap().into_iter() {
       for transfer in transfers.iter() {
         let address_filter = vec![transfer.subaddr_index.minor];
-        let address = wallet_rpc.get_address(0, Some(address_filter)).await;
+        let address = wallet_rpc
+          .get_address(self.account, Some(address_filter))
+          .await;
 
         let address_tmp = address.unwrap();
         let sub_address = address_tmp.addresses.get(0).ok_or_else(|| OpuzaRpcError);
@@ -268,7 +295,7 @@ impl MoneroRpcClient {
             // Save the metadata we need later on as serialized data in the wallet
             let label = serde_json::to_string(&monero_invoice).unwrap();
             let index = Index {
-              major: 0,
+              major: self.account,
               minor: transfer.subaddr_index.minor.clone(),
             };
             let _result = wallet_rpc
@@ -287,7 +314,10 @@ impl MoneroRpcClient {
       // All transactions have been processed up until update_block_height
       // This is mechanism is mainly because of unlock_time
       wallet_rpc
-        .set_attribute("block_height".to_string(), current_block_height.to_string())
+        .set_attribute(
+          self.block_height_attribute(),
+          current_block_height.to_string(),
+        )
         .await
         .map_err(|_| OpuzaRpcError)?;
     }
@@ -8,7 +8,7 @@ use {
   std::fmt::{self, Display, Formatter},
 };
 
-#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Serialize)]
 pub struct Piconero(u64);
 
 impl Piconero {
@@ -67,18 +67,42 @@ impl<'de> Visitor<'de> for PiconeroVisitor {
 
 impl Display for Piconero {
   fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-    use num_format::{Locale, ToFormattedString};
+    self.display_in(&num_format::Locale::en).fmt(f)
+  }
+}
+
+impl Piconero {
+  /// Display with the digit grouping and decimal separator of `locale`.
+  pub fn display_in(self, locale: &'static num_format::Locale) -> DisplayIn {
+    DisplayIn {
+      piconero: self,
+      locale,
+    }
+  }
+}
+
+pub struct DisplayIn {
+  piconero: Piconero,
+  locale: &'static num_format::Locale,
+}
+
+impl Display for DisplayIn {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    use num_format::ToFormattedString;
 
     const BASE: u64 = 1000000000000;
 
-    write!(f, "{}", (self.0 / BASE).to_formatted_string(&Locale::en))?;
+    let value = self.piconero.0;
+
+    write!(f, "{}", (value / BASE).to_formatted_string(self.locale))?;
 
-    let piconero = self.0 % BASE;
+    let piconero = value % BASE;
 
     if piconero > 0 {
       write!(
         f,
-        ".{}",
+        "{}{}",
+        self.locale.decimal(),
         ((piconero as f64) / (BASE as f64))
           .to_string()
           .strip_prefix("0.")
@@ -186,6 +210,16 @@ mod tests {
     );
   }
 
+  #[test]
+  fn display_in_locale() {
+    assert_eq!(
+      Piconero::new(1_000_123_000_000_000)
+        .display_in(&num_format::Locale::de)
+        .to_string(),
+      "1.000,123 XMR"
+    );
+  }
+
   #[test]
   fn from_string_decimal() {
     assert_eq!(
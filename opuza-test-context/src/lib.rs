@@ -5,8 +5,9 @@ use {
   std::{
     fs,
     io::{BufRead, BufReader, Read},
+    mem,
     path::{Path, PathBuf},
-    process::{Child, ChildStderr, Command, Stdio},
+    process::{Child, ChildStderr, Command, ExitStatus, Stdio},
   },
   tempfile::TempDir,
 };
@@ -18,7 +19,7 @@ pub struct OpuzaTestContext {
   files_directory: PathBuf,
   files_url: Url,
   port: u16,
-  stderr: ChildStderr,
+  stderr: BufReader<ChildStderr>,
   tempdir: TempDir,
 }
 
@@ -64,7 +65,11 @@ impl OpuzaTestContext {
       .stderr
       .read_to_string(&mut self.collected_stderr)
       .unwrap();
-    self.collected_stderr
+    mem::take(&mut self.collected_stderr)
+  }
+
+  pub fn pid(&self) -> u32 {
+    self.child.id()
   }
 
   pub fn port(&self) -> u16 {
@@ -107,6 +112,15 @@ impl OpuzaTestContext {
     self.get(url).text().unwrap()
   }
 
+  pub fn wait(mut self) -> (ExitStatus, String) {
+    let status = self.child.wait().unwrap();
+    self
+      .stderr
+      .read_to_string(&mut self.collected_stderr)
+      .unwrap();
+    (status, mem::take(&mut self.collected_stderr))
+  }
+
   pub fn write(&self, path: &str, content: &str) -> PathBuf {
     let path = self.files_directory().join(path);
     std::fs::create_dir_all(path.parent().unwrap()).unwrap();
@@ -115,6 +129,13 @@ impl OpuzaTestContext {
   }
 }
 
+impl Drop for OpuzaTestContext {
+  fn drop(&mut self) {
+    self.child.kill().ok();
+    self.child.wait().ok();
+  }
+}
+
 pub struct Builder {
   address: Option<String>,
   args: Vec<String>,
@@ -168,7 +189,7 @@ impl Builder {
 
     if let Some(http_port) = self.http_port {
       command.arg("--http-port");
-      command.arg(&http_port.to_string());
+      command.arg(http_port.to_string());
     }
 
     command
@@ -192,8 +213,8 @@ impl Builder {
     let port_string = first_line
       .trim()
       .trim_end_matches('`')
-      .split(':')
-      .last()
+      .rsplit(':')
+      .next()
       .unwrap_or_else(|| {
         panic!(
           "first line to stderr does not contain `:` and port: {}",
@@ -215,7 +236,7 @@ impl Builder {
       files_directory,
       files_url,
       port,
-      stderr: child_stderr.into_inner(),
+      stderr: child_stderr,
       tempdir: self.tempdir,
     }
   }
@@ -0,0 +1,255 @@
+use {
+  crate::{common::*, sites::SitesConfig},
+  rustls_acme::{
+    acme::ACME_TLS_ALPN_NAME,
+    caches::DirCache,
+    futures_rustls::rustls::{
+      server::{ClientHello, ResolvesServerCert},
+      sign::CertifiedKey,
+      ClientConfig, RootCertStore, ServerConfig,
+    },
+    AcmeConfig, AcmeState, EventOk, ResolvesServerCertAcme, UseChallenge,
+  },
+  std::iter,
+  tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer},
+};
+
+/// The type of challenge the ACME server validates control of a domain with.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub(crate) enum AcmeChallenge {
+  /// Answered during the TLS handshake on the HTTPS port.
+  #[value(name = "tls-alpn-01")]
+  TlsAlpn01,
+  /// Answered on the HTTP port and the HTTPS redirect port.
+  #[value(name = "http-01")]
+  Http01,
+}
+
+/// Fetches and renews TLS certificates via ACME: one for `--acme-domain`,
+/// and one for the hosts of each site in the `--sites` file.
+pub(crate) struct Acme {
+  challenge: AcmeChallenge,
+  directory_url: String,
+  certificates: Vec<Certificate>,
+}
+
+/// A certificate for `domains`, which `state` fetches and renews.
+struct Certificate {
+  domains: Vec<String>,
+  state: AcmeState<io::Error>,
+}
+
+impl Acme {
+  /// `None` unless HTTPS is served with certificates fetched via ACME.
+  pub(crate) fn new(
+    environment: &Environment,
+    arguments: &Arguments,
+    sites: &SitesConfig,
+  ) -> Result<Option<Self>> {
+    let cache_directory = match &arguments.acme_cache_directory {
+      Some(cache_directory) if arguments.https() => {
+        environment.working_directory.join(cache_directory)
+      }
+      _ => return Ok(None),
+    };
+
+    assert!(!arguments.acme_domain.is_empty());
+
+    let client_config = arguments
+      .acme_root_certificate
+      .as_ref()
+      .map(|path| Self::client_config(&environment.working_directory.join(path)))
+      .transpose()?;
+
+    let certificates = iter::once(&arguments.acme_domain)
+      .chain(sites.sites.iter().map(|site| &site.hosts))
+      .map(|domains| {
+        let mut config = AcmeConfig::new(domains)
+          .directory(&arguments.acme_directory_url)
+          .contact(arguments.acme_contact.iter().map(|contact| {
+            if contact.contains(':') {
+              contact.clone()
+            } else {
+              format!("mailto:{}", contact)
+            }
+          }))
+          .challenge_type(match arguments.acme_challenge {
+            AcmeChallenge::TlsAlpn01 => UseChallenge::TlsAlpn01,
+            AcmeChallenge::Http01 => UseChallenge::Http01,
+          })
+          .cache(DirCache::new(cache_directory.clone()));
+
+        if let Some(client_config) = &client_config {
+          config = config.client_tls_config(client_config.clone());
+        }
+
+        Certificate {
+          domains: domains.iter().map(|domain| domain.to_lowercase()).collect(),
+          state: config.state(),
+        }
+      })
+      .collect();
+
+    Ok(Some(Self {
+      challenge: arguments.acme_challenge,
+      directory_url: arguments.acme_directory_url.clone(),
+      certificates,
+    }))
+  }
+
+  /// A client config for talking to the ACME server that only trusts the
+  /// PEM-encoded root certificates in `path`.
+  fn client_config(path: &Path) -> Result<Arc<ClientConfig>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+      .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+      .context(error::TlsPem { path })?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _ignored) = roots.add_parsable_certificates(certificates);
+    if added == 0 {
+      return Err(error::TlsCertificateMissing { path }.build());
+    }
+
+    Ok(Arc::new(
+      ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth(),
+    ))
+  }
+
+  /// Certificates for each server name, falling back to the certificate for
+  /// `--acme-domain`.
+  fn resolver(&self) -> Arc<Resolver> {
+    Arc::new(Resolver(
+      self
+        .certificates
+        .iter()
+        .map(|certificate| (certificate.domains.clone(), certificate.state.resolver()))
+        .collect(),
+    ))
+  }
+
+  /// The config that HTTPS connections are served with, whose certificates
+  /// are swapped in once they have been fetched.
+  pub(crate) fn default_rustls_config(&self) -> ServerConfig {
+    ServerConfig::builder()
+      .with_no_client_auth()
+      .with_cert_resolver(self.resolver())
+  }
+
+  /// The config that TLS-ALPN-01 challenges are answered with, if the ACME
+  /// server uses them.
+  pub(crate) fn challenge_rustls_config(&self) -> Option<Arc<ServerConfig>> {
+    match self.challenge {
+      AcmeChallenge::TlsAlpn01 => {
+        let mut config = ServerConfig::builder()
+          .with_no_client_auth()
+          .with_cert_resolver(self.resolver());
+        config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+        Some(Arc::new(config))
+      }
+      AcmeChallenge::Http01 => None,
+    }
+  }
+
+  /// The HTTP-01 challenges to answer over plain HTTP, if the ACME server
+  /// uses them.
+  pub(crate) fn http01_challenges(&self) -> Option<Http01Challenges> {
+    match self.challenge {
+      AcmeChallenge::TlsAlpn01 => None,
+      AcmeChallenge::Http01 => Some(Http01Challenges(
+        self
+          .certificates
+          .iter()
+          .map(|certificate| certificate.state.resolver())
+          .collect(),
+      )),
+    }
+  }
+
+  /// Fetch the certificates, from the cache or the ACME server, and renew
+  /// them before they expire, logging what happens.
+  pub(crate) async fn run(self) {
+    let directory_url = &self.directory_url;
+    futures::future::join_all(
+      self
+        .certificates
+        .into_iter()
+        .map(|certificate| certificate.run(directory_url)),
+    )
+    .await;
+  }
+}
+
+impl Certificate {
+  async fn run(mut self, directory_url: &str) {
+    let domains = self.domains.join(", ");
+    while let Some(event) = self.state.next().await {
+      match event {
+        Ok(EventOk::DeployedCachedCert) => {
+          log::info!("Using cached TLS certificate for {}", domains)
+        }
+        Ok(EventOk::DeployedNewCert) => log::info!(
+          "Obtained new TLS certificate for {} from `{}`",
+          domains,
+          directory_url
+        ),
+        Ok(EventOk::CertCacheStore) => log::info!("Cached TLS certificate for {}", domains),
+        Ok(EventOk::AccountCacheStore) => {
+          log::info!("Cached ACME account for `{}`", directory_url)
+        }
+        Err(source) => log::error!(
+          "{}",
+          error::Acme { domains: &domains }.into_error(Box::new(source))
+        ),
+      }
+    }
+  }
+}
+
+/// Picks the certificate whose domains include the server name the client
+/// asks for, or else the first one. TLS-ALPN-01 challenges are answered by
+/// the certificate they are for.
+#[derive(Debug)]
+struct Resolver(Vec<(Vec<String>, Arc<ResolvesServerCertAcme>)>);
+
+impl ResolvesServerCert for Resolver {
+  fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    let server_name = client_hello.server_name().map(str::to_lowercase);
+    let (_, resolver) = self
+      .0
+      .iter()
+      .find(|(domains, _)| {
+        server_name
+          .as_ref()
+          .is_some_and(|server_name| domains.contains(server_name))
+      })
+      .unwrap_or(&self.0[0]);
+    resolver.resolve(client_hello)
+  }
+}
+
+/// Answers the HTTP-01 challenges of the ACME server, for any of the
+/// certificates.
+#[derive(Clone)]
+pub(crate) struct Http01Challenges(Vec<Arc<ResolvesServerCertAcme>>);
+
+impl Http01Challenges {
+  const PATH_PREFIX: &'static str = "/.well-known/acme-challenge/";
+
+  /// The response to `request`, if it is for a pending challenge.
+  pub(crate) fn response(&self, request: &Request<Body>) -> Option<Response<Body>> {
+    let token = request.uri().path().strip_prefix(Self::PATH_PREFIX)?;
+    let key_authorization = self
+      .0
+      .iter()
+      .find_map(|resolver| resolver.get_http_01_key_auth(token))?;
+    log::info!("Answering HTTP-01 challenge `{}`", token);
+    Some(
+      Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(key_authorization.into())
+        .expect("response is valid"),
+    )
+  }
+}
@@ -0,0 +1,185 @@
+use {
+  crate::{
+    bandwidth_limiter::{BandwidthLimiter, Connection},
+    common::*,
+    vfs::Vfs,
+  },
+  async_compression::tokio::write::GzipEncoder,
+  async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder},
+  hyper::body::Bytes,
+  percent_encoding::NON_ALPHANUMERIC,
+  std::{str::FromStr, time::UNIX_EPOCH},
+  tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+  },
+  tokio_util::{compat::TokioAsyncReadCompatExt, io::ReaderStream},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Archive {
+  Zip,
+  TarGz,
+}
+
+impl Archive {
+  const BUFFER_SIZE: usize = 64 * 1024;
+
+  fn extension(self) -> &'static str {
+    match self {
+      Self::Zip => "zip",
+      Self::TarGz => "tar.gz",
+    }
+  }
+
+  fn content_type(self) -> &'static str {
+    match self {
+      Self::Zip => "application/zip",
+      Self::TarGz => "application/gzip",
+    }
+  }
+
+  pub(crate) async fn serve(
+    self,
+    vfs: &Vfs,
+    dir: &InputPath,
+    bandwidth_limiter: &BandwidthLimiter,
+    connection: Option<&Connection>,
+  ) -> Result<Response<Body>> {
+    let limits = vfs.dir_bandwidth_limits(dir)?;
+    let files = vfs.free_files(dir).await?;
+
+    let name = dir
+      .display_path()
+      .file_name()
+      .map(|file_name| file_name.to_string_lossy().into_owned())
+      .unwrap_or_else(|| "files".to_owned());
+
+    let (writer, reader) = tokio::io::duplex(Self::BUFFER_SIZE);
+
+    let task = tokio::spawn(async move {
+      match self {
+        Self::Zip => Self::write_zip(writer, files).await,
+        Self::TarGz => Self::write_tar_gz(writer, files).await,
+      }
+    });
+
+    let trailer = futures::stream::once(async move {
+      match task.await.context(error::RequestHandlerPanic) {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) | Err(error) => Some(Err(error)),
+      }
+    })
+    .filter_map(future::ready);
+
+    let stream = ReaderStream::with_capacity(reader, Self::BUFFER_SIZE)
+      .map(|result| result.context(error::ArchiveIo))
+      .chain(trailer);
+
+    let stream = bandwidth_limiter.throttle(stream, connection, false, limits);
+
+    Response::builder()
+      .header(header::CONTENT_TYPE, self.content_type())
+      .header(
+        header::CONTENT_DISPOSITION,
+        format!(
+          "attachment; filename*=UTF-8''{}.{}",
+          percent_encoding::utf8_percent_encode(&name, NON_ALPHANUMERIC),
+          self.extension(),
+        ),
+      )
+      .body(Body::wrap_stream::<_, Bytes, Error>(stream))
+      .map_err(|error| Error::internal(format!("Failed to construct response: {}", error)))
+  }
+
+  async fn write_zip(writer: DuplexStream, files: Vec<(String, InputPath)>) -> Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    for (name, path) in files {
+      let file = File::open(&path)
+        .await
+        .with_context(|| Error::filesystem_io(&path))?;
+      let mut entry = zip
+        .write_entry_stream(
+          ZipEntryBuilder::new(name.into(), Compression::Stored).unix_permissions(0o644),
+        )
+        .await
+        .context(error::ArchiveZip)?;
+      futures::io::copy(&mut file.compat(), &mut entry)
+        .await
+        .context(error::ArchiveIo)?;
+      entry.close().await.context(error::ArchiveZip)?;
+    }
+
+    zip.close().await.context(error::ArchiveZip)?;
+
+    Ok(())
+  }
+
+  async fn write_tar_gz(writer: DuplexStream, files: Vec<(String, InputPath)>) -> Result<()> {
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(writer));
+
+    for (name, path) in files {
+      let file = File::open(&path)
+        .await
+        .with_context(|| Error::filesystem_io(&path))?;
+      let metadata = file
+        .metadata()
+        .await
+        .with_context(|| Error::filesystem_io(&path))?;
+
+      let mut header = tokio_tar::Header::new_gnu();
+      header.set_size(metadata.len());
+      header.set_mode(0o644);
+      if let Some(mtime) = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      {
+        header.set_mtime(mtime.as_secs());
+      }
+
+      tar
+        .append_data(&mut header, &name, file.take(metadata.len()))
+        .await
+        .context(error::ArchiveIo)?;
+    }
+
+    let mut encoder = tar.into_inner().await.context(error::ArchiveIo)?;
+    encoder.shutdown().await.context(error::ArchiveIo)?;
+
+    Ok(())
+  }
+}
+
+impl FromStr for Archive {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "zip" => Ok(Self::Zip),
+      "tar.gz" => Ok(Self::TarGz),
+      _ => Err(
+        error::ArchiveFormat {
+          format: s.to_owned(),
+        }
+        .build(),
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_archive_formats() {
+    assert_eq!("zip".parse::<Archive>().unwrap(), Archive::Zip);
+    assert_eq!("tar.gz".parse::<Archive>().unwrap(), Archive::TarGz);
+    assert_matches!(
+      "rar".parse::<Archive>(),
+      Err(Error::ArchiveFormat { format, .. }) if format == "rar"
+    );
+  }
+}
@@ -1,53 +1,151 @@
-use clap::builder::ArgPredicate;
 use clap::crate_version;
 use clap::ColorChoice;
 use clap::Parser;
-use {crate::common::*, clap::ArgGroup};
+use {
+  crate::{
+    acme::AcmeChallenge,
+    common::*,
+    security_headers::{parse_header_value, SecurityHeaders},
+    trusted_proxies::TrustedProxy,
+  },
+  clap::ArgGroup,
+};
 
 #[derive(Debug, Parser)]
 #[command(
   group = ArgGroup::new("port").multiple(true).required(true),
+  group = ArgGroup::new("certificate").args(["acme_cache_directory", "tls_certificate"]),
+  group = ArgGroup::new("socket").multiple(true),
+  group = ArgGroup::new("http").multiple(true),
   color = if cfg!(test) { ColorChoice::Never } else { ColorChoice::Auto },
   version = crate_version!())
 ]
 pub(crate) struct Arguments {
   #[arg(
     long,
-    help = "Store TLS certificates fetched from Let's Encrypt via the ACME protocol in <acme-cache-directory>."
+    help = "Store TLS certificates fetched via the ACME protocol, from Let's Encrypt or <acme-directory-url>, in <acme-cache-directory>.",
+    requires = "acme_domain"
   )]
   pub(crate) acme_cache_directory: Option<PathBuf>,
   #[arg(
     long,
-    help = "Request TLS certificate for <acme-domain>. This opuza instance must be reachable at <acme-domain>:443 to respond to Let's Encrypt ACME challenges."
+    value_enum,
+    default_value = "tls-alpn-01",
+    help = "Prove control of <acme-domain> to the ACME server with <acme-challenge>: `tls-alpn-01` is answered on the HTTPS port, `http-01` on the HTTP port and the HTTPS redirect port, for when port 443 isn't reachable by the ACME server.",
+    requires = "acme_cache_directory",
+    requires_if("http-01", "http")
+  )]
+  pub(crate) acme_challenge: AcmeChallenge,
+  #[arg(
+    long,
+    help = "Register the ACME account with contact <acme-contact>, an email address or a `mailto:` URL, to be told about problems with certificates. May be given more than once.",
+    requires = "acme_cache_directory"
+  )]
+  pub(crate) acme_contact: Vec<String>,
+  #[arg(
+    long,
+    default_value = rustls_acme::acme::LETS_ENCRYPT_PRODUCTION_DIRECTORY,
+    help = "Fetch certificates from the ACME server whose directory is at <acme-directory-url>, e.g. Let's Encrypt's staging environment at `https://acme-staging-v02.api.letsencrypt.org/directory` or a local test CA like Pebble.",
+    requires = "acme_cache_directory"
+  )]
+  pub(crate) acme_directory_url: String,
+  #[arg(
+    long,
+    help = "Request TLS certificate for <acme-domain>. This opuza instance must be reachable at <acme-domain> on port 443, or on port 80 with `--acme-challenge http-01`, to respond to ACME challenges.",
+    requires = "acme_cache_directory"
   )]
   pub(crate) acme_domain: Vec<String>,
+  #[arg(
+    long,
+    help = "Trust only the PEM-encoded CA certificates in <acme-root-certificate> when connecting to <acme-directory-url>, e.g. a local test CA's own root.",
+    requires = "acme_cache_directory"
+  )]
+  pub(crate) acme_root_certificate: Option<PathBuf>,
   #[arg(
     long,
     default_value = "0.0.0.0",
-    help = "Listen on <address> for incoming requests."
+    help = "Listen on <address> for incoming requests. May be given more than once, e.g. `--address 0.0.0.0 --address ::` to listen on all IPv4 and IPv6 addresses. Every address that <address> resolves to is listened on."
+  )]
+  pub(crate) address: Vec<String>,
+  #[arg(
+    long,
+    default_value = SecurityHeaders::CONTENT_SECURITY_POLICY,
+    value_parser = parse_header_value,
+    help = "Send <content-security-policy> as the `Content-Security-Policy` header, e.g. to allow images from other sites in Markdown files. An empty value sends no policy."
+  )]
+  pub(crate) content_security_policy: HeaderValue,
+  #[arg(
+    long,
+    help = "Serve files from <directory>. With <sites>, it is served for hosts that don't have a site of their own, which otherwise get a `421 Misdirected Request` error.",
+    required_unless_present = "sites"
+  )]
+  pub(crate) directory: Option<PathBuf>,
+  #[arg(
+    long,
+    default_value = "30",
+    value_parser = clap::value_parser!(u64).range(1..),
+    help = "Close connections whose clients take longer than <header-read-timeout> seconds to send the headers of a request."
   )]
-  pub(crate) address: String,
-  #[arg(long, help = "Serve files from <directory>")]
-  pub(crate) directory: PathBuf,
+  pub(crate) header_read_timeout: u64,
+  #[arg(
+    long,
+    value_parser = clap::value_parser!(u64).range(1..),
+    help = "Send HTTP/2 pings every <http2-keep-alive-interval> seconds, and close HTTP/2 connections whose clients don't respond. By default, no pings are sent."
+  )]
+  pub(crate) http2_keep_alive_interval: Option<u64>,
+  #[arg(
+    long,
+    default_value = "100",
+    value_parser = clap::value_parser!(u32).range(1..),
+    help = "Allow each HTTP/2 client to make up to <http2-max-concurrent-streams> requests at once over a single connection."
+  )]
+  pub(crate) http2_max_concurrent_streams: u32,
   #[arg(
     long,
     group = "port",
+    group = "http",
     help = "Listen on <http-port> for incoming HTTP requests."
   )]
   pub(crate) http_port: Option<u16>,
+  #[cfg(unix)]
+  #[arg(
+    long,
+    group = "port",
+    group = "socket",
+    group = "http",
+    help = "Listen on the Unix domain socket <http-socket> for incoming HTTP requests. A stale socket left behind at <http-socket> by an earlier run is removed, and the socket is removed again on shutdown."
+  )]
+  pub(crate) http_socket: Option<PathBuf>,
   #[arg(
     long,
     group = "port",
     help = "Listen on <https-port> for incoming HTTPS requests.",
-    requires_ifs = [(ArgPredicate::IsPresent, "acme_cache_directory"), (ArgPredicate::IsPresent,"acme_domain")]
+    requires = "certificate"
   )]
   pub(crate) https_port: Option<u16>,
   #[arg(
     long,
+    default_value = "1024",
+    value_parser = clap::value_parser!(u32).range(1..),
+    help = "Serve at most <https-max-connections> HTTPS connections at once. Further connections wait until one closes."
+  )]
+  pub(crate) https_max_connections: u32,
+  #[arg(
+    long,
+    group = "http",
     help = "Redirect HTTP requests on <https-redirect-port> to HTTPS on <https-port>.",
     requires = "https_port"
   )]
   pub(crate) https_redirect_port: Option<u16>,
+  #[cfg(unix)]
+  #[arg(
+    long,
+    group = "port",
+    group = "socket",
+    help = "Listen on the Unix domain socket <https-socket> for incoming HTTPS requests. A stale socket left behind at <https-socket> by an earlier run is removed, and the socket is removed again on shutdown.",
+    requires = "certificate"
+  )]
+  pub(crate) https_socket: Option<PathBuf>,
   #[arg(
     long,
     help = "Connect to LND gRPC server with host and port <lnd-rpc-authority>. By default a locally running LND instance will expose its gRPC API on `localhost:10009`."
@@ -65,8 +163,135 @@ pub(crate) struct Arguments {
     requires = "_lnd_rpc_authority"
   )]
   pub(crate) _lnd_rpc_macaroon_path: Option<PathBuf>,
+  #[arg(
+    long,
+    default_value = "100",
+    value_parser = clap::value_parser!(u32).range(1..),
+    help = "Allow all clients together to create up to <global-invoice-burst> invoices at once. The allowance refills at <global-invoice-burst> invoices per <invoice-window> seconds."
+  )]
+  pub(crate) global_invoice_burst: u32,
+  #[arg(
+    long,
+    default_value = "10",
+    value_parser = clap::value_parser!(u32).range(1..),
+    help = "Allow each client IP address to create up to <invoice-burst> invoices at once. The allowance refills at <invoice-burst> invoices per <invoice-window> seconds. Clients over the limit receive `429 Too Many Requests`."
+  )]
+  pub(crate) invoice_burst: u32,
+  #[arg(
+    long,
+    default_value = "600",
+    help = "Send clients requesting the same paid file again within <invoice-reuse-window> seconds to the invoice they already have instead of creating a new one."
+  )]
+  pub(crate) invoice_reuse_window: u64,
+  #[arg(
+    long,
+    default_value = "60",
+    value_parser = clap::value_parser!(u64).range(1..),
+    help = "Refill invoice allowances over <invoice-window> seconds."
+  )]
+  pub(crate) invoice_window: u64,
   #[arg(long, help = "Connect to monero node rpc.")]
   pub(crate) monero_rpc_address: Option<String>,
+  #[arg(
+    long,
+    help = "Expect connections from trusted proxies to start with a HAProxy PROXY protocol v1 or v2 header, and take the client's address from it. Connections from other peers are served as usual.",
+    requires = "trusted_proxy"
+  )]
+  pub(crate) proxy_protocol: bool,
+  #[arg(
+    long,
+    default_value = SecurityHeaders::REFERRER_POLICY,
+    value_parser = parse_header_value,
+    help = "Send <referrer-policy> as the `Referrer-Policy` header. An empty value sends none."
+  )]
+  pub(crate) referrer_policy: HeaderValue,
+  #[arg(
+    long,
+    help = "Serve a different directory for each host that requests are made to, as listed in the YAML file <sites>. Each site in it gives its `hosts`, the `directory` to serve, and optionally its own `theme-directory` and `monero-account`. Relative paths are relative to the directory <sites> is in."
+  )]
+  pub(crate) sites: Option<PathBuf>,
+  #[arg(
+    long,
+    default_value = "30",
+    help = "On SIGINT or SIGTERM, stop accepting connections and wait up to <shutdown-timeout> seconds for downloads in progress to finish before exiting."
+  )]
+  pub(crate) shutdown_timeout: u64,
+  #[cfg(unix)]
+  #[arg(
+    long,
+    value_parser = parse_socket_mode,
+    help = "Set the permissions of Unix domain sockets to the octal <socket-mode>, e.g. `660`. By default, they depend on the umask.",
+    requires = "socket"
+  )]
+  pub(crate) socket_mode: Option<u32>,
+  #[cfg(unix)]
+  #[arg(
+    long,
+    help = "Change the owner of Unix domain sockets to <socket-owner>, given as `user`, `user:group` or `:group`, either by name or numeric ID.",
+    requires = "socket"
+  )]
+  pub(crate) socket_owner: Option<String>,
+  #[arg(
+    long,
+    default_value = SecurityHeaders::STRICT_TRANSPORT_SECURITY,
+    value_parser = parse_header_value,
+    help = "Send <strict-transport-security> as the `Strict-Transport-Security` header with responses over HTTPS, telling browsers to only use HTTPS from then on. An empty value sends none."
+  )]
+  pub(crate) strict_transport_security: HeaderValue,
+  #[arg(
+    long,
+    help = "Load CSS, a logo, and header, footer and page templates from <theme-directory>, falling back to the built-in defaults for anything it doesn't provide."
+  )]
+  pub(crate) theme_directory: Option<PathBuf>,
+  #[arg(
+    long,
+    help = "Cache generated image thumbnails in <thumbnail-cache-directory>, which must not be inside <directory> or the directories of <sites>. Without it, thumbnails are generated anew for every request."
+  )]
+  pub(crate) thumbnail_cache_directory: Option<PathBuf>,
+  #[arg(
+    long,
+    help = "Serve HTTPS with the PEM-encoded certificate chain in <tls-certificate>, instead of fetching a certificate via ACME. The server's certificate must come first, followed by any intermediate certificates. It is reloaded, along with <tls-key>, when either changes or on SIGHUP.",
+    requires = "tls_key",
+    conflicts_with_all = ["acme_cache_directory", "acme_domain"]
+  )]
+  pub(crate) tls_certificate: Option<PathBuf>,
+  #[arg(
+    long,
+    default_value = "10",
+    value_parser = clap::value_parser!(u64).range(1..),
+    help = "Close HTTPS connections whose clients don't complete the TLS handshake within <tls-handshake-timeout> seconds."
+  )]
+  pub(crate) tls_handshake_timeout: u64,
+  #[arg(
+    long,
+    help = "Trust the reverse proxy at <trusted-proxy>, an IP address or CIDR range like `10.0.0.0/8`, or `unix` for peers connecting over a Unix domain socket, to report the client's address, scheme and host in `Forwarded` or `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers. May be given more than once."
+  )]
+  pub(crate) trusted_proxy: Vec<TrustedProxy>,
+  #[arg(
+    long,
+    help = "Read the PEM-encoded RSA or ECDSA private key of <tls-certificate> from <tls-key>.",
+    requires = "tls_certificate"
+  )]
+  pub(crate) tls_key: Option<PathBuf>,
+}
+
+impl Arguments {
+  /// Whether to serve HTTPS, on a port or a Unix domain socket.
+  pub(crate) fn https(&self) -> bool {
+    #[cfg(unix)]
+    if self.https_socket.is_some() {
+      return true;
+    }
+    self.https_port.is_some()
+  }
+}
+
+#[cfg(unix)]
+fn parse_socket_mode(mode: &str) -> Result<u32, String> {
+  match u32::from_str_radix(mode, 8) {
+    Ok(mode) if mode <= 0o777 => Ok(mode),
+    _ => Err("expected octal permissions, e.g. `660`".into()),
+  }
 }
 
 #[cfg(test)]
@@ -88,28 +313,64 @@ mod tests {
       .to_string(),
       &"
         the following required arguments were not provided:
-          --acme-cache-directory <ACME_CACHE_DIRECTORY>
-          --acme-domain <ACME_DOMAIN>
           --https-port <HTTPS_PORT>
+          <--acme-cache-directory <ACME_CACHE_DIRECTORY>|--tls-certificate <TLS_CERTIFICATE>>
       "
       .unindent(),
     );
   }
 
   #[test]
-  fn https_port_requires_acme_cache_directory() {
+  fn https_port_requires_acme_cache_directory_or_tls_certificate() {
     assert_contains(
       &Arguments::try_parse_from(["opuza", "--directory=www", "--https-port=0"])
         .unwrap_err()
         .to_string(),
       &"
         the following required arguments were not provided:
-          --acme-cache-directory <ACME_CACHE_DIRECTORY>
+          <--acme-cache-directory <ACME_CACHE_DIRECTORY>|--tls-certificate <TLS_CERTIFICATE>>
+      "
+      .unindent(),
+    );
+  }
+
+  #[test]
+  fn tls_certificate_requires_tls_key() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--https-port=0",
+        "--tls-certificate=cert.pem",
+      ])
+      .unwrap_err()
+      .to_string(),
+      &"
+        the following required arguments were not provided:
+          --tls-key <TLS_KEY>
       "
       .unindent(),
     );
   }
 
+  #[test]
+  fn tls_certificate_conflicts_with_acme() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--https-port=0",
+        "--tls-certificate=cert.pem",
+        "--tls-key=key.pem",
+        "--acme-cache-directory=cache",
+        "--acme-domain=example.com",
+      ])
+      .unwrap_err()
+      .to_string(),
+      "the argument '--tls-certificate <TLS_CERTIFICATE>' cannot be used with",
+    );
+  }
+
   #[test]
   fn https_port_requires_acme_domain() {
     assert_contains(
@@ -137,9 +398,181 @@ mod tests {
         .to_string(),
       &"
       the following required arguments were not provided:
-        <--http-port <HTTP_PORT>|--https-port <HTTPS_PORT>>
+        <--http-port <HTTP_PORT>|--http-socket <HTTP_SOCKET>|--https-port <HTTPS_PORT>|--https-socket <HTTPS_SOCKET>>
+      "
+      .unindent(),
+    );
+  }
+
+  #[test]
+  fn https_socket_requires_acme_cache_directory_or_tls_certificate() {
+    assert_contains(
+      &Arguments::try_parse_from(["opuza", "--directory=www", "--https-socket=opuza.sock"])
+        .unwrap_err()
+        .to_string(),
+      &"
+        the following required arguments were not provided:
+          <--acme-cache-directory <ACME_CACHE_DIRECTORY>|--tls-certificate <TLS_CERTIFICATE>>
+      "
+      .unindent(),
+    );
+  }
+
+  #[test]
+  fn acme_directory_url_defaults_to_lets_encrypt() {
+    let arguments = Arguments::try_parse_from([
+      "opuza",
+      "--directory=www",
+      "--https-port=0",
+      "--acme-cache-directory=cache",
+      "--acme-domain=example.com",
+    ])
+    .unwrap();
+    assert_eq!(
+      arguments.acme_directory_url,
+      "https://acme-v02.api.letsencrypt.org/directory"
+    );
+    assert_eq!(arguments.acme_challenge, AcmeChallenge::TlsAlpn01);
+  }
+
+  #[test]
+  fn acme_options_require_acme_cache_directory() {
+    for option in [
+      "--acme-challenge=tls-alpn-01",
+      "--acme-contact=admin@example.com",
+      "--acme-directory-url=https://localhost:14000/dir",
+      "--acme-root-certificate=root.pem",
+    ] {
+      assert_contains(
+        &Arguments::try_parse_from(["opuza", "--directory=www", "--http-port=0", option])
+          .unwrap_err()
+          .to_string(),
+        "\n  --acme-cache-directory <ACME_CACHE_DIRECTORY>\n",
+      );
+    }
+  }
+
+  #[test]
+  fn http01_challenges_require_http_listener() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--https-port=0",
+        "--acme-cache-directory=cache",
+        "--acme-domain=example.com",
+        "--acme-challenge=http-01",
+      ])
+      .unwrap_err()
+      .to_string(),
+      "the following required arguments were not provided:",
+    );
+
+    for listener in ["--http-port=0", "--https-redirect-port=0"] {
+      Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--https-port=0",
+        "--acme-cache-directory=cache",
+        "--acme-domain=example.com",
+        "--acme-challenge=http-01",
+        listener,
+      ])
+      .unwrap();
+    }
+  }
+
+  #[test]
+  fn proxy_protocol_requires_trusted_proxy() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--http-port=0",
+        "--proxy-protocol",
+      ])
+      .unwrap_err()
+      .to_string(),
+      &"
+        the following required arguments were not provided:
+          --trusted-proxy <TRUSTED_PROXY>
       "
       .unindent(),
     );
   }
+
+  #[test]
+  fn trusted_proxies_must_be_addresses_ranges_or_unix() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--http-port=0",
+        "--trusted-proxy=proxy.local",
+      ])
+      .unwrap_err()
+      .to_string(),
+      "expected an IP address, a CIDR range like `10.0.0.0/8`, or `unix`, got `proxy.local`",
+    );
+  }
+
+  #[test]
+  fn socket_mode_requires_socket() {
+    assert_contains(
+      &Arguments::try_parse_from([
+        "opuza",
+        "--directory=www",
+        "--http-port=0",
+        "--socket-mode=660",
+      ])
+      .unwrap_err()
+      .to_string(),
+      &"
+        the following required arguments were not provided:
+          <--http-socket <HTTP_SOCKET>|--https-socket <HTTPS_SOCKET>>
+      "
+      .unindent(),
+    );
+  }
+
+  #[test]
+  fn socket_mode_is_octal() {
+    let arguments = Arguments::try_parse_from([
+      "opuza",
+      "--directory=www",
+      "--http-socket=opuza.sock",
+      "--socket-mode=660",
+    ])
+    .unwrap();
+    assert_eq!(arguments.socket_mode, Some(0o660));
+
+    for mode in ["rw", "680", "1777"] {
+      assert_contains(
+        &Arguments::try_parse_from([
+          "opuza",
+          "--directory=www",
+          "--http-socket=opuza.sock",
+          &format!("--socket-mode={}", mode),
+        ])
+        .unwrap_err()
+        .to_string(),
+        "expected octal permissions, e.g. `660`",
+      );
+    }
+  }
+
+  #[test]
+  fn directory_is_only_required_without_sites() {
+    assert_contains(
+      &Arguments::try_parse_from(["opuza", "--http-port=0"])
+        .unwrap_err()
+        .to_string(),
+      "\n  --directory <DIRECTORY>\n",
+    );
+
+    let arguments =
+      Arguments::try_parse_from(["opuza", "--http-port=0", "--sites=sites.yaml"]).unwrap();
+    assert_eq!(arguments.directory, None);
+    assert_eq!(arguments.sites, Some("sites.yaml".into()));
+  }
 }
@@ -0,0 +1,144 @@
+use {
+  crate::common::*,
+  serde::de::{self, Deserializer, Visitor},
+  std::str::FromStr,
+};
+
+/// A data rate in bytes per second, written in `.opuza.yaml` as e.g.
+/// `512 KiB` or `10 MB/s`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub(crate) struct Bandwidth(u64);
+
+impl Bandwidth {
+  const UNITS: &'static [(&'static str, u64)] = &[
+    ("B", 1),
+    ("KB", 1000),
+    ("KiB", 1024),
+    ("MB", 1000 * 1000),
+    ("MiB", 1024 * 1024),
+    ("GB", 1000 * 1000 * 1000),
+    ("GiB", 1024 * 1024 * 1024),
+  ];
+
+  pub(crate) fn bytes_per_second(self) -> u64 {
+    self.0
+  }
+
+  #[cfg(test)]
+  pub(crate) fn new(bytes_per_second: u64) -> Self {
+    Self(bytes_per_second)
+  }
+}
+
+/// Limits applying to the download of a single file, per connection, per
+/// client IP address, and across all clients.
+#[derive(PartialEq, Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BandwidthLimits {
+  pub(crate) connection: Option<Bandwidth>,
+  pub(crate) client: Option<Bandwidth>,
+  pub(crate) global: Option<Bandwidth>,
+}
+
+impl BandwidthLimits {
+  pub(crate) fn merge_parent(self, parent: Self) -> Self {
+    Self {
+      connection: self.connection.or(parent.connection),
+      client: self.client.or(parent.client),
+      global: self.global.or(parent.global),
+    }
+  }
+}
+
+impl FromStr for Bandwidth {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid bandwidth `{}`", s);
+
+    let (value, unit) = s.split_once(' ').ok_or_else(invalid)?;
+    let unit = unit.strip_suffix("/s").unwrap_or(unit);
+
+    let multiplier = Self::UNITS
+      .iter()
+      .find(|(name, _)| *name == unit)
+      .map(|(_, multiplier)| *multiplier)
+      .ok_or_else(invalid)?;
+
+    let value = value.parse::<f64>().map_err(|_| invalid())?;
+
+    if !value.is_finite() || value <= 0.0 {
+      return Err(invalid());
+    }
+
+    Ok(Self(((value * multiplier as f64) as u64).max(1)))
+  }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    deserializer.deserialize_str(BandwidthVisitor)
+  }
+}
+
+struct BandwidthVisitor;
+
+impl<'de> Visitor<'de> for BandwidthVisitor {
+  type Value = Bandwidth;
+
+  fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+    formatter.write_str("a string, e.g. \"1 MiB\"")
+  }
+
+  fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+  where
+    E: de::Error,
+  {
+    value.parse().map_err(|_| {
+      de::Error::invalid_value(
+        de::Unexpected::Str(value),
+        &"positive number of bytes per second, including unit, e.g. \"1 MiB\"",
+      )
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_units() {
+    assert_eq!("1 B".parse(), Ok(Bandwidth(1)));
+    assert_eq!("2 KB".parse(), Ok(Bandwidth(2000)));
+    assert_eq!("2 KiB".parse(), Ok(Bandwidth(2048)));
+    assert_eq!("1.5 MiB".parse(), Ok(Bandwidth(1536 * 1024)));
+    assert_eq!("1 GB/s".parse(), Ok(Bandwidth(1_000_000_000)));
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    for input in [
+      "1", "1MiB", "1 mib", "0 MiB", "-1 MiB", "inf MiB", "1 MiB/h",
+    ] {
+      assert!(
+        input.parse::<Bandwidth>().is_err(),
+        "`{}` should not parse",
+        input
+      );
+    }
+  }
+
+  #[test]
+  fn deserialize_error() {
+    assert_eq!(
+      serde_yaml::from_str::<Bandwidth>("1 parsec")
+        .unwrap_err()
+        .to_string(),
+      "invalid value: string \"1 parsec\", expected positive number of bytes per second, including unit, e.g. \"1 MiB\""
+    );
+  }
+}
@@ -0,0 +1,367 @@
+use {
+  crate::{
+    bandwidth::{Bandwidth, BandwidthLimits},
+    common::*,
+  },
+  hyper::body::Bytes,
+  pin_project::pin_project,
+  std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Mutex,
+    },
+    time::{Duration, Instant},
+  },
+  tokio::time::Sleep,
+};
+
+/// The connection a request arrived on. `RequestHandler` inserts this into
+/// the extensions of every request it receives, with `client` and `https`
+/// as reported by trusted proxies.
+///
+/// `client` is the IP address of the client, or `None` if it's unknown, e.g.
+/// for connections over a Unix domain socket. Such requests are only limited
+/// per connection and globally.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Connection {
+  id: u64,
+  pub(crate) client: Option<IpAddr>,
+  pub(crate) https: bool,
+}
+
+impl Connection {
+  pub(crate) fn new(client: Option<IpAddr>, https: bool) -> Self {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    Self {
+      id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+      client,
+      https,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+  Connection(u64),
+  Client(IpAddr),
+  Global,
+}
+
+/// Token buckets shared by all connections. Free and paid downloads draw
+/// from separate buckets, so free visitors can't starve paying customers.
+///
+/// Buckets are also keyed by their configured rate, so directories with
+/// different limits in their `.opuza.yaml` each get a bucket of their own,
+/// instead of sharing one whose rate depends on the last request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BandwidthLimiter {
+  buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+  map: HashMap<(bool, Key, u64), TokenBucket>,
+  evicted: Option<Instant>,
+}
+
+impl BandwidthLimiter {
+  const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+  const MAX_BUCKETS: usize = 1024;
+  const MIN_PIECE_SIZE: u64 = 1024;
+  const MAX_PIECE_SIZE: u64 = 64 * 1024;
+
+  pub(crate) fn throttle<S>(
+    &self,
+    stream: S,
+    connection: Option<&Connection>,
+    paid: bool,
+    limits: BandwidthLimits,
+  ) -> Throttle<S> {
+    let mut buckets = Vec::new();
+
+    if let Some(global) = limits.global {
+      buckets.push((Key::Global, global));
+    }
+
+    if let Some(connection) = connection {
+      if let (Some(ip), Some(client)) = (connection.client, limits.client) {
+        buckets.push((Key::Client(ip), client));
+      }
+
+      if let Some(per_connection) = limits.connection {
+        buckets.push((Key::Connection(connection.id), per_connection));
+      }
+    }
+
+    // Hand out pieces of at most a tenth of a second's worth of data, so that
+    // slow downloads trickle along instead of stalling between large chunks.
+    let piece_size = buckets
+      .iter()
+      .map(|(_, bandwidth)| bandwidth.bytes_per_second() / 10)
+      .min()
+      .unwrap_or(Self::MAX_PIECE_SIZE)
+      .clamp(Self::MIN_PIECE_SIZE, Self::MAX_PIECE_SIZE) as usize;
+
+    Throttle {
+      inner: stream,
+      limiter: self.clone(),
+      buckets,
+      paid,
+      piece_size,
+      remaining: Bytes::new(),
+      delayed: None,
+    }
+  }
+
+  fn take(&self, buckets: &[(Key, Bandwidth)], paid: bool, amount: u64) -> Duration {
+    let now = Instant::now();
+
+    let mut state = self.buckets.lock().unwrap();
+
+    let mut delay = Duration::ZERO;
+
+    for (key, bandwidth) in buckets {
+      let bucket = state
+        .map
+        .entry((paid, *key, bandwidth.bytes_per_second()))
+        .or_insert_with(|| TokenBucket::new(*bandwidth, now));
+      delay = delay.max(bucket.take(amount, now));
+    }
+
+    // A full bucket behaves exactly like a fresh one, so dropping it is
+    // lossless. Evicting at most once per interval keeps many concurrent
+    // downloads from scanning the map on every piece.
+    if state.map.len() > Self::MAX_BUCKETS
+      && !matches!(
+        state.evicted,
+        Some(evicted) if now.saturating_duration_since(evicted) < Self::EVICTION_INTERVAL
+      )
+    {
+      state.map.retain(|_, bucket| !bucket.is_full(now));
+      state.evicted = Some(now);
+    }
+
+    delay
+  }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+  rate: u64,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  fn new(bandwidth: Bandwidth, now: Instant) -> Self {
+    Self {
+      rate: bandwidth.bytes_per_second(),
+      tokens: bandwidth.bytes_per_second() as f64,
+      updated: now,
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    self.updated = now;
+  }
+
+  /// Take `amount` tokens, going into debt if there aren't enough, and
+  /// return how long the caller must wait until the debt is repaid.
+  fn take(&mut self, amount: u64, now: Instant) -> Duration {
+    self.refill(now);
+    self.tokens -= amount as f64;
+    if self.tokens >= 0.0 {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64(-self.tokens / self.rate as f64)
+    }
+  }
+
+  fn is_full(&mut self, now: Instant) -> bool {
+    self.refill(now);
+    self.tokens >= self.rate as f64
+  }
+}
+
+#[pin_project]
+pub(crate) struct Throttle<S> {
+  #[pin]
+  inner: S,
+  limiter: BandwidthLimiter,
+  buckets: Vec<(Key, Bandwidth)>,
+  paid: bool,
+  piece_size: usize,
+  remaining: Bytes,
+  delayed: Option<(Pin<Box<Sleep>>, Bytes)>,
+}
+
+impl<S: Stream<Item = Result<Bytes>>> Stream for Throttle<S> {
+  type Item = Result<Bytes>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let mut projected = self.project();
+
+    if projected.buckets.is_empty() {
+      return projected.inner.poll_next(cx);
+    }
+
+    if let Some((sleep, _)) = projected.delayed {
+      futures::ready!(sleep.poll_unpin(cx));
+      let (_, piece) = projected.delayed.take().expect("delayed is some");
+      return Poll::Ready(Some(Ok(piece)));
+    }
+
+    if projected.remaining.is_empty() {
+      match futures::ready!(projected.inner.as_mut().poll_next(cx)) {
+        Some(Ok(chunk)) => *projected.remaining = chunk,
+        other => return Poll::Ready(other),
+      }
+    }
+
+    let len = projected.remaining.len().min(*projected.piece_size);
+    let piece = projected.remaining.split_to(len);
+
+    let delay = projected
+      .limiter
+      .take(projected.buckets, *projected.paid, len as u64);
+
+    if delay.is_zero() {
+      return Poll::Ready(Some(Ok(piece)));
+    }
+
+    let mut sleep = Box::pin(tokio::time::sleep(delay));
+
+    if sleep.poll_unpin(cx).is_ready() {
+      return Poll::Ready(Some(Ok(piece)));
+    }
+
+    *projected.delayed = Some((sleep, piece));
+
+    Poll::Pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bandwidth(bytes_per_second: u64) -> Bandwidth {
+    Bandwidth::new(bytes_per_second)
+  }
+
+  #[test]
+  fn token_bucket_allows_one_second_burst() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(bandwidth(1000), now);
+    assert_eq!(bucket.take(1000, now), Duration::ZERO);
+    assert_eq!(bucket.take(500, now), Duration::from_millis(500));
+  }
+
+  #[test]
+  fn token_bucket_refills_over_time() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(bandwidth(1000), now);
+    bucket.take(1000, now);
+    assert!(!bucket.is_full(now));
+    assert_eq!(
+      bucket.take(500, now + Duration::from_millis(500)),
+      Duration::ZERO
+    );
+    assert!(bucket.is_full(now + Duration::from_secs(2)));
+  }
+
+  #[test]
+  fn free_and_paid_buckets_are_separate() {
+    let limiter = BandwidthLimiter::default();
+    let buckets = [(Key::Global, bandwidth(1000))];
+    assert_eq!(limiter.take(&buckets, false, 1000), Duration::ZERO);
+    assert_eq!(limiter.take(&buckets, true, 1000), Duration::ZERO);
+    assert!(limiter.take(&buckets, false, 1000) > Duration::ZERO);
+  }
+
+  #[test]
+  fn buckets_with_different_rates_are_separate() {
+    let limiter = BandwidthLimiter::default();
+    assert_eq!(
+      limiter.take(&[(Key::Global, bandwidth(1000))], false, 1000),
+      Duration::ZERO
+    );
+    assert_eq!(
+      limiter.take(&[(Key::Global, bandwidth(2000))], false, 2000),
+      Duration::ZERO
+    );
+    let delay = limiter.take(&[(Key::Global, bandwidth(1000))], false, 500);
+    assert!(
+      delay > Duration::from_millis(400) && delay <= Duration::from_millis(500),
+      "delay: {:?}",
+      delay
+    );
+  }
+
+  #[test]
+  fn full_buckets_are_evicted_at_most_once_per_interval() {
+    let limiter = BandwidthLimiter::default();
+    for id in 0..=BandwidthLimiter::MAX_BUCKETS as u64 {
+      limiter.take(&[(Key::Connection(id), bandwidth(1000))], false, 0);
+    }
+    assert!(limiter.buckets.lock().unwrap().map.is_empty());
+    for id in 0..=BandwidthLimiter::MAX_BUCKETS as u64 {
+      limiter.take(&[(Key::Connection(id), bandwidth(1000))], false, 0);
+    }
+    assert_eq!(
+      limiter.buckets.lock().unwrap().map.len(),
+      BandwidthLimiter::MAX_BUCKETS + 1
+    );
+  }
+
+  #[tokio::test]
+  async fn throttle_limits_throughput() {
+    let limiter = BandwidthLimiter::default();
+    let connection = Connection::new(Some([127, 0, 0, 1].into()), false);
+
+    let chunks = futures::stream::iter(vec![Ok(Bytes::from(vec![0; 30 * 1024]))]);
+
+    let mut throttle = limiter.throttle(
+      chunks,
+      Some(&connection),
+      false,
+      BandwidthLimits {
+        connection: Some(bandwidth(10 * 1024)),
+        client: None,
+        global: None,
+      },
+    );
+
+    let start = Instant::now();
+    let mut total = 0;
+    while let Some(piece) = throttle.next().await {
+      let piece = piece.unwrap();
+      assert!(piece.len() <= 1024);
+      total += piece.len();
+    }
+
+    assert_eq!(total, 30 * 1024);
+
+    // The first 10 KiB are covered by the burst, the rest takes two seconds.
+    let elapsed = start.elapsed();
+    assert!(
+      elapsed >= Duration::from_millis(1900) && elapsed < Duration::from_secs(5),
+      "elapsed: {:?}",
+      elapsed
+    );
+  }
+
+  #[tokio::test]
+  async fn unlimited_streams_are_passed_through() {
+    let limiter = BandwidthLimiter::default();
+    let chunks = futures::stream::iter(vec![Ok(Bytes::from(vec![0; 1024 * 1024]))]);
+    let mut throttle = limiter.throttle(chunks, None, true, BandwidthLimits::default());
+    assert_eq!(throttle.next().await.unwrap().unwrap().len(), 1024 * 1024);
+    assert!(throttle.next().await.is_none());
+  }
+}
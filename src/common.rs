@@ -1,16 +1,20 @@
 pub(crate) use {
   crate::{
+    archive::Archive,
     arguments::Arguments,
     display_size::DisplaySize,
+    display_time::DisplayTime,
     environment::Environment,
     error::{self, Error, Result},
     error_page, html,
     https_redirect_service::HttpsRedirectService,
     https_request_handler::HttpsRequestHandler,
     input_path::InputPath,
+    query_parameter::query_parameter,
     redirect::redirect,
     request_handler::RequestHandler,
     server::Server,
+    shutdown_signal::shutdown_signal,
     stderr::Stderr,
   },
   clap::Parser,
@@ -31,25 +35,23 @@ pub(crate) use {
   serde::Deserialize,
   snafu::{IntoError, ResultExt},
   std::{
+    collections::HashSet,
     convert::Infallible,
     env,
     ffi::OsString,
     fmt::{self, Display, Formatter},
     fs::{self, FileType},
-    future,
+    future::{self, Future},
     io::{self, Write},
-    mem::MaybeUninit,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
     path::{Path, PathBuf},
     pin::Pin,
     str,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
   },
 };
 
 #[cfg(test)]
-pub(crate) use ::{
-  std::{future::Future, time::Duration},
-  tempfile::TempDir,
-};
+pub(crate) use tempfile::TempDir;
@@ -1,16 +1,17 @@
-use crate::common::*;
+use {crate::common::*, crate::locale::Locale};
 
 pub(crate) trait DisplaySize {
-  fn display_size(self) -> Wrapper;
+  /// Display with the decimal separator of `locale`.
+  fn display_size(self, locale: Locale) -> Wrapper;
 }
 
 impl DisplaySize for u64 {
-  fn display_size(self) -> Wrapper {
-    Wrapper(self)
+  fn display_size(self, locale: Locale) -> Wrapper {
+    Wrapper(self, locale)
   }
 }
 
-pub(crate) struct Wrapper(u64);
+pub(crate) struct Wrapper(u64, Locale);
 
 impl Display for Wrapper {
   fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -20,14 +21,19 @@ impl Display for Wrapper {
       .iter()
       .enumerate()
       .map(|(i, suffix)| (1024u64.pow(i as u32), suffix))
-      .filter(|(power, _)| self.0 >= power - 1)
-      .last()
+      .rfind(|(power, _)| self.0 >= power - 1)
       .unwrap();
 
     if power == 1 {
       write!(f, "{} {}", self.0, suffix)?;
     } else {
-      write!(f, "{:.1} {}", self.0 as f64 / power as f64, suffix)?;
+      write!(
+        f,
+        "{} {}",
+        format!("{:.1}", self.0 as f64 / power as f64)
+          .replace('.', self.1.number_format().decimal()),
+        suffix
+      )?;
     }
 
     Ok(())
@@ -40,31 +46,37 @@ mod tests {
 
   #[test]
   fn zero() {
-    assert_eq!(0u64.display_size().to_string(), "0 B");
+    assert_eq!(0u64.display_size(Locale::En).to_string(), "0 B");
   }
 
   #[test]
   fn halfway_to_1kib() {
-    assert_eq!(512.display_size().to_string(), "512 B");
+    assert_eq!(512.display_size(Locale::En).to_string(), "512 B");
   }
 
   #[test]
   fn kib() {
-    assert_eq!(2u64.pow(10).display_size().to_string(), "1.0 KiB");
+    assert_eq!(2u64.pow(10).display_size(Locale::En).to_string(), "1.0 KiB");
   }
 
   #[test]
   fn halfway_to_2kib() {
-    assert_eq!((1024 + 512).display_size().to_string(), "1.5 KiB");
+    assert_eq!((1024 + 512).display_size(Locale::En).to_string(), "1.5 KiB");
   }
 
   #[test]
   fn remainder() {
-    assert_eq!(1025.display_size().to_string(), "1.0 KiB");
+    assert_eq!(1025.display_size(Locale::En).to_string(), "1.0 KiB");
+  }
+
+  #[test]
+  fn localized_decimal_separator() {
+    assert_eq!((1024 + 512).display_size(Locale::De).to_string(), "1,5 KiB");
+    assert_eq!(512.display_size(Locale::Es).to_string(), "512 B");
   }
 
   #[test]
   fn max() {
-    assert_eq!(u64::MAX.display_size().to_string(), "16.0 EiB");
+    assert_eq!(u64::MAX.display_size(Locale::En).to_string(), "16.0 EiB");
   }
 }
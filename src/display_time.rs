@@ -0,0 +1,84 @@
+use {crate::common::*, std::time::SystemTime};
+
+pub(crate) trait DisplayTime {
+  fn display_time(self) -> Wrapper;
+}
+
+impl DisplayTime for SystemTime {
+  fn display_time(self) -> Wrapper {
+    Wrapper(self)
+  }
+}
+
+/// Displays a time as `YYYY-MM-DD HH:MM` in UTC.
+pub(crate) struct Wrapper(SystemTime);
+
+impl Display for Wrapper {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    let seconds = match self.0.duration_since(SystemTime::UNIX_EPOCH) {
+      Ok(duration) => duration.as_secs(),
+      Err(_) => 0,
+    };
+
+    let days = seconds / 86400;
+    let minutes = seconds % 86400 / 60;
+
+    // Convert days since the epoch to a proleptic Gregorian date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    write!(
+      f,
+      "{:04}-{:02}-{:02} {:02}:{:02}",
+      year,
+      month,
+      day,
+      minutes / 60,
+      minutes % 60
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn display(seconds: u64) -> String {
+    (SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+      .display_time()
+      .to_string()
+  }
+
+  #[test]
+  fn epoch() {
+    assert_eq!(display(0), "1970-01-01 00:00");
+  }
+
+  #[test]
+  fn leap_day() {
+    assert_eq!(display(951_782_400 + 59), "2000-02-29 00:00");
+  }
+
+  #[test]
+  fn time_of_day() {
+    assert_eq!(display(1_700_000_000), "2023-11-14 22:13");
+  }
+
+  #[test]
+  fn before_epoch() {
+    assert_eq!(
+      (SystemTime::UNIX_EPOCH - Duration::from_secs(1))
+        .display_time()
+        .to_string(),
+      "1970-01-01 00:00"
+    );
+  }
+}
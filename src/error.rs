@@ -1,11 +1,14 @@
 use {
   crate::common::*,
+  crate::listeners::Peer,
   color_backtrace::BacktracePrinter,
   opuza_monero_client::OpuzaRpcError,
+  rustls_acme::EventError,
   snafu::{ErrorCompat, Snafu},
   std::{path::MAIN_SEPARATOR, str::Utf8Error},
   termcolor::WriteColor,
   tokio::task::JoinError,
+  tokio_rustls::rustls,
 };
 
 pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
@@ -13,6 +16,12 @@ pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub(crate) enum Error {
+  #[snafu(display("ACME certificate management for {} failed: {}", domains, source))]
+  Acme {
+    backtrace: Backtrace,
+    domains: String,
+    source: Box<EventError<io::Error, io::Error>>,
+  },
   #[snafu(display("Failed to resolve `{}` to an IP address: {}", input, source))]
   AddressResolutionIo {
     backtrace: Backtrace,
@@ -21,6 +30,21 @@ pub(crate) enum Error {
   },
   #[snafu(display("`{}` did not resolve to an IP address", input))]
   AddressResolutionNoAddresses { input: String, backtrace: Backtrace },
+  #[snafu(display("Unsupported archive format `{}`, expected `zip` or `tar.gz`", format))]
+  ArchiveFormat {
+    backtrace: Backtrace,
+    format: String,
+  },
+  #[snafu(display("I/O error writing archive: {}", source))]
+  ArchiveIo {
+    backtrace: Backtrace,
+    source: io::Error,
+  },
+  #[snafu(display("Error writing ZIP archive: {}", source))]
+  ArchiveZip {
+    backtrace: Backtrace,
+    source: async_zip::error::ZipError,
+  },
   #[snafu(context(false), display("{}", source))]
   Clap {
     backtrace: Backtrace,
@@ -95,6 +119,14 @@ pub(crate) enum Error {
     r_hash: [u8; 32],
     request_tail: String,
   },
+  #[snafu(display(
+    "Too many invoices requested, retry in {} seconds",
+    retry_after.as_secs_f64().ceil()
+  ))]
+  InvoiceRateLimit {
+    backtrace: Backtrace,
+    retry_after: Duration,
+  },
   #[snafu(display("Invoice request requires LND client configuration: {}", uri_path))]
   LndNotConfiguredInvoiceRequest {
     backtrace: Backtrace,
@@ -134,17 +166,67 @@ pub(crate) enum Error {
   },
   #[snafu(display("URI path did not match any route: {}", uri_path))]
   RouteNotFound { uri_path: String },
+  #[snafu(display("Failed to serve HTTPS connection from `{}`: {}", peer, source))]
+  ServeConnection {
+    backtrace: Backtrace,
+    peer: Peer,
+    source: hyper::Error,
+  },
   #[snafu(display("Failed running HTTP server: {}", source))]
   ServerRun {
     backtrace: Backtrace,
     source: hyper::Error,
   },
+  #[snafu(display("Failed to install signal handler: {}", source))]
+  SignalHandlerInstall {
+    backtrace: Backtrace,
+    source: io::Error,
+  },
+  #[snafu(display("No site is served for host `{}`", host))]
+  SiteUnknown { backtrace: Backtrace, host: String },
+  #[snafu(display("Host `{}` appears more than once in `{}`", host, path.display()))]
+  SitesHostDuplicate {
+    backtrace: Backtrace,
+    host: String,
+    path: PathBuf,
+  },
+  #[snafu(display(
+    "Site for `{}` in `{}` has no hosts",
+    directory.display(),
+    path.display()
+  ))]
+  SitesHostsMissing {
+    backtrace: Backtrace,
+    directory: PathBuf,
+    path: PathBuf,
+  },
+  #[snafu(display("No sites in `{}`", path.display()))]
+  SitesMissing { backtrace: Backtrace, path: PathBuf },
   #[snafu(display("I/O error on socket address `{}`: {}", socket_addr, source))]
   SocketIo {
     backtrace: Backtrace,
     socket_addr: SocketAddr,
     source: io::Error,
   },
+  #[snafu(display("Failed to look up `{}` for `--socket-owner`: {}", name, source))]
+  SocketOwnerLookup {
+    backtrace: Backtrace,
+    name: String,
+    source: io::Error,
+  },
+  #[snafu(display("Unknown {} `{}` in `--socket-owner`", kind, name))]
+  SocketOwnerUnknown {
+    backtrace: Backtrace,
+    kind: &'static str,
+    name: String,
+  },
+  #[snafu(display(
+    "Unsupported sort key `{}`, expected `name`, `size`, `mtime` or `price`",
+    key
+  ))]
+  SortKey { backtrace: Backtrace, key: String },
+  #[snafu(display("Unsupported sort order `{}`, expected `asc` or `desc`", order))]
+  SortOrder { backtrace: Backtrace, order: String },
   #[snafu(display("Static asset not found: {}", uri_path))]
   StaticAssetNotFound {
     backtrace: Backtrace,
@@ -155,8 +237,89 @@ pub(crate) enum Error {
     backtrace: Backtrace,
     source: io::Error,
   },
+  #[snafu(display("Invalid template `{}`: {}", path.display(), message))]
+  ThemeTemplate {
+    backtrace: Backtrace,
+    path: PathBuf,
+    message: String,
+  },
+  #[snafu(display(
+    "Thumbnail cache directory `{}` may not be inside the served directory",
+    path.display()
+  ))]
+  ThumbnailCacheDirectory { backtrace: Backtrace, path: PathBuf },
+  #[snafu(display("Failed to generate thumbnail of `{}`: {}", path.display(), source))]
+  ThumbnailImage {
+    backtrace: Backtrace,
+    path: PathBuf,
+    source: image::ImageError,
+  },
+  #[snafu(display("Thumbnails are not supported for `{}`", path.display()))]
+  ThumbnailUnsupported { backtrace: Backtrace, path: PathBuf },
+  #[snafu(display("No certificates found in `{}`", path.display()))]
+  TlsCertificateMissing { backtrace: Backtrace, path: PathBuf },
+  #[snafu(display(
+    "Failed to use TLS certificate `{}` with key `{}`: {}",
+    certificate.display(),
+    key.display(),
+    source
+  ))]
+  TlsConfig {
+    backtrace: Backtrace,
+    certificate: PathBuf,
+    key: PathBuf,
+    source: rustls::Error,
+  },
+  #[snafu(display(
+    "TLS key `{}` does not belong to the first certificate in `{}`",
+    key.display(),
+    certificate.display()
+  ))]
+  TlsKeyMismatch {
+    backtrace: Backtrace,
+    certificate: PathBuf,
+    key: PathBuf,
+  },
+  #[snafu(display("TLS handshake with `{}` failed: {}", peer, source))]
+  TlsHandshake {
+    backtrace: Backtrace,
+    peer: Peer,
+    source: io::Error,
+  },
+  #[snafu(display(
+    "TLS handshake with `{}` timed out after {} seconds",
+    peer,
+    timeout.as_secs()
+  ))]
+  TlsHandshakeTimeout {
+    backtrace: Backtrace,
+    peer: Peer,
+    timeout: Duration,
+  },
+  #[snafu(display("Failed to read PEM file `{}`: {}", path.display(), source))]
+  TlsPem {
+    backtrace: Backtrace,
+    path: PathBuf,
+    source: rustls::pki_types::pem::Error,
+  },
   #[snafu(display("Forbidden access to escaping symlink: `{}`", path.display()))]
   SymlinkAccess { backtrace: Backtrace, path: PathBuf },
+  #[snafu(display(
+    "Unix domain socket `{}` is already in use by another process",
+    path.display()
+  ))]
+  UnixSocketInUse { backtrace: Backtrace, path: PathBuf },
+  #[snafu(display("I/O error on Unix domain socket `{}`: {}", path.display(), source))]
+  UnixSocketIo {
+    backtrace: Backtrace,
+    path: PathBuf,
+    source: io::Error,
+  },
+  #[snafu(display(
+    "Refusing to replace `{}` with a Unix domain socket, because it is not a socket",
+    path.display()
+  ))]
+  UnixSocketNotSocket { backtrace: Backtrace, path: PathBuf },
 }
 
 impl Error {
@@ -166,18 +329,27 @@ impl Error {
       FilesystemIo { source, .. } if source.kind() == io::ErrorKind::NotFound => {
         StatusCode::NOT_FOUND
       }
-      InvalidFilePath { .. }
+      ArchiveFormat { .. }
+      | InvalidFilePath { .. }
       | InvalidUriPath { .. }
       | InvoiceId { .. }
-      | InvoicePathMismatch { .. } => StatusCode::BAD_REQUEST,
+      | InvoicePathMismatch { .. }
+      | SortKey { .. }
+      | SortOrder { .. } => StatusCode::BAD_REQUEST,
       HiddenFileAccess { .. }
       | InvoiceNotFound { .. }
       | LndNotConfiguredInvoiceRequest { .. }
       | RouteNotFound { .. }
       | StaticAssetNotFound { .. }
-      | SymlinkAccess { .. } => StatusCode::NOT_FOUND,
-      AddressResolutionIo { .. }
+      | SymlinkAccess { .. }
+      | ThumbnailUnsupported { .. } => StatusCode::NOT_FOUND,
+      InvoiceRateLimit { .. } => StatusCode::TOO_MANY_REQUESTS,
+      SiteUnknown { .. } => StatusCode::MISDIRECTED_REQUEST,
+      Acme { .. }
+      | AddressResolutionIo { .. }
       | AddressResolutionNoAddresses { .. }
+      | ArchiveIo { .. }
+      | ArchiveZip { .. }
       | Clap { .. }
       | ConfigDeserialize { .. }
       | ConfigMissingBasePrice { .. }
@@ -190,13 +362,40 @@ impl Error {
       | LndRpcStatus { .. }
       | PaymentRequestTooLongForQrCode { .. }
       | RequestHandlerPanic { .. }
+      | ServeConnection { .. }
       | ServerRun { .. }
+      | SignalHandlerInstall { .. }
+      | SitesHostDuplicate { .. }
+      | SitesHostsMissing { .. }
+      | SitesMissing { .. }
       | SocketIo { .. }
-      | StderrWrite { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+      | SocketOwnerLookup { .. }
+      | SocketOwnerUnknown { .. }
+      | StderrWrite { .. }
+      | ThemeTemplate { .. }
+      | ThumbnailCacheDirectory { .. }
+      | ThumbnailImage { .. }
+      | TlsCertificateMissing { .. }
+      | TlsConfig { .. }
+      | TlsHandshake { .. }
+      | TlsHandshakeTimeout { .. }
+      | TlsKeyMismatch { .. }
+      | TlsPem { .. }
+      | UnixSocketInUse { .. }
+      | UnixSocketIo { .. }
+      | UnixSocketNotSocket { .. } => StatusCode::INTERNAL_SERVER_ERROR,
       Custom { status_code, .. } => *status_code,
     }
   }
 
+  /// How long a client should wait before retrying, sent as `Retry-After`.
+  pub(crate) fn retry_after(&self) -> Option<Duration> {
+    match self {
+      Self::InvoiceRateLimit { retry_after, .. } => Some(*retry_after),
+      _ => None,
+    }
+  }
+
   pub(crate) fn internal(message: impl Into<String>) -> Self {
     Internal { message }.build()
   }
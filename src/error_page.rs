@@ -1,21 +1,76 @@
-use {crate::common::*, maud::html};
+use {
+  crate::{common::*, locale::Locale, theme::Theme},
+  maud::html,
+};
 
 pub(crate) fn map_error(
   mut stderr: Stderr,
+  theme: &Theme,
+  locale: Locale,
   result: Result<Response<Body>, Error>,
 ) -> Response<Body> {
   result.unwrap_or_else(|error| {
     error.print_backtrace(&mut stderr);
     writeln!(stderr, "{}", error).ok();
+    let title = locale.messages().status(error.status());
     let mut response = html::wrap_body(
-      error.status().canonical_reason().unwrap_or("Error"),
+      theme,
+      locale,
+      title,
       html! {
         h1 {
-          (error.status())
+          (error.status().as_u16()) " " (title)
         }
       },
     );
     *response.status_mut() = error.status();
+    if let Some(retry_after) = error.retry_after() {
+      response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+      );
+    }
     response
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rate_limit_errors_set_retry_after() {
+    let response = map_error(
+      Stderr::test(),
+      &Theme::default(),
+      Locale::En,
+      Err(
+        error::InvoiceRateLimit {
+          retry_after: Duration::from_millis(2500),
+        }
+        .build(),
+      ),
+    );
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+  }
+
+  #[tokio::test]
+  async fn error_titles_are_localized() {
+    let response = map_error(
+      Stderr::test(),
+      &Theme::default(),
+      Locale::De,
+      Err(
+        error::RouteNotFound {
+          uri_path: "/foo".to_owned(),
+        }
+        .build(),
+      ),
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(str::from_utf8(&body)
+      .unwrap()
+      .contains("<h1>404 Nicht gefunden</h1>"));
+  }
+}
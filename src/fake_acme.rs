@@ -0,0 +1,259 @@
+use {
+  crate::common::*,
+  base64::prelude::*,
+  hyper::{server::conn::Http, service::service_fn, Method},
+  serde_json::{json, Value},
+  std::{collections::HashMap, sync::Mutex},
+  tokio::{net::TcpListener, sync::oneshot},
+  tokio_rustls::{
+    rustls::{
+      pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+      ServerConfig,
+    },
+    TlsAcceptor,
+  },
+};
+
+/// A stand-in for an ACME server, served over HTTPS with a certificate
+/// signed by `root.pem`. It hands out an HTTP-01 challenge for every order,
+/// and keeps the authorizations pending, so that their tokens stay answerable.
+pub(crate) struct FakeAcme {
+  state: Arc<Mutex<State>>,
+  directory_url: String,
+  tempdir: TempDir,
+  shutdown: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Default)]
+struct State {
+  /// The thumbprints of the account keys, indexed by account.
+  accounts: Vec<String>,
+  /// The key authorizations of the challenges whose validation was asked
+  /// for, by token.
+  key_authorizations: HashMap<String, String>,
+  orders: usize,
+}
+
+impl FakeAcme {
+  pub(crate) fn new() -> Self {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+
+    let root = {
+      let mut params: CertificateParams = Default::default();
+      params
+        .distinguished_name
+        .push(DnType::CommonName, "opuza test ACME root");
+      params.key_pair = Some(KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap());
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      Certificate::from_params(params).unwrap()
+    };
+    let leaf =
+      Certificate::from_params(CertificateParams::new(vec!["127.0.0.1".to_owned()])).unwrap();
+
+    let tempdir = TempDir::new().unwrap();
+    fs::write(
+      tempdir.path().join("root.pem"),
+      root.serialize_pem().unwrap(),
+    )
+    .unwrap();
+
+    let config = ServerConfig::builder()
+      .with_no_client_auth()
+      .with_single_cert(
+        vec![CertificateDer::from(
+          leaf.serialize_der_with_signer(&root).unwrap(),
+        )],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(leaf.serialize_private_key_der())),
+      )
+      .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let state = Arc::new(Mutex::new(State::default()));
+    let (shutdown, mut receiver) = oneshot::channel::<()>();
+
+    let service_state = state.clone();
+    let service_url = url.clone();
+    std::thread::spawn(move || {
+      tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+          listener.set_nonblocking(true).unwrap();
+          let listener = TcpListener::from_std(listener).unwrap();
+          loop {
+            let stream = tokio::select! {
+              result = listener.accept() => result.unwrap().0,
+              _ = &mut receiver => return,
+            };
+            let acceptor = acceptor.clone();
+            let state = service_state.clone();
+            let url = service_url.clone();
+            tokio::spawn(async move {
+              if let Ok(stream) = acceptor.accept(stream).await {
+                Http::new()
+                  .serve_connection(
+                    stream,
+                    service_fn(move |request| {
+                      let state = state.clone();
+                      let url = url.clone();
+                      async move { Ok::<_, Infallible>(Self::handle(&state, &url, request).await) }
+                    }),
+                  )
+                  .await
+                  .ok();
+              }
+            });
+          }
+        });
+    });
+
+    Self {
+      state,
+      directory_url: format!("{}/directory", url),
+      tempdir,
+      shutdown: Some(shutdown),
+    }
+  }
+
+  pub(crate) fn directory_url(&self) -> &str {
+    &self.directory_url
+  }
+
+  /// The root certificate that the server's certificate is signed by.
+  pub(crate) fn root_certificate(&self) -> PathBuf {
+    self.tempdir.path().join("root.pem")
+  }
+
+  /// Wait until the client has asked for the challenge with `token` to be
+  /// validated, so it's ready to answer it, and return the key authorization
+  /// it should answer with.
+  pub(crate) async fn key_authorization(&self, token: &str) -> String {
+    for _ in 0..100 {
+      if let Some(key_authorization) = self.state.lock().unwrap().key_authorizations.get(token) {
+        return key_authorization.clone();
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("challenge `{}` was not triggered", token);
+  }
+
+  async fn handle(state: &Mutex<State>, url: &str, request: Request<Body>) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+    let mut state = state.lock().unwrap();
+
+    let (status, location, body) = match (&method, path.as_str()) {
+      (&Method::GET, "/directory") => (
+        StatusCode::OK,
+        None,
+        json!({
+          "newNonce": format!("{}/nonce", url),
+          "newAccount": format!("{}/account", url),
+          "newOrder": format!("{}/order", url),
+        }),
+      ),
+      (&Method::HEAD, "/nonce") => (StatusCode::OK, None, Value::Null),
+      (&Method::POST, "/account") => {
+        let account = state.accounts.len();
+        state
+          .accounts
+          .push(Self::thumbprint(&Self::protected(&body)["jwk"]));
+        (
+          StatusCode::CREATED,
+          Some(format!("{}/account/{}", url, account)),
+          json!({}),
+        )
+      }
+      (&Method::POST, "/order") => {
+        let order = state.orders;
+        state.orders += 1;
+        (
+          StatusCode::CREATED,
+          Some(format!("{}/order/{}", url, order)),
+          json!({
+            "status": "pending",
+            "authorizations": [format!("{}/authorization/{}", url, order)],
+            "finalize": format!("{}/finalize/{}", url, order),
+          }),
+        )
+      }
+      (&Method::POST, path) if path.starts_with("/authorization/") => {
+        let order = path.trim_start_matches("/authorization/");
+        (
+          StatusCode::OK,
+          None,
+          json!({
+            "status": "pending",
+            "identifier": { "type": "dns", "value": "localhost" },
+            "challenges": [{
+              "type": "http-01",
+              "url": format!("{}/challenge/{}", url, order),
+              "token": format!("token-{}", order),
+            }],
+          }),
+        )
+      }
+      (&Method::POST, path) if path.starts_with("/challenge/") => {
+        let token = format!("token-{}", path.trim_start_matches("/challenge/"));
+        let account = Self::protected(&body)["kid"]
+          .as_str()
+          .unwrap()
+          .rsplit('/')
+          .next()
+          .unwrap()
+          .parse::<usize>()
+          .unwrap();
+        let key_authorization = format!("{}.{}", token, state.accounts[account]);
+        state.key_authorizations.insert(token, key_authorization);
+        (StatusCode::OK, None, json!({}))
+      }
+      _ => (StatusCode::NOT_FOUND, None, json!({})),
+    };
+
+    let mut response = Response::builder()
+      .status(status)
+      .header("replay-nonce", "nonce");
+    if let Some(location) = location {
+      response = response.header(header::LOCATION, location);
+    }
+    response
+      .body(match body {
+        Value::Null => Body::empty(),
+        body => Body::from(body.to_string()),
+      })
+      .unwrap()
+  }
+
+  /// The protected header of the JWS `body`.
+  fn protected(body: &[u8]) -> Value {
+    let body = serde_json::from_slice::<Value>(body).unwrap();
+    let protected = BASE64_URL_SAFE_NO_PAD
+      .decode(body["protected"].as_str().unwrap())
+      .unwrap();
+    serde_json::from_slice(&protected).unwrap()
+  }
+
+  /// The RFC 7638 thumbprint of the elliptic curve key `jwk`.
+  fn thumbprint(jwk: &Value) -> String {
+    let members = format!(
+      r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+      jwk["crv"].as_str().unwrap(),
+      jwk["kty"].as_str().unwrap(),
+      jwk["x"].as_str().unwrap(),
+      jwk["y"].as_str().unwrap(),
+    );
+    BASE64_URL_SAFE_NO_PAD.encode(openssl::sha::sha256(members.as_bytes()))
+  }
+}
+
+impl Drop for FakeAcme {
+  fn drop(&mut self) {
+    if let Some(shutdown) = self.shutdown.take() {
+      shutdown.send(()).ok();
+    }
+  }
+}
@@ -0,0 +1,185 @@
+use {
+  crate::common::*,
+  hyper::service::{make_service_fn, service_fn},
+  monero::{Address, KeyPair, Network, PrivateKey},
+  serde_json::{json, Value},
+  std::{collections::HashMap, sync::Mutex},
+  tokio::sync::oneshot,
+};
+
+/// An in-memory stand-in for `monero-wallet-rpc`, implementing the JSON-RPC
+/// methods `opuza` uses to create, look up, and settle invoices.
+pub(crate) struct FakeWallet {
+  state: Arc<Mutex<State>>,
+  url: String,
+  shutdown: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Default)]
+struct State {
+  accounts: Vec<u64>,
+  addresses: Vec<(Address, String)>,
+  attributes: HashMap<String, String>,
+}
+
+impl FakeWallet {
+  pub(crate) fn new() -> Self {
+    let state = Arc::new(Mutex::new(State::default()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (shutdown, receiver) = oneshot::channel::<()>();
+
+    let service_state = state.clone();
+    std::thread::spawn(move || {
+      tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+          listener.set_nonblocking(true).unwrap();
+          hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_| {
+              let state = service_state.clone();
+              async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                  let state = state.clone();
+                  async move { Ok::<_, Infallible>(Self::handle(&state, request).await) }
+                }))
+              }
+            }))
+            .with_graceful_shutdown(async {
+              receiver.await.ok();
+            })
+            .await
+            .unwrap();
+        });
+    });
+
+    Self {
+      state,
+      url,
+      shutdown: Some(shutdown),
+    }
+  }
+
+  pub(crate) fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// The accounts that addresses were created in, in order of creation.
+  pub(crate) fn accounts(&self) -> Vec<u64> {
+    self.state.lock().unwrap().accounts.clone()
+  }
+
+  /// Mark the invoice with `payment_hash` as paid, as `update_payments` would
+  /// once it sees a sufficient transfer.
+  pub(crate) fn settle(&self, payment_hash: &str) {
+    let mut state = self.state.lock().unwrap();
+    for (_, label) in &mut state.addresses {
+      if let Ok(mut invoice) = serde_json::from_str::<Value>(label) {
+        if invoice["payment_hash"] == payment_hash {
+          invoice["is_settled"] = true.into();
+          *label = invoice.to_string();
+          return;
+        }
+      }
+    }
+    panic!("no invoice with payment hash {}", payment_hash);
+  }
+
+  async fn handle(state: &Mutex<State>, request: Request<Body>) -> Response<Body> {
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+    let call = serde_json::from_slice::<Value>(&body).unwrap();
+    let params = &call["params"];
+    let mut state = state.lock().unwrap();
+
+    let result = match call["method"].as_str().unwrap() {
+      "get_height" => Ok(json!({ "height": 1 })),
+      "get_transfers" => Ok(json!({})),
+      "create_address" => {
+        let index = state.addresses.len();
+        let address = Self::address(index);
+        state
+          .accounts
+          .push(params["account_index"].as_u64().unwrap());
+        state
+          .addresses
+          .push((address, params["label"].as_str().unwrap_or("").to_owned()));
+        Ok(json!({ "address": address.to_string(), "address_index": index }))
+      }
+      "label_address" => {
+        let index = params["index"]["minor"].as_u64().unwrap() as usize;
+        state.addresses[index].1 = params["label"].as_str().unwrap().to_owned();
+        Ok(json!({}))
+      }
+      "get_address" => {
+        let index = params["address_index"][0].as_u64().unwrap() as usize;
+        let (address, label) = &state.addresses[index];
+        Ok(json!({
+          "address": address.to_string(),
+          "addresses": [{
+            "address": address.to_string(),
+            "address_index": index,
+            "label": label,
+            "used": false,
+          }],
+        }))
+      }
+      "get_address_index" => state
+        .addresses
+        .iter()
+        .position(|(address, _)| address.to_string() == params["address"])
+        .map(|index| json!({ "index": { "major": 0, "minor": index } }))
+        .ok_or("unknown address"),
+      "set_attribute" => {
+        state.attributes.insert(
+          params["key"].as_str().unwrap().to_owned(),
+          params["value"].as_str().unwrap().to_owned(),
+        );
+        Ok(json!({}))
+      }
+      "get_attribute" => state
+        .attributes
+        .get(params["key"].as_str().unwrap())
+        .map(|value| json!({ "value": value }))
+        .ok_or("attribute not found"),
+      method => panic!("unexpected wallet RPC method `{}`", method),
+    };
+
+    let response = match result {
+      Ok(result) => json!({ "jsonrpc": "2.0", "id": call["id"], "result": result }),
+      Err(message) => json!({
+        "jsonrpc": "2.0",
+        "id": call["id"],
+        "error": { "code": -1, "message": message },
+      }),
+    };
+
+    Response::new(Body::from(response.to_string()))
+  }
+
+  fn address(index: usize) -> Address {
+    let key = |offset: u8| {
+      let mut bytes = [0; 32];
+      bytes[0] = offset;
+      bytes[1..9].copy_from_slice(&(index as u64).to_le_bytes());
+      PrivateKey::from_slice(&bytes).unwrap()
+    };
+    Address::from_keypair(
+      Network::Mainnet,
+      &KeyPair {
+        spend: key(1),
+        view: key(2),
+      },
+    )
+  }
+}
+
+impl Drop for FakeWallet {
+  fn drop(&mut self) {
+    if let Some(shutdown) = self.shutdown.take() {
+      shutdown.send(()).ok();
+    }
+  }
+}
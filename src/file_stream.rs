@@ -1,63 +1,85 @@
 use {
-  crate::common::*,
-  hyper::body::Bytes,
-  pin_project::pin_project,
-  tokio::{
-    fs::File,
-    io::{AsyncRead, ReadBuf},
-  },
+  crate::common::*, bytes::BytesMut, hyper::body::Bytes, pin_project::pin_project, tokio::fs::File,
+  tokio_util::io::poll_read_buf,
 };
 
 #[pin_project]
 pub(crate) struct FileStream {
+  buffer: BytesMut,
+  chunk_size: usize,
   #[pin]
   file: File,
   path: InputPath,
 }
 
 impl FileStream {
+  const MIN_CHUNK_SIZE: usize = 16 * 1024;
+  const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
   pub(crate) async fn new(file_path: InputPath) -> Result<Self> {
+    let mut file = File::open(&file_path)
+      .await
+      .with_context(|| Error::filesystem_io(&file_path))?;
+
+    let len = file
+      .metadata()
+      .await
+      .with_context(|| Error::filesystem_io(&file_path))?
+      .len();
+
+    let chunk_size = Self::chunk_size(len);
+
+    file.set_max_buf_size(chunk_size);
+
     Ok(Self {
-      file: File::open(&file_path)
-        .await
-        .with_context(|| Error::filesystem_io(&file_path))?,
+      buffer: BytesMut::with_capacity(chunk_size),
+      chunk_size,
+      file,
       path: file_path,
     })
   }
+
+  /// Read small files in a single chunk, and large files in chunks of
+  /// `MAX_CHUNK_SIZE`, so that multi-gigabyte downloads don't pay for a
+  /// read and an allocation every few kilobytes.
+  fn chunk_size(len: u64) -> usize {
+    len.clamp(Self::MIN_CHUNK_SIZE as u64, Self::MAX_CHUNK_SIZE as u64) as usize
+  }
 }
 
 impl Stream for FileStream {
   type Item = Result<Bytes>;
 
   fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-    let data = &mut [MaybeUninit::uninit(); 8 * 1024];
-    let mut buf = ReadBuf::uninit(data);
-
     let projected = self.project();
 
-    let file = projected.file;
-    let path = projected.path;
+    let buffer = projected.buffer;
 
-    let poll = file
-      .poll_read(cx, &mut buf)
-      .map(|result| result.with_context(|| Error::filesystem_io(path)))?;
-
-    if poll.is_pending() {
-      return Poll::Pending;
+    // Once the chunks handed out by previous polls have been dropped, this
+    // reclaims their memory instead of allocating a new buffer.
+    if buffer.capacity() < *projected.chunk_size {
+      buffer.reserve(*projected.chunk_size);
     }
 
-    if buf.filled().is_empty() {
+    let path = projected.path;
+
+    let read = match poll_read_buf(projected.file, cx, buffer) {
+      Poll::Pending => return Poll::Pending,
+      Poll::Ready(result) => result.with_context(|| Error::filesystem_io(path))?,
+    };
+
+    if read == 0 {
       return Poll::Ready(None);
     }
 
-    Poll::Ready(Some(Ok(Bytes::copy_from_slice(buf.filled()))))
+    Poll::Ready(Some(Ok(buffer.split().freeze())))
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use futures::StreamExt;
+  use {crate::locale::Locale, futures::StreamExt};
 
   #[tokio::test]
   async fn file_stream_yields_file_contents() {
@@ -80,4 +102,73 @@ mod tests {
 
     assert_eq!(output, input);
   }
+
+  #[test]
+  fn chunk_size_depends_on_file_size() {
+    assert_eq!(FileStream::chunk_size(0), FileStream::MIN_CHUNK_SIZE);
+    assert_eq!(FileStream::chunk_size(100 * 1024), 100 * 1024);
+    assert_eq!(FileStream::chunk_size(u64::MAX), FileStream::MAX_CHUNK_SIZE);
+  }
+
+  #[tokio::test]
+  async fn large_files_are_read_in_large_chunks() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let file_path = InputPath::new_unchecked(tempdir.path(), "large");
+
+    let input = (0..8 * 1024 * 1024)
+      .map(|i| (i % 251) as u8)
+      .collect::<Vec<u8>>();
+
+    std::fs::write(&file_path, &input).unwrap();
+
+    let mut stream = FileStream::new(file_path).await.unwrap();
+
+    let mut output = Vec::new();
+    let mut chunks = 0;
+
+    while let Some(result) = stream.next().await {
+      output.extend(result.unwrap());
+      chunks += 1;
+    }
+
+    assert!(output == input, "file contents were corrupted");
+    assert!(chunks <= 16, "file was read in {} chunks", chunks);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  #[ignore = "benchmark, run with `cargo test --release -- --ignored sustained_throughput`"]
+  async fn sustained_throughput() {
+    use std::{io::Write, time::Instant};
+
+    const LEN: usize = 256 * 1024 * 1024;
+    const MIN_BYTES_PER_SECOND: f64 = 100.0 * 1024.0 * 1024.0;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let file_path = InputPath::new_unchecked(tempdir.path(), "large");
+
+    let mut file = std::fs::File::create(&file_path).unwrap();
+    let block = vec![0x15; 1024 * 1024];
+    for _ in 0..LEN / block.len() {
+      file.write_all(&block).unwrap();
+    }
+    file.sync_all().unwrap();
+
+    let start = Instant::now();
+
+    let mut stream = FileStream::new(file_path).await.unwrap();
+    let mut total = 0;
+    while let Some(result) = stream.next().await {
+      total += result.unwrap().len();
+    }
+
+    let bytes_per_second = total as f64 / start.elapsed().as_secs_f64();
+
+    assert_eq!(total, LEN);
+    assert!(
+      bytes_per_second >= MIN_BYTES_PER_SECOND,
+      "throughput of {} per second is below {} per second",
+      (bytes_per_second as u64).display_size(Locale::En),
+      (MIN_BYTES_PER_SECOND as u64).display_size(Locale::En),
+    );
+  }
 }
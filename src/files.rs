@@ -1,6 +1,19 @@
 use qrcodegen::QrCode;
 use {
-  crate::{common::*, file_stream::FileStream, vfs::Vfs},
+  crate::{
+    bandwidth_limiter::{BandwidthLimiter, Connection},
+    common::*,
+    file_stream::FileStream,
+    invoice_limiter::InvoiceLimiter,
+    listing::Listing,
+    locale::{Locale, Messages},
+    page::Cursor,
+    search_index::SearchIndex,
+    sort::{Sort, SortKey, SortOrder},
+    theme::Theme,
+    thumbnails::Thumbnails,
+    vfs::{DirEntry, Vfs, View},
+  },
   maud::html,
   percent_encoding::{AsciiSet, NON_ALPHANUMERIC},
   uuid::Uuid,
@@ -10,16 +23,37 @@ use {
 pub(crate) struct Files {
   vfs: Vfs,
   rpc_client: Option<opuza_monero_client::MoneroRpcClient>,
+  bandwidth_limiter: BandwidthLimiter,
+  invoice_limiter: InvoiceLimiter,
+  search_index: SearchIndex,
+  thumbnails: Thumbnails,
+  theme: Theme,
+  memo_prefix: String,
 }
 
 impl Files {
+  /// Invoice memos start with `memo_prefix`, followed by the path of the
+  /// file, so that an invoice for one site can't be used to download a file
+  /// at the same path on another.
   pub(crate) fn new(
     base_directory: InputPath,
     rpc_client: Option<opuza_monero_client::MoneroRpcClient>,
+    invoice_limiter: InvoiceLimiter,
+    bandwidth_limiter: BandwidthLimiter,
+    thumbnails: Thumbnails,
+    theme: Theme,
+    memo_prefix: String,
   ) -> Self {
+    let vfs = Vfs::new(base_directory.clone());
     Self {
-      vfs: Vfs::new(base_directory),
+      search_index: SearchIndex::new(vfs.clone(), base_directory),
+      vfs,
       rpc_client,
+      bandwidth_limiter,
+      invoice_limiter,
+      thumbnails,
+      theme,
+      memo_prefix,
     }
   }
 
@@ -42,7 +76,34 @@ impl Files {
     }
 
     if file_type.is_dir() {
-      self.serve_dir(tail, &file_path).await
+      match query_parameter(request, "archive") {
+        Some(archive) => {
+          archive
+            .parse::<Archive>()?
+            .serve(
+              &self.vfs,
+              &file_path,
+              &self.bandwidth_limiter,
+              request.extensions().get::<Connection>(),
+            )
+            .await
+        }
+        None => self.serve_dir(request, tail, &file_path).await,
+      }
+    } else if query_parameter(request, "thumbnail").is_some() {
+      self
+        .thumbnails
+        .serve(&file_path, self.vfs.thumbnail_variant(&file_path)?)
+        .await
+    } else if query_parameter(request, "preview").is_some() {
+      let locale = Locale::new(request, self.vfs.lang(&file_path)?);
+      self.serve_preview(locale, tail, &file_path).await
+    } else if Self::is_markdown(file_path.display_path())
+      && query_parameter(request, "raw").is_none()
+      && !self.vfs.paid(&file_path)?
+    {
+      let locale = Locale::new(request, self.vfs.lang(&file_path)?);
+      self.serve_markdown(locale, tail, &file_path).await
     } else {
       self.access_file(request, tail, &file_path).await
     }
@@ -73,61 +134,475 @@ impl Files {
     .remove(b'~');
 
   fn render_index(&self, dir: &InputPath) -> Result<Option<Markup>> {
-    use pulldown_cmark::{html, Options, Parser};
+    Ok(
+      self
+        .vfs
+        .index_file_markdown(dir)?
+        .map(|markdown| Self::render_markdown(&markdown)),
+    )
+  }
 
-    let markdown = match self.vfs.index_file_markdown(dir)? {
-      None => return Ok(None),
-      Some(markdown) => markdown,
-    };
+  fn render_markdown(markdown: &str) -> Markup {
+    use pulldown_cmark::{html, Options, Parser};
 
     let options = Options::ENABLE_FOOTNOTES
       | Options::ENABLE_STRIKETHROUGH
       | Options::ENABLE_TABLES
       | Options::ENABLE_TASKLISTS;
-    let parser = Parser::new_ext(&markdown, options);
+    let parser = Parser::new_ext(markdown, options);
     let mut html = String::new();
     html::push_html(&mut html, parser);
-    Ok(Some(maud::PreEscaped(html)))
+    maud::PreEscaped(html)
   }
 
-  async fn serve_dir(&self, tail: &[&str], dir: &InputPath) -> Result<Response<Body>> {
-    let body = html! {
-      ul class="listing" {
-        @for entry in self.vfs.read_dir(dir).await? {
+  fn is_markdown(path: impl AsRef<Path>) -> bool {
+    path
+      .as_ref()
+      .extension()
+      .map(|extension| extension.eq_ignore_ascii_case("md"))
+      .unwrap_or(false)
+  }
 
-          @let file_name = {
-            let mut file_name = entry.file_name.to_string_lossy().into_owned();
-            if entry.file_type.is_dir() {
-              file_name.push('/');
+  /// Render the Markdown file at `path` as a page. It is served from its own
+  /// URL, so relative links and images inside it resolve as they would on
+  /// the raw file.
+  async fn serve_markdown(
+    &self,
+    locale: Locale,
+    tail: &[&str],
+    path: &InputPath,
+  ) -> Result<Response<Body>> {
+    let messages = locale.messages();
+    let markdown = tokio::fs::read(path)
+      .await
+      .with_context(|| Error::filesystem_io(path))?;
+    let file_name = path
+      .display_path()
+      .file_name()
+      .unwrap_or_default()
+      .to_string_lossy()
+      .into_owned();
+    let raw = format!(
+      "{}?raw",
+      percent_encoding::utf8_percent_encode(&file_name, &Self::ENCODE_CHARACTERS)
+    );
+
+    Ok(html::wrap_body(
+      &self.theme,
+      locale,
+      &format!("/{}", tail.join("")),
+      html! {
+        (Self::breadcrumbs(messages, tail))
+        article class="markdown" {
+          (Self::render_markdown(&String::from_utf8_lossy(&markdown)))
+        }
+        div class="links" {
+          a href=(raw) {
+            (messages.view_source)
+          }
+          a download=(file_name) href=(raw) {
+            (messages.download)
+          }
+        }
+      },
+    ))
+  }
+
+  async fn serve_dir(
+    &self,
+    request: &Request<Body>,
+    tail: &[&str],
+    dir: &InputPath,
+  ) -> Result<Response<Body>> {
+    let config = self.vfs.listing_config(dir)?;
+    let sort = Sort::new(request, config.sort)?;
+    let locale = Locale::new(request, config.lang);
+    let page = self
+      .vfs
+      .read_dir_page(dir, sort, Cursor::new(request), config.page_size)
+      .await?;
+    let previous = page.previous.map(|cursor| cursor.link(request));
+    let next = page.next.map(|cursor| cursor.link(request));
+    let title = format!("/{}", tail.join(""));
+
+    let mut response = if Listing::requested(request) {
+      Listing::new(
+        title,
+        &page.entries,
+        self.render_index(dir)?,
+        previous,
+        next,
+      )
+      .into_response()?
+    } else {
+      let entries = match config.view {
+        View::List => Self::render_list(locale, &page.entries),
+        View::Gallery => Self::render_gallery(locale, &page.entries),
+      };
+      html::wrap_body(
+        &self.theme,
+        locale,
+        &title,
+        html! {
+          (Self::breadcrumbs(locale.messages(), tail))
+          (self.render_listing(locale, dir, sort, entries, previous, next)?)
+        },
+      )
+    };
+
+    response
+      .headers_mut()
+      .append(header::VARY, HeaderValue::from_static("accept"));
+
+    Ok(response)
+  }
+
+  fn render_listing(
+    &self,
+    locale: Locale,
+    dir: &InputPath,
+    sort: Sort,
+    entries: Markup,
+    previous: Option<String>,
+    next: Option<String>,
+  ) -> Result<Markup> {
+    let messages = locale.messages();
+    Ok(html! {
+      (Self::search_form(messages, "", false))
+      div class="sort" {
+        (messages.sort_by)
+        @for key in SortKey::ALL {
+          @let active = sort.key == *key;
+          @let order = if active { sort.order.reverse() } else { SortOrder::Asc };
+          a.active[active] href={"?sort=" (key.as_str()) "&order=" (order.as_str())} {
+            (key.label(messages))
+            @if active {
+              @match sort.order {
+                SortOrder::Asc => " ↑",
+                SortOrder::Desc => " ↓",
+              }
+            }
+          }
+        }
+      }
+      (entries)
+      @if previous.is_some() || next.is_some() {
+        nav class="pages" {
+          @if let Some(previous) = previous {
+            a rel="prev" href=(previous) {
+              (messages.previous)
             }
-            file_name
-          };
+          }
+          @if let Some(next) = next {
+            a rel="next" href=(next) {
+              (messages.next)
+            }
+          }
+        }
+      }
+      div class="archive" {
+        (messages.archive)
+        a href="?archive=zip" {
+          "ZIP"
+        }
+        (messages.archive_or)
+        a href="?archive=tar.gz" {
+          "tar.gz"
+        }
+      }
+      @if let Some(index) = self.render_index(dir)? {
+        div {
+          (index)
+        }
+      }
+    })
+  }
+
+  /// Links to the parent directory, to the root directory and to each
+  /// directory along `tail`, followed by the name of the current file or
+  /// directory.
+  fn breadcrumbs(messages: &Messages, tail: &[&str]) -> Markup {
+    html! {
+      nav class="breadcrumbs" aria-label=(messages.breadcrumbs) {
+        @if let Some(parent) = tail.len().checked_sub(1) {
+          a class="parent" rel="up" href={
+            "/files/"
+            (percent_encoding::utf8_percent_encode(&tail[..parent].join(""), &Self::ENCODE_CHARACTERS))
+          } title=(messages.parent_directory) {
+            (Files::icon("corner-left-up"))
+          }
+        }
+        @if tail.is_empty() {
+          span aria-current="page" {
+            "/"
+          }
+        } @else {
+          a href="/files/" {
+            "/"
+          }
+        }
+        @for (i, segment) in tail.iter().enumerate() {
+          @if i + 1 == tail.len() {
+            span aria-current="page" {
+              (segment)
+            }
+          } @else {
+            a href={
+              "/files/"
+              (percent_encoding::utf8_percent_encode(&tail[..=i].join(""), &Self::ENCODE_CHARACTERS))
+            } {
+              (segment)
+            }
+          }
+        }
+      }
+    }
+  }
+
+  /// The name of `entry` as shown in listings, with a trailing `/` for
+  /// directories.
+  fn entry_name(entry: &DirEntry) -> String {
+    let mut file_name = entry.file_name.to_string_lossy().into_owned();
+    if entry.file_type.is_dir() {
+      file_name.push('/');
+    }
+    file_name
+  }
+
+  fn render_list(locale: Locale, entries: &[DirEntry]) -> Markup {
+    let messages = locale.messages();
+    html! {
+      ul class="listing" {
+        @for entry in entries {
+          @let file_name = Self::entry_name(entry);
           @let encoded = percent_encoding::utf8_percent_encode(&file_name, &Self::ENCODE_CHARACTERS);
+          @let purchasable = entry.paid && !entry.file_type.is_dir();
           li {
-            a href=(encoded) class="view" {
-              (file_name)
+            @if purchasable {
+              a href={(encoded) "?preview"} class="view" {
+                (file_name)
+              }
+            } @else {
+              a href=(encoded) class="view" {
+                (file_name)
+              }
             }
 
             @if let Some(file_size) = entry.file_size {
               span class="filesize" {
-                (file_size.display_size())
+                (file_size.display_size(locale))
+              }
+            }
+            @if let Some(modified) = entry.modified {
+              span class="modified" {
+                (modified.display_time())
+              }
+            }
+            @if purchasable {
+              span class="paid" {
+                (messages.paid)
+              }
+              @if let Some(price) = entry.price {
+                span class="price" {
+                  (price.display_in(locale.number_format()))
+                }
+              }
+              a class="buy" href=(encoded) {
+                (messages.buy)
               }
             }
             @if entry.file_type.is_file() && !entry.paid {
-              a download href=(encoded) {
+              a download href={(encoded) @if Self::is_markdown(&file_name) { "?raw" }} {
                 (Files::icon("download"))
               }
             }
           }
         }
       }
-      @if let Some(index) = self.render_index(dir)? {
-        div {
-          (index)
+    }
+  }
+
+  fn render_gallery(locale: Locale, entries: &[DirEntry]) -> Markup {
+    html! {
+      ul class="gallery" {
+        @for entry in entries {
+          @let file_name = Self::entry_name(entry);
+          @let encoded = percent_encoding::utf8_percent_encode(&file_name, &Self::ENCODE_CHARACTERS);
+          @let purchasable = entry.paid && !entry.file_type.is_dir();
+          li {
+            a class="view" href={(encoded) @if purchasable { "?preview" }} {
+              @if entry.file_type.is_dir() {
+                (Files::icon("folder"))
+              } @else if Thumbnails::supported(&file_name) {
+                img
+                  src={(encoded) "?thumbnail"}
+                  alt=(file_name)
+                  loading="lazy";
+              } @else {
+                (Files::icon("file"))
+              }
+              span class="filename" {
+                (file_name)
+              }
+            }
+            @if purchasable {
+              div {
+                span class="paid" {
+                  (locale.messages().paid)
+                }
+                @if let Some(price) = entry.price {
+                  " "
+                  span class="price" {
+                    (price.display_in(locale.number_format()))
+                  }
+                }
+              }
+            }
+          }
         }
       }
-    };
-    Ok(html::wrap_body(&format!("/{}", tail.join("")), body))
+    }
+  }
+
+  async fn serve_preview(
+    &self,
+    locale: Locale,
+    tail: &[&str],
+    path: &InputPath,
+  ) -> Result<Response<Body>> {
+    let messages = locale.messages();
+    let entry = self.vfs.entry(path)?;
+    let file_name = entry.file_name.to_string_lossy();
+    let encoded = percent_encoding::utf8_percent_encode(&file_name, &Self::ENCODE_CHARACTERS);
+
+    Ok(html::wrap_body(
+      &self.theme,
+      locale,
+      &format!("/{}", tail.join("")),
+      html! {
+        (Self::breadcrumbs(messages, tail))
+        div class="preview" {
+          @if Thumbnails::supported(path.display_path()) {
+            img class="thumbnail" src={(encoded) "?thumbnail"} alt=(file_name);
+          }
+          dl {
+            dt { (messages.file) }
+            dd class="filename" { (file_name) }
+            @if let Some(file_size) = entry.file_size {
+              dt { (messages.size) }
+              dd class="filesize" { (file_size.display_size(locale)) }
+            }
+            @if let Some(mime_type) = &entry.mime_type {
+              dt { (messages.type_) }
+              dd class="mime-type" { (mime_type.essence_str()) }
+            }
+            @if entry.paid {
+              dt { (messages.price) }
+              dd class="price" {
+                @if let Some(price) = entry.price {
+                  (price.display_in(locale.number_format()))
+                }
+                " "
+                span class="paid" {
+                  (messages.paid)
+                }
+              }
+            }
+          }
+          div class="links" {
+            @if entry.paid {
+              a class="buy" href=(encoded) {
+                (messages.buy)
+              }
+            } @else {
+              a href=(encoded) {
+                (messages.view)
+              }
+              a download href={(encoded) @if Self::is_markdown(&*file_name) { "?raw" }} {
+                (messages.download)
+              }
+            }
+          }
+        }
+      },
+    ))
+  }
+
+  pub(crate) async fn search(&self, request: &Request<Body>) -> Result<Response<Body>> {
+    let query = query_parameter(request, "q").unwrap_or_default();
+    let include_index = query_parameter(request, "index").is_some();
+    let (results, truncated) = self.search_index.search(&query, include_index).await?;
+    let locale = Locale::new(
+      request,
+      self.vfs.listing_config(&self.vfs.file_path("")?)?.lang,
+    );
+    let messages = locale.messages();
+
+    Ok(html::wrap_body(
+      &self.theme,
+      locale,
+      &format!("{}{}", messages.search_title, query),
+      html! {
+        (Self::search_form(messages, &query, include_index))
+        @if !query.trim().is_empty() {
+          @if results.is_empty() {
+            p class="search-summary" {
+              (messages.no_files_found)
+            }
+          } @else {
+            ul class="listing" {
+              @for result in &results {
+                li {
+                  a class="view" href={
+                    "/files/"
+                    (percent_encoding::utf8_percent_encode(&result.path, &Self::ENCODE_CHARACTERS))
+                  } {
+                    "/" (result.path)
+                  }
+                  @if let Some(size) = result.size {
+                    span class="filesize" {
+                      (size.display_size(locale))
+                    }
+                  }
+                  @if let Some(price) = result.price {
+                    span class="price" {
+                      (price.display_in(locale.number_format()))
+                    }
+                  }
+                }
+              }
+            }
+            @if truncated {
+              p class="search-summary" {
+                (messages.search_truncated_start)
+                (SearchIndex::MAX_RESULTS)
+                (messages.search_truncated_end)
+              }
+            }
+          }
+        }
+      },
+    ))
+  }
+
+  fn search_form(messages: &Messages, query: &str, include_index: bool) -> Markup {
+    html! {
+      form class="search" action="/search" {
+        input
+          type="search"
+          name="q"
+          value=(query)
+          placeholder=(messages.search_files)
+          aria-label=(messages.search_files);
+        label {
+          input type="checkbox" name="index" checked[include_index];
+          (messages.include_index_pages)
+        }
+        button type="submit" {
+          (messages.search)
+        }
+      }
+    }
   }
 
   fn icon(name: &str) -> Markup {
@@ -145,7 +620,7 @@ impl Files {
     path: &InputPath,
   ) -> Result<Response<Body>> {
     if !self.vfs.paid(path)? {
-      return Self::serve_file(path).await;
+      return self.serve_file(request, path, false).await;
     }
 
     let rpc_client = self.rpc_client.as_mut().ok_or_else(|| {
@@ -155,32 +630,76 @@ impl Files {
       .build()
     })?;
 
-    let file_path = tail.join("");
+    let file_path = format!("{}{}", self.memo_prefix, tail.join(""));
     let base_price = self.vfs.base_price(path)?.ok_or_else(|| {
       error::ConfigMissingBasePrice {
         path: path.display_path(),
       }
       .build()
     })?;
-    let file_path_with_uuid = format!("{}_{}!", file_path, Uuid::new_v4());
-    let invoice = rpc_client
-      .add_invoice(&file_path_with_uuid, base_price)
-      .await
-      .context(error::LndRpcStatus)?;
-    redirect(format!(
-      "{}?invoice={}",
-      request.uri().path(),
-      invoice.payment_hash,
-    ))
+
+    let client = request
+      .extensions()
+      .get::<Connection>()
+      .and_then(|connection| connection.client);
+
+    let mut existing = self.invoice_limiter.existing_invoice(client, &file_path);
+
+    // Only send the client back to an invoice that hasn't been paid yet.
+    // Otherwise anyone sharing its IP address, e.g. behind NAT, could use it
+    // to download the file for free.
+    if let Some(payment_hash) = &existing {
+      let mut r_hash = [0; 32];
+      hex::decode_to_slice(payment_hash, &mut r_hash).context(error::InvoiceId)?;
+      let invoice = rpc_client
+        .lookup_invoice(r_hash)
+        .await
+        .context(error::LndRpcStatus)?;
+      if !matches!(invoice, Some(invoice) if !invoice.is_settled) {
+        self
+          .invoice_limiter
+          .forget_invoice(client, &file_path, payment_hash);
+        existing = None;
+      }
+    }
+
+    let payment_hash = match existing {
+      Some(payment_hash) => payment_hash,
+      None => {
+        self.invoice_limiter.acquire(client)?;
+        let file_path_with_uuid = format!("{}_{}!", file_path, Uuid::new_v4());
+        let invoice = rpc_client
+          .add_invoice(&file_path_with_uuid, base_price)
+          .await
+          .context(error::LndRpcStatus)?;
+        self
+          .invoice_limiter
+          .record_invoice(client, &file_path, invoice.payment_hash.clone());
+        invoice.payment_hash
+      }
+    };
+
+    redirect(format!("{}?invoice={}", request.uri().path(), payment_hash))
   }
 
-  async fn serve_file(path: &InputPath) -> Result<Response<Body>> {
+  async fn serve_file(
+    &self,
+    request: &Request<Body>,
+    path: &InputPath,
+    paid: bool,
+  ) -> Result<Response<Body>> {
+    let limits = self.vfs.bandwidth_limits(path, paid)?;
     let mut builder = Response::builder().status(StatusCode::OK);
     if let Some(guess) = path.mime_guess().first() {
       builder = builder.header(header::CONTENT_TYPE, guess.essence_str());
     }
     builder
-      .body(Body::wrap_stream(FileStream::new(path.clone()).await?))
+      .body(Body::wrap_stream(self.bandwidth_limiter.throttle(
+        FileStream::new(path.clone()).await?,
+        request.extensions().get::<Connection>(),
+        paid,
+        limits,
+      )))
       .map_err(|error| Error::internal(format!("Failed to construct response: {}", error)))
   }
 
@@ -202,8 +721,10 @@ impl Files {
       .context(error::LndRpcStatus)?
       .ok_or_else(|| error::InvoiceNotFound { r_hash }.build())?;
 
+    let segments = request_tail;
     let request_tail = request_tail.join("");
-    if !(invoice.memo.starts_with(&request_tail)) {
+    let memo_path = format!("{}{}", self.memo_prefix, request_tail);
+    if !(invoice.memo.starts_with(&memo_path)) {
       return Err(
         error::InvoicePathMismatch {
           invoice_tail: invoice.memo,
@@ -216,17 +737,31 @@ impl Files {
 
     let value = Piconero::new(invoice.value);
     if invoice.is_settled {
+      self.invoice_limiter.forget_invoice(
+        request
+          .extensions()
+          .get::<Connection>()
+          .and_then(|connection| connection.client),
+        &memo_path,
+        &invoice.payment_hash,
+      );
       let path = self.vfs.file_path(&request_tail)?;
-      Self::serve_file(&path).await
+      self.serve_file(request, &path, true).await
     } else {
       let qr_code_url = format!("/invoice/{}.svg", invoice.payment_hash);
+      let locale = Locale::new(request, self.vfs.lang(&self.vfs.file_path(&request_tail)?)?);
+      let messages = locale.messages();
+      let value = value.display_in(locale.number_format());
       let filename = request_tail;
       Ok(html::wrap_body(
-        &format!("Invoice for {}", filename),
+        &self.theme,
+        locale,
+        &format!("{}{}", messages.invoice_title, filename),
         html! {
+          (Self::breadcrumbs(messages, segments))
           div class="invoice" {
             div class="label" {
-              "Monero Payment Request for " (value) " to access "
+              (messages.invoice_label_start) (value) (messages.invoice_label_middle)
               span class="filename" {
                   (filename)
               }
@@ -234,9 +769,7 @@ impl Files {
               ":"
             }
             div class="payment-request"{
-              button class="clipboard-copy" onclick=(
-                format!("navigator.clipboard.writeText(\"{}\")", invoice.payment_request)
-              ) {
+              button class="clipboard-copy" data-clipboard-text=(invoice.payment_request) {
                 (Files::icon("clipboard"))
               }
               (invoice.payment_request)
@@ -244,35 +777,33 @@ impl Files {
 
             div class="links" {
               a class="payment-link" href={(invoice.payment_request)} {
-                "Open invoice in wallet"
+                (messages.open_invoice_in_wallet)
               }
               a class="reload-link" href=(request.uri()) {
-                "Access file"
+                (messages.access_file)
               }
             }
             img
               class="qr-code"
-              alt="Monero Network Invoice QR Code"
+              alt=(messages.invoice_qr_code)
               src=(qr_code_url)
               width="400"
               height="400";
           }
           div class="instructions" {
-            "To access "
+            (messages.invoice_instructions_start)
             span class="filename" {
                 (filename)
             }
-            ":"
+            (messages.invoice_instructions_end)
             ol {
               li {
-                "Pay the invoice for " (value) " above "
-                "with your Monero wallet by "
-                "scanning the QR code, "
-                "copying the payment request string, or "
-                "clicking the \"Open invoice in wallet\" link."
+                (messages.invoice_instructions_pay_start)
+                (value)
+                (messages.invoice_instructions_pay_end)
               }
               li {
-                "Click the \"Access file\" link or reload the page."
+                (messages.invoice_instructions_reload)
               }
             }
           }
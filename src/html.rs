@@ -1,34 +1,69 @@
 use {
-  crate::common::*,
+  crate::{common::*, locale::Locale, theme::Theme},
   maud::{html, DOCTYPE},
 };
 
-pub(crate) fn wrap_body(title_slug: &str, body: Markup) -> Response<Body> {
-  let html = html! {
-    (DOCTYPE)
-    html lang="en" {
-      head {
-        meta charset="utf-8";
-        meta name="viewport" content="width=device-width, initial-scale=1";
-        title {
-          (format!("{} · Opuza", title_slug))
-        }
-        link rel="stylesheet" href="/static/index.css";
-        script type="module" src="/static/index.js" {}
+pub(crate) fn wrap_body(
+  theme: &Theme,
+  locale: Locale,
+  title_slug: &str,
+  body: Markup,
+) -> Response<Body> {
+  let title = html! {
+    (title_slug)
+  };
+  let head = html! {
+    meta charset="utf-8";
+    meta name="viewport" content="width=device-width, initial-scale=1";
+    link rel="stylesheet" href="/static/index.css";
+    script type="module" src="/static/index.js" {}
+  };
+  let header = theme.header(&title);
+  let footer = theme.footer(&title).unwrap_or_else(|| {
+    html! {
+      (locale.messages().powered_by)
+      a href="https://github.com/refring/opuza" {
+        "Opuza"
       }
-      body {
-        main {
-          (body)
+      "."
+    }
+  });
+
+  let html = match theme.page() {
+    Some(page) => html! {
+      (DOCTYPE)
+      (page.render(&[
+        ("title", &title),
+        ("head", &head),
+        ("header", &header.unwrap_or_default()),
+        ("body", &body),
+        ("footer", &footer),
+      ]))
+    },
+    None => html! {
+      (DOCTYPE)
+      html lang=(locale.code()) {
+        head {
+          (head)
+          title {
+            (format!("{} · Opuza", title_slug))
+          }
         }
-        footer {
-          "Powered by "
-          a href="https://github.com/refring/opuza" {
-            "Opuza"
+        body {
+          @if let Some(header) = header {
+            header {
+              (header)
+            }
+          }
+          main {
+            (body)
+          }
+          footer {
+            (footer)
           }
-          "."
         }
       }
-    }
+    },
   };
   Response::builder()
     .header(header::CONTENT_TYPE, "text/html")
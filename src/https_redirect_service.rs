@@ -1,67 +1,114 @@
 #![allow(clippy::unnecessary_to_owned)]
 
-use {crate::common::*, tower::make::Shared};
+use crate::{
+  acme::Http01Challenges,
+  common::*,
+  listeners,
+  locale::Locale,
+  proxy_protocol::{ProxyProtocolIncoming, ProxyProtocolStream, ProxySource, RemoteIp},
+  theme::Theme,
+  trusted_proxies::TrustedProxies,
+};
 
 #[derive(Clone)]
 pub(crate) struct HttpsRedirectService {
+  http01_challenges: Option<Http01Challenges>,
   https_port: u16,
+  peer: Option<IpAddr>,
+  proxy_source: ProxySource,
   stderr: Stderr,
+  theme: Theme,
+  trusted_proxies: TrustedProxies,
 }
 
 impl HttpsRedirectService {
-  pub(crate) fn new_server(
-    environment: &Environment,
+  /// A server for each address, redirecting to `https_request_handler`.
+  pub(crate) fn new_servers(
+    environment: &mut Environment,
     arguments: &Arguments,
     https_request_handler: &HttpsRequestHandler,
-  ) -> Result<Option<hyper::Server<AddrIncoming, Shared<HttpsRedirectService>>>> {
-    match arguments.https_redirect_port {
-      None => Ok(None),
-      Some(https_redirect_port) => {
-        let socket_addr = (arguments.address.as_str(), https_redirect_port)
-          .to_socket_addrs()
-          .context(error::AddressResolutionIo {
-            input: &arguments.address,
-          })?
-          .next()
-          .ok_or_else(|| {
-            error::AddressResolutionNoAddresses {
-              input: arguments.address.clone(),
-            }
-            .build()
-          })?;
-
-        Ok(Some(hyper::Server::bind(&socket_addr).serve(Shared::new(
-          HttpsRedirectService {
-            https_port: https_request_handler.https_port(),
-            stderr: environment.stderr.clone(),
-          },
-        ))))
-      }
-    }
+  ) -> Result<Vec<listeners::TcpServer<HttpsRedirectService>>> {
+    let https_redirect_port = match arguments.https_redirect_port {
+      None => return Ok(Vec::new()),
+      Some(https_redirect_port) => https_redirect_port,
+    };
+
+    let service = HttpsRedirectService {
+      http01_challenges: https_request_handler.http01_challenges().cloned(),
+      https_port: https_request_handler
+        .https_port()
+        .expect("<https-redirect-port> requires <https-port>"),
+      peer: None,
+      proxy_source: ProxySource::default(),
+      stderr: environment.stderr.clone(),
+      theme: https_request_handler.theme().clone(),
+      trusted_proxies: TrustedProxies::new(arguments),
+    };
+
+    Ok(
+      listeners::incoming(
+        environment,
+        arguments,
+        https_redirect_port,
+        "HTTPS redirect",
+      )?
+      .into_iter()
+      .map(|(local_addr, incoming)| {
+        (
+          local_addr,
+          hyper::Server::builder(ProxyProtocolIncoming::new(
+            incoming,
+            service.trusted_proxies.clone(),
+          ))
+          .serve(service.clone()),
+        )
+      })
+      .collect(),
+    )
   }
 
   fn response(&mut self, request: Request<Body>) -> Result<Response<Body>> {
-    let authority = request.headers().get(header::HOST).ok_or_else(|| {
+    if let Some(response) = self
+      .http01_challenges
+      .as_ref()
+      .and_then(|challenges| challenges.response(&request))
+    {
+      return Ok(response);
+    }
+
+    let forwarded =
+      self
+        .trusted_proxies
+        .forwarded(self.proxy_source.or(self.peer), false, request.headers());
+
+    let authority = match forwarded.host {
+      Some(host) => host.into_bytes(),
+      None => request
+        .headers()
+        .get(header::HOST)
+        .ok_or_else(|| {
+          error::Custom {
+            message: "Missing HOST header",
+            status_code: StatusCode::BAD_REQUEST,
+          }
+          .build()
+        })?
+        .as_bytes()
+        .to_vec(),
+    };
+
+    let authority = Authority::from_maybe_shared(authority.clone()).map_err(|error| {
       error::Custom {
-        message: "Missing HOST header",
+        message: format!(
+          "Invalid HOST header `{}`: {}",
+          String::from_utf8_lossy(&authority),
+          error
+        ),
         status_code: StatusCode::BAD_REQUEST,
       }
       .build()
     })?;
 
-    let authority =
-      Authority::from_maybe_shared(authority.as_bytes().to_vec()).map_err(|error| {
-        error::Custom {
-          message: format!(
-            "Invalid HOST header `{}`: {}",
-            String::from_utf8_lossy(authority.as_bytes()),
-            error
-          ),
-          status_code: StatusCode::BAD_REQUEST,
-        }
-        .build()
-      })?;
-
     redirect(format!(
       "https://{}:{}{}",
       authority.host(),
@@ -81,7 +128,31 @@ impl Service<Request<Body>> for HttpsRedirectService {
   }
 
   fn call(&mut self, request: Request<Body>) -> Self::Future {
+    let locale = Locale::new(&request, None);
     let result = self.response(request);
-    future::ready(Ok(error_page::map_error(self.stderr.clone(), result)))
+    future::ready(Ok(error_page::map_error(
+      self.stderr.clone(),
+      &self.theme,
+      locale,
+      result,
+    )))
+  }
+}
+
+impl<'a, S: RemoteIp> Service<&'a ProxyProtocolStream<S>> for HttpsRedirectService {
+  type Response = HttpsRedirectService;
+  type Error = Infallible;
+  type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, stream: &'a ProxyProtocolStream<S>) -> Self::Future {
+    future::ready(Ok(Self {
+      peer: stream.inner().remote_ip(),
+      proxy_source: stream.source(),
+      ..self.clone()
+    }))
   }
 }
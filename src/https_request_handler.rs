@@ -1,120 +1,348 @@
-use rustls_acme::caches::DirCache;
+#[cfg(unix)]
+use crate::unix_socket::UnixSocket;
 use rustls_acme::futures_rustls::rustls::ServerConfig;
-use rustls_acme::{is_tls_alpn_challenge, AcmeConfig};
+use rustls_acme::is_tls_alpn_challenge;
 use tokio::io::AsyncWriteExt;
 use tokio_rustls::LazyConfigAcceptor;
-use {crate::common::*, hyper::server::conn::Http};
+use {
+  crate::{
+    acme::{Acme, Http01Challenges},
+    common::*,
+    listeners::{self, Listen, Peer},
+    proxy_protocol::ProxyProtocolStream,
+    theme::Theme,
+    tls_certificate::{TlsCertificate, TlsCertificateReloader},
+  },
+  hyper::server::conn::Http,
+  tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Semaphore,
+    task::JoinSet,
+  },
+  tokio_util::sync::CancellationToken,
+};
 
 pub(crate) struct HttpsRequestHandler {
   request_handler: RequestHandler,
-  https_port: u16,
-  listener: tokio::net::TcpListener,
-  cache_dir: PathBuf,
-  acme_domains: Vec<String>,
+  https_port: Option<u16>,
+  listeners: Vec<TcpListener>,
+  #[cfg(unix)]
+  unix_socket: Option<UnixSocket>,
+  acme: Option<Acme>,
+  tls_certificate_reloader: Option<TlsCertificateReloader>,
+  default_rustls_config: Arc<ServerConfig>,
+  challenge_rustls_config: Option<Arc<ServerConfig>>,
+  connection_limit: Arc<Semaphore>,
+  settings: ConnectionSettings,
+}
+
+/// How HTTPS connections are served, once accepted.
+#[derive(Clone, Copy)]
+struct ConnectionSettings {
+  handshake_timeout: Duration,
+  header_read_timeout: Duration,
+  http2_max_concurrent_streams: u32,
+  http2_keep_alive_interval: Option<Duration>,
 }
 
 impl HttpsRequestHandler {
+  /// Protocols offered via ALPN, in order of preference.
+  const ALPN_PROTOCOLS: [&'static [u8]; 2] = [b"h2", b"http/1.1"];
+
   pub(crate) async fn new(
     environment: &mut Environment,
     arguments: &Arguments,
-    acme_cache_directory: &Path,
-    https_port: u16,
-    rpc_client: Option<opuza_monero_client::MoneroRpcClient>,
+    request_handler: RequestHandler,
+    acme: Option<Acme>,
   ) -> Result<HttpsRequestHandler> {
-    let request_handler = RequestHandler::new(environment, &arguments.directory, rpc_client);
-    let socket_addr = (arguments.address.as_str(), https_port)
-      .to_socket_addrs()
-      .context(error::AddressResolutionIo {
-        input: &arguments.address,
-      })?
-      .next()
-      .ok_or_else(|| {
-        error::AddressResolutionNoAddresses {
-          input: arguments.address.clone(),
+    let (default_rustls_config, challenge_rustls_config, tls_certificate_reloader) =
+      match (&arguments.tls_certificate, &arguments.tls_key, &acme) {
+        (Some(certificate), Some(key), _) => {
+          let tls_certificate = TlsCertificate::load(
+            environment.working_directory.join(certificate),
+            environment.working_directory.join(key),
+          )?;
+          (
+            Self::with_alpn(
+              ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(tls_certificate.clone()),
+            ),
+            None,
+            Some(TlsCertificateReloader::new(tls_certificate)?),
+          )
         }
-        .build()
-      })?;
-    let listener = tokio::net::TcpListener::bind(socket_addr)
-      .await
-      .context(error::SocketIo { socket_addr })?;
-    let local_addr = listener
-      .local_addr()
-      .context(error::SocketIo { socket_addr })?;
-    writeln!(
-      environment.stderr,
-      "Listening for HTTPS connections on `{}`",
-      local_addr,
-    )
-    .context(error::StderrWrite)?;
-    let https_port = local_addr.port();
-    let cache_dir = environment.working_directory.join(acme_cache_directory);
-    assert!(!arguments.acme_domain.is_empty());
+        (_, _, Some(acme)) => (
+          Self::with_alpn(acme.default_rustls_config()),
+          acme.challenge_rustls_config(),
+          None,
+        ),
+        _ => panic!("<https-port> requires <acme-cache-directory> or <tls-certificate>"),
+      };
+
+    let listeners = match arguments.https_port {
+      Some(https_port) => listeners::bind(environment, arguments, https_port, "HTTPS")?,
+      None => Vec::new(),
+    };
+    let https_port = listeners
+      .first()
+      .and_then(|listener| listener.local_addr().ok())
+      .map(|local_addr| local_addr.port());
+
+    #[cfg(unix)]
+    let unix_socket = arguments
+      .https_socket
+      .as_ref()
+      .map(|path| UnixSocket::bind(environment, arguments, path, "HTTPS"))
+      .transpose()?;
+
     Ok(HttpsRequestHandler {
-      acme_domains: arguments.acme_domain.clone(),
       request_handler,
       https_port,
-      listener,
-      cache_dir,
+      listeners,
+      #[cfg(unix)]
+      unix_socket,
+      acme,
+      tls_certificate_reloader,
+      default_rustls_config,
+      challenge_rustls_config,
+      connection_limit: Arc::new(Semaphore::new(arguments.https_max_connections as usize)),
+      settings: ConnectionSettings {
+        handshake_timeout: Duration::from_secs(arguments.tls_handshake_timeout),
+        header_read_timeout: Duration::from_secs(arguments.header_read_timeout),
+        http2_max_concurrent_streams: arguments.http2_max_concurrent_streams,
+        http2_keep_alive_interval: arguments.http2_keep_alive_interval.map(Duration::from_secs),
+      },
     })
   }
 
-  pub(crate) async fn run(self) {
-    let acme_domains = self.acme_domains.clone();
-    let cache_dir = self
-      .cache_dir
-      .clone()
-      .into_os_string()
-      .into_string()
-      .unwrap();
-
-    let mut state = AcmeConfig::new(acme_domains)
-      .cache_option(Some(DirCache::new(cache_dir)))
-      .directory_lets_encrypt(cfg!(test) == false)
-      .state();
-
-    let challenge_rustls_config = state.challenge_rustls_config();
-    let default_rustls_config = state.default_rustls_config();
-
-    tokio::spawn(async move {
-      loop {
-        match state.next().await.unwrap() {
-          Ok(ok) => log::info!("event: {:?}", ok),
-          Err(err) => log::error!("error: {:?}", err),
-        }
-      }
-    });
+  fn with_alpn(mut config: ServerConfig) -> Arc<ServerConfig> {
+    config.alpn_protocols = Self::ALPN_PROTOCOLS
+      .iter()
+      .map(|protocol| protocol.to_vec())
+      .collect();
+    Arc::new(config)
+  }
+
+  /// Serve HTTPS connections until shutdown, fetching and renewing the
+  /// certificate in the background if it comes from ACME, or reloading it
+  /// when it changes if it comes from files.
+  pub(crate) async fn run(mut self, shutdown: CancellationToken) {
+    if let Some(acme) = self.acme.take() {
+      tokio::spawn(acme.run());
+    }
+
+    if let Some(tls_certificate_reloader) = self.tls_certificate_reloader.take() {
+      tokio::spawn(tls_certificate_reloader.run(shutdown.clone()));
+    }
+
+    let acceptor = Acceptor {
+      tls: Arc::new(Tls {
+        default_rustls_config: self.default_rustls_config,
+        challenge_rustls_config: self.challenge_rustls_config,
+        settings: self.settings,
+      }),
+      request_handler: self.request_handler,
+      connection_limit: self.connection_limit,
+      shutdown,
+    };
 
-    self.serve(default_rustls_config, challenge_rustls_config).await;
+    let mut accepting = self
+      .listeners
+      .into_iter()
+      .map(|listener| acceptor.clone().accept(listener).boxed())
+      .collect::<Vec<BoxFuture<()>>>();
+
+    #[cfg(unix)]
+    accepting.extend(
+      self
+        .unix_socket
+        .map(|unix_socket| acceptor.clone().accept(unix_socket).boxed()),
+    );
+
+    futures::future::join_all(accepting).await;
+  }
+
+  /// The TCP port HTTPS is served on, if any.
+  pub(crate) fn https_port(&self) -> Option<u16> {
+    self.https_port
+  }
+
+  pub(crate) fn theme(&self) -> &Theme {
+    &self.request_handler.theme
   }
 
-  async fn serve(self, default_rustls_config: Arc<ServerConfig>, challenge_rustls_config: Arc<ServerConfig>) {
-    let listener = self.listener;
+  pub(crate) fn http01_challenges(&self) -> Option<&Http01Challenges> {
+    self.request_handler.http01_challenges()
+  }
+}
+
+/// Accepts HTTPS connections and serves them.
+#[derive(Clone)]
+struct Acceptor {
+  tls: Arc<Tls>,
+  request_handler: RequestHandler,
+  connection_limit: Arc<Semaphore>,
+  shutdown: CancellationToken,
+}
+
+impl Acceptor {
+  const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+  const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+  /// Accept connections on `listener` until shutdown, then wait for
+  /// connections in progress to finish.
+  async fn accept(self, listener: impl Listen) {
+    let Self {
+      tls,
+      request_handler,
+      connection_limit,
+      shutdown,
+    } = self;
+
+    let mut connections = JoinSet::new();
+    let mut backoff = Self::MIN_ACCEPT_BACKOFF;
     loop {
-      let (tcp, _) = listener.accept().await.unwrap();
-      let challenge_rustls_config = challenge_rustls_config.clone();
-      let default_rustls_config = default_rustls_config.clone();
+      // Wait for a free connection slot before accepting, so that excess
+      // clients queue in the listen backlog instead of using up memory and
+      // file descriptors.
+      let permit = tokio::select! {
+        permit = connection_limit.clone().acquire_owned() => {
+          permit.expect("connection limit semaphore is never closed")
+        }
+        () = shutdown.cancelled() => break,
+      };
 
-      let request_handler = self.request_handler.clone();
+      let accepted = tokio::select! {
+        accepted = listener.accept() => accepted,
+        () = shutdown.cancelled() => break,
+      };
 
-      tokio::spawn(async move {
-        let start_handshake = LazyConfigAcceptor::new(Default::default(), tcp).await.unwrap();
+      let (stream, peer) = match accepted {
+        Ok(accepted) => {
+          backoff = Self::MIN_ACCEPT_BACKOFF;
+          accepted
+        }
+        Err(source) => {
+          // Errors like `EMFILE` usually clear up once other connections
+          // close, so wait a little and try again.
+          log::error!("{}", listener.accept_error(source));
+          tokio::select! {
+            () = tokio::time::sleep(backoff) => {}
+            () = shutdown.cancelled() => break,
+          }
+          backoff = (backoff * 2).min(Self::MAX_ACCEPT_BACKOFF);
+          continue;
+        }
+      };
 
-        if is_tls_alpn_challenge(&start_handshake.client_hello()) {
-          log::info!("received TLS-ALPN-01 validation request");
-          let mut tls = start_handshake.into_stream(challenge_rustls_config).await.unwrap();
-          tls.shutdown().await.unwrap();
-        } else {
-          let tls = start_handshake.into_stream(default_rustls_config).await.unwrap();
-          Http::new()
-              .serve_connection(tls, request_handler)
-              .await
-              .unwrap()
+      let stream = ProxyProtocolStream::new(
+        stream,
+        request_handler
+          .trusted_proxies()
+          .expect_proxy_protocol(peer.ip()),
+      );
+      let tls = tls.clone();
+      let request_handler = request_handler.for_connection(peer.ip(), true, stream.source());
+      let shutdown = shutdown.clone();
+
+      connections.spawn(async move {
+        if let Err(error) = tls
+          .serve_connection(stream, peer, request_handler, shutdown)
+          .await
+        {
+          log::warn!("{}", error);
         }
+        drop(permit);
       });
+
+      while connections.try_join_next().is_some() {}
     }
+
+    drop(listener);
+
+    while connections.join_next().await.is_some() {}
   }
+}
 
-  pub(crate) fn https_port(&self) -> u16 {
-    self.https_port
+/// What HTTPS connections are served with.
+struct Tls {
+  default_rustls_config: Arc<ServerConfig>,
+  challenge_rustls_config: Option<Arc<ServerConfig>>,
+  settings: ConnectionSettings,
+}
+
+impl Tls {
+  async fn serve_connection(
+    &self,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    peer: Peer,
+    request_handler: RequestHandler,
+    shutdown: CancellationToken,
+  ) -> Result<()> {
+    let handshake = async {
+      let start_handshake = LazyConfigAcceptor::new(Default::default(), stream).await?;
+
+      match self
+        .challenge_rustls_config
+        .clone()
+        .filter(|_| is_tls_alpn_challenge(&start_handshake.client_hello()))
+      {
+        Some(challenge_rustls_config) => {
+          log::info!("received TLS-ALPN-01 validation request");
+          let mut tls = start_handshake.into_stream(challenge_rustls_config).await?;
+          tls.shutdown().await?;
+          Ok(None)
+        }
+        None => Ok(Some(
+          start_handshake
+            .into_stream(self.default_rustls_config.clone())
+            .await?,
+        )),
+      }
+    };
+
+    let settings = self.settings;
+
+    let tls = match tokio::time::timeout(settings.handshake_timeout, handshake).await {
+      Ok(Ok(Some(tls))) => tls,
+      Ok(Ok(None)) => return Ok(()),
+      Ok(Err(source)) => return Err(error::TlsHandshake { peer }.into_error(source)),
+      Err(_) => {
+        return Err(
+          error::TlsHandshakeTimeout {
+            peer,
+            timeout: settings.handshake_timeout,
+          }
+          .build(),
+        )
+      }
+    };
+
+    // Serve the protocol negotiated via ALPN, or HTTP/1.1 if the client
+    // didn't ask for one.
+    let mut http = Http::new();
+    if tls.get_ref().1.alpn_protocol() == Some(b"h2") {
+      http
+        .http2_only(true)
+        .http2_max_concurrent_streams(settings.http2_max_concurrent_streams)
+        .http2_keep_alive_interval(settings.http2_keep_alive_interval);
+    } else {
+      http
+        .http1_only(true)
+        .http1_header_read_timeout(settings.header_read_timeout);
+    }
+
+    let connection = http.serve_connection(tls, request_handler);
+    tokio::pin!(connection);
+    tokio::select! {
+      result = connection.as_mut() => result,
+      () = shutdown.cancelled() => {
+        connection.as_mut().graceful_shutdown();
+        connection.await
+      }
+    }
+    .context(error::ServeConnection { peer })
   }
 }
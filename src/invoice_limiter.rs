@@ -0,0 +1,285 @@
+use {
+  crate::common::*,
+  std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Instant},
+};
+
+/// Limits on invoice creation, from the `--invoice-*` arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct InvoiceLimits {
+  pub(crate) client_burst: u32,
+  pub(crate) global_burst: u32,
+  pub(crate) window: Duration,
+  pub(crate) reuse_window: Duration,
+}
+
+impl InvoiceLimits {
+  pub(crate) fn new(arguments: &Arguments) -> Self {
+    Self {
+      client_burst: arguments.invoice_burst,
+      global_burst: arguments.global_invoice_burst,
+      window: Duration::from_secs(arguments.invoice_window),
+      reuse_window: Duration::from_secs(arguments.invoice_reuse_window),
+    }
+  }
+}
+
+/// Rate limits invoice creation per client IP address and across all
+/// clients, and remembers recently created invoices, so that a client
+/// reloading a paid file can be sent back to the invoice it already has,
+/// as long as that invoice hasn't been paid.
+#[derive(Clone, Debug)]
+pub(crate) struct InvoiceLimiter {
+  limits: InvoiceLimits,
+  state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+  global: Bucket,
+  clients: HashMap<IpAddr, Bucket>,
+  invoices: HashMap<(IpAddr, String), (String, Instant)>,
+}
+
+impl InvoiceLimiter {
+  const MAX_CLIENTS: usize = 1024;
+
+  pub(crate) fn new(limits: InvoiceLimits) -> Self {
+    Self {
+      limits,
+      state: Arc::new(Mutex::new(State {
+        global: Bucket::new(limits.global_burst, Instant::now()),
+        clients: HashMap::new(),
+        invoices: HashMap::new(),
+      })),
+    }
+  }
+
+  /// The payment hash of an invoice created for `client` and `file_path`
+  /// within the reuse window, if any.
+  pub(crate) fn existing_invoice(&self, client: Option<IpAddr>, file_path: &str) -> Option<String> {
+    let client = client?;
+    let now = Instant::now();
+    let state = self.state.lock().unwrap();
+    let (payment_hash, created) = state.invoices.get(&(client, file_path.to_owned()))?;
+    if now.saturating_duration_since(*created) < self.limits.reuse_window {
+      Some(payment_hash.clone())
+    } else {
+      None
+    }
+  }
+
+  /// Take one invoice from the client's and the global allowance, or fail
+  /// with the time until both allow another invoice.
+  pub(crate) fn acquire(&self, client: Option<IpAddr>) -> Result<()> {
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+    let State {
+      global, clients, ..
+    } = &mut *state;
+
+    let mut retry_after = global.wait(self.limits.global_burst, self.limits.window, now);
+
+    let mut client_bucket = client.map(|client| {
+      clients
+        .entry(client)
+        .or_insert_with(|| Bucket::new(self.limits.client_burst, now))
+    });
+
+    if let Some(bucket) = client_bucket.as_mut() {
+      retry_after = retry_after.max(bucket.wait(self.limits.client_burst, self.limits.window, now));
+    }
+
+    if !retry_after.is_zero() {
+      return Err(error::InvoiceRateLimit { retry_after }.build());
+    }
+
+    global.tokens -= 1.0;
+    if let Some(bucket) = client_bucket {
+      bucket.tokens -= 1.0;
+    }
+
+    // A full bucket behaves exactly like a fresh one, so dropping it is
+    // lossless.
+    if clients.len() > Self::MAX_CLIENTS {
+      let (burst, window) = (self.limits.client_burst, self.limits.window);
+      clients.retain(|_, bucket| bucket.tokens(burst, window, now) < f64::from(burst));
+    }
+
+    Ok(())
+  }
+
+  /// Forget the invoice recorded for `client` and `file_path`, if it is the
+  /// one with `payment_hash`. Called once it has been paid.
+  pub(crate) fn forget_invoice(&self, client: Option<IpAddr>, file_path: &str, payment_hash: &str) {
+    let client = match client {
+      Some(client) => client,
+      None => return,
+    };
+    let mut state = self.state.lock().unwrap();
+    let key = (client, file_path.to_owned());
+    if matches!(state.invoices.get(&key), Some((recorded, _)) if recorded == payment_hash) {
+      state.invoices.remove(&key);
+    }
+  }
+
+  pub(crate) fn record_invoice(
+    &self,
+    client: Option<IpAddr>,
+    file_path: &str,
+    payment_hash: String,
+  ) {
+    let client = match client {
+      Some(client) => client,
+      None => return,
+    };
+    let now = Instant::now();
+    let reuse_window = self.limits.reuse_window;
+    let mut state = self.state.lock().unwrap();
+    state
+      .invoices
+      .retain(|_, (_, created)| now.saturating_duration_since(*created) < reuse_window);
+    state
+      .invoices
+      .insert((client, file_path.to_owned()), (payment_hash, now));
+  }
+}
+
+/// A token bucket holding up to `burst` invoices, refilled at a rate of
+/// `burst` invoices per `window`.
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl Bucket {
+  fn new(burst: u32, now: Instant) -> Self {
+    Self {
+      tokens: f64::from(burst),
+      updated: now,
+    }
+  }
+
+  fn tokens(&mut self, burst: u32, window: Duration, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+    let rate = f64::from(burst) / window.as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rate).min(f64::from(burst));
+    self.updated = now;
+    self.tokens
+  }
+
+  /// How long until the bucket holds a whole token.
+  fn wait(&mut self, burst: u32, window: Duration, now: Instant) -> Duration {
+    let tokens = self.tokens(burst, window, now);
+    if tokens >= 1.0 {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64((1.0 - tokens) * window.as_secs_f64() / f64::from(burst))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(client_burst: u32, global_burst: u32) -> InvoiceLimiter {
+    InvoiceLimiter::new(InvoiceLimits {
+      client_burst,
+      global_burst,
+      window: Duration::from_secs(60),
+      reuse_window: Duration::from_secs(60),
+    })
+  }
+
+  fn ip(last: u8) -> Option<IpAddr> {
+    Some(IpAddr::from([10, 0, 0, last]))
+  }
+
+  #[test]
+  fn clients_are_limited_to_their_burst() {
+    let limiter = limiter(2, 100);
+    limiter.acquire(ip(1)).unwrap();
+    limiter.acquire(ip(1)).unwrap();
+    assert_matches!(
+      limiter.acquire(ip(1)),
+      Err(Error::InvoiceRateLimit { retry_after, .. })
+        if retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30)
+    );
+    limiter.acquire(ip(2)).unwrap();
+  }
+
+  #[test]
+  fn global_limit_applies_across_clients() {
+    let limiter = limiter(10, 2);
+    limiter.acquire(ip(1)).unwrap();
+    limiter.acquire(ip(2)).unwrap();
+    assert_matches!(limiter.acquire(ip(3)), Err(Error::InvoiceRateLimit { .. }));
+    assert_matches!(limiter.acquire(None), Err(Error::InvoiceRateLimit { .. }));
+  }
+
+  #[test]
+  fn rejected_requests_do_not_use_up_allowance() {
+    let limiter = limiter(1, 1);
+    limiter.acquire(ip(1)).unwrap();
+    for _ in 0..10 {
+      assert!(limiter.acquire(ip(2)).is_err());
+    }
+    let mut state = limiter.state.lock().unwrap();
+    assert_eq!(state.clients.get(&ip(2).unwrap()).unwrap().tokens, 1.0);
+    assert!(
+      state
+        .global
+        .tokens(1, Duration::from_secs(60), Instant::now())
+        < 1.0
+    );
+  }
+
+  #[test]
+  fn buckets_refill_over_window() {
+    let now = Instant::now();
+    let window = Duration::from_secs(60);
+    let mut bucket = Bucket::new(2, now);
+    bucket.tokens -= 2.0;
+    assert_eq!(bucket.wait(2, window, now), Duration::from_secs(30));
+    assert_eq!(
+      bucket.wait(2, window, now + Duration::from_secs(30)),
+      Duration::ZERO
+    );
+    assert_eq!(
+      bucket.tokens(2, window, now + Duration::from_secs(600)),
+      2.0
+    );
+  }
+
+  #[test]
+  fn invoices_are_reused_per_client_and_file() {
+    let limiter = limiter(10, 10);
+    assert_eq!(limiter.existing_invoice(ip(1), "foo"), None);
+    limiter.record_invoice(ip(1), "foo", "hash".into());
+    assert_eq!(limiter.existing_invoice(ip(1), "foo"), Some("hash".into()));
+    assert_eq!(limiter.existing_invoice(ip(1), "bar"), None);
+    assert_eq!(limiter.existing_invoice(ip(2), "foo"), None);
+    assert_eq!(limiter.existing_invoice(None, "foo"), None);
+  }
+
+  #[test]
+  fn forgotten_invoices_are_not_reused() {
+    let limiter = limiter(10, 10);
+    limiter.record_invoice(ip(1), "foo", "hash".into());
+    limiter.forget_invoice(ip(1), "foo", "other");
+    assert_eq!(limiter.existing_invoice(ip(1), "foo"), Some("hash".into()));
+    limiter.forget_invoice(ip(1), "foo", "hash");
+    assert_eq!(limiter.existing_invoice(ip(1), "foo"), None);
+  }
+
+  #[test]
+  fn invoices_expire_after_reuse_window() {
+    let limiter = InvoiceLimiter::new(InvoiceLimits {
+      reuse_window: Duration::ZERO,
+      ..limiter(10, 10).limits
+    });
+    limiter.record_invoice(ip(1), "foo", "hash".into());
+    assert_eq!(limiter.existing_invoice(ip(1), "foo"), None);
+  }
+}
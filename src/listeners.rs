@@ -0,0 +1,222 @@
+use {
+  crate::{common::*, proxy_protocol::ProxyProtocolIncoming},
+  socket2::{Domain, Protocol, Socket, Type},
+  tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+  },
+};
+
+/// A hyper server for `service` on a TCP listener, with its local address.
+pub(crate) type TcpServer<S> = (
+  SocketAddr,
+  hyper::Server<ProxyProtocolIncoming<AddrIncoming>, S>,
+);
+
+/// A listener that HTTPS connections are accepted from.
+pub(crate) trait Listen: Send + Sync + 'static {
+  type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+  fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, Peer)>> + Send;
+
+  /// The error to log when accepting a connection fails with `source`.
+  fn accept_error(&self, source: io::Error) -> Error;
+}
+
+impl Listen for TcpListener {
+  type Stream = TcpStream;
+
+  async fn accept(&self) -> io::Result<(TcpStream, Peer)> {
+    let (stream, remote_addr) = TcpListener::accept(self).await?;
+    Ok((stream, Peer::Tcp(remote_addr)))
+  }
+
+  fn accept_error(&self, source: io::Error) -> Error {
+    match self.local_addr() {
+      Ok(socket_addr) => error::SocketIo { socket_addr }.into_error(source),
+      Err(_) => Error::internal(format!("Failed to accept connection: {}", source)),
+    }
+  }
+}
+
+/// The other end of an accepted connection: a client's address, or the path
+/// of the Unix domain socket it connected to.
+#[derive(Clone, Debug)]
+pub(crate) enum Peer {
+  Tcp(SocketAddr),
+  #[cfg(unix)]
+  Unix(PathBuf),
+}
+
+impl Peer {
+  /// The client's IP address, if known.
+  pub(crate) fn ip(&self) -> Option<IpAddr> {
+    match self {
+      Self::Tcp(remote_addr) => Some(remote_addr.ip()),
+      #[cfg(unix)]
+      Self::Unix(_) => None,
+    }
+  }
+}
+
+impl Display for Peer {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      Self::Tcp(remote_addr) => write!(f, "{}", remote_addr),
+      #[cfg(unix)]
+      Self::Unix(path) => write!(f, "{}", path.display()),
+    }
+  }
+}
+
+/// Bind `port` on every address that the `--address` arguments resolve to,
+/// and log each one as listening for `protocol` connections.
+///
+/// If `port` is 0, the port that the OS picks for the first address is used
+/// for the others too, so that every address is reachable on the same port.
+pub(crate) fn bind(
+  environment: &mut Environment,
+  arguments: &Arguments,
+  port: u16,
+  protocol: &str,
+) -> Result<Vec<TcpListener>> {
+  let mut listeners = Vec::new();
+  let mut port = port;
+
+  for socket_addr in resolve(arguments, port)? {
+    let socket_addr = SocketAddr::new(socket_addr.ip(), port);
+    let listener = bind_socket(socket_addr)?;
+    let local_addr = listener
+      .local_addr()
+      .context(error::SocketIo { socket_addr })?;
+    port = local_addr.port();
+    writeln!(
+      environment.stderr,
+      "Listening for {} connections on `{}`",
+      protocol, local_addr,
+    )
+    .context(error::StderrWrite)?;
+    listeners.push(listener);
+  }
+
+  Ok(listeners)
+}
+
+/// Like `bind`, but returns hyper `AddrIncoming`s, each with its local
+/// address.
+pub(crate) fn incoming(
+  environment: &mut Environment,
+  arguments: &Arguments,
+  port: u16,
+  protocol: &str,
+) -> Result<Vec<(SocketAddr, AddrIncoming)>> {
+  bind(environment, arguments, port, protocol)?
+    .into_iter()
+    .map(|listener| {
+      let incoming = AddrIncoming::from_listener(listener).context(error::ServerRun)?;
+      Ok((incoming.local_addr(), incoming))
+    })
+    .collect()
+}
+
+/// Resolve every `--address` argument, dropping duplicates.
+fn resolve(arguments: &Arguments, port: u16) -> Result<Vec<SocketAddr>> {
+  let mut socket_addrs = Vec::new();
+
+  for address in &arguments.address {
+    let resolved = (address.as_str(), port)
+      .to_socket_addrs()
+      .context(error::AddressResolutionIo { input: address })?
+      .collect::<Vec<SocketAddr>>();
+
+    if resolved.is_empty() {
+      return Err(
+        error::AddressResolutionNoAddresses {
+          input: address.clone(),
+        }
+        .build(),
+      );
+    }
+
+    for socket_addr in resolved {
+      if !socket_addrs.contains(&socket_addr) {
+        socket_addrs.push(socket_addr);
+      }
+    }
+  }
+
+  Ok(socket_addrs)
+}
+
+fn bind_socket(socket_addr: SocketAddr) -> Result<TcpListener> {
+  let socket = || -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+      Domain::for_address(socket_addr),
+      Type::STREAM,
+      Some(Protocol::TCP),
+    )?;
+    // Without this, binding `::` also binds `0.0.0.0` on most systems, and
+    // binding both would fail.
+    if socket_addr.is_ipv6() {
+      socket.set_only_v6(true)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket_addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+  };
+
+  socket()
+    .and_then(TcpListener::from_std)
+    .context(error::SocketIo { socket_addr })
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::net::Ipv6Addr};
+
+  #[tokio::test]
+  async fn binds_every_address_on_the_same_port() {
+    let mut environment = Environment::test();
+    environment.arguments = vec![
+      "opuza".into(),
+      "--address=127.0.0.1".into(),
+      "--address=::1".into(),
+      "--address=127.0.0.1".into(),
+      "--http-port=0".into(),
+      "--directory=www".into(),
+    ];
+    let arguments = environment.arguments().unwrap();
+
+    let listeners = bind(&mut environment, &arguments, 0, "HTTP").unwrap();
+
+    let local_addrs = listeners
+      .iter()
+      .map(|listener| listener.local_addr().unwrap())
+      .collect::<Vec<SocketAddr>>();
+    assert_eq!(local_addrs.len(), 2);
+    assert_eq!(local_addrs[0].ip(), IpAddr::from([127, 0, 0, 1]));
+    assert_eq!(local_addrs[1].ip(), IpAddr::from(Ipv6Addr::LOCALHOST));
+    assert_eq!(local_addrs[0].port(), local_addrs[1].port());
+  }
+
+  #[tokio::test]
+  async fn binds_ipv4_and_ipv6_wildcards_together() {
+    let mut environment = Environment::test();
+    environment.arguments = vec![
+      "opuza".into(),
+      "--address=0.0.0.0".into(),
+      "--address=::".into(),
+      "--http-port=0".into(),
+      "--directory=www".into(),
+    ];
+    let arguments = environment.arguments().unwrap();
+
+    assert_eq!(
+      bind(&mut environment, &arguments, 0, "HTTP").unwrap().len(),
+      2
+    );
+  }
+}
@@ -0,0 +1,103 @@
+use {
+  crate::{common::*, vfs::DirEntry},
+  serde::Serialize,
+  std::time::UNIX_EPOCH,
+};
+
+/// The JSON representation of a directory listing, served instead of HTML
+/// when requested with `Accept: application/json` or `?format=json`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Listing {
+  path: String,
+  entries: Vec<Entry>,
+  index: Option<String>,
+  previous: Option<String>,
+  next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+  name: String,
+  #[serde(rename = "type")]
+  kind: EntryKind,
+  size: Option<u64>,
+  modified: Option<u64>,
+  mime_type: Option<String>,
+  paid: bool,
+  price: Option<Piconero>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+  Directory,
+  File,
+  Symlink,
+}
+
+impl Listing {
+  pub(crate) fn new(
+    path: String,
+    entries: &[DirEntry],
+    index: Option<Markup>,
+    previous: Option<String>,
+    next: Option<String>,
+  ) -> Self {
+    Self {
+      path,
+      entries: entries
+        .iter()
+        .map(|entry| Entry {
+          name: entry.file_name.to_string_lossy().into_owned(),
+          kind: if entry.file_type.is_dir() {
+            EntryKind::Directory
+          } else if entry.file_type.is_symlink() {
+            EntryKind::Symlink
+          } else {
+            EntryKind::File
+          },
+          size: entry.file_size,
+          modified: entry
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs()),
+          mime_type: entry
+            .mime_type
+            .as_ref()
+            .map(|mime_type| mime_type.essence_str().to_owned()),
+          paid: entry.paid,
+          price: entry.price,
+        })
+        .collect(),
+      index: index.map(Markup::into_string),
+      previous,
+      next,
+    }
+  }
+
+  /// Whether `request` asks for a JSON listing. An explicit `format` query
+  /// parameter takes precedence over the `Accept` header.
+  pub(crate) fn requested(request: &Request<Body>) -> bool {
+    if let Some(format) = query_parameter(request, "format") {
+      return format == "json";
+    }
+
+    request
+      .headers()
+      .get_all(header::ACCEPT)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .filter_map(|media_range| media_range.split(';').next())
+      .any(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+  }
+
+  pub(crate) fn into_response(self) -> Result<Response<Body>> {
+    let json = serde_json::to_string(&self)
+      .map_err(|error| Error::internal(format!("Failed to serialize listing: {}", error)))?;
+    Response::builder()
+      .header(header::CONTENT_TYPE, "application/json")
+      .body(Body::from(json))
+      .map_err(|error| Error::internal(format!("Failed to construct response: {}", error)))
+  }
+}
@@ -0,0 +1,329 @@
+use crate::common::*;
+
+/// A language of the user interface.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Locale {
+  En,
+  Es,
+  De,
+}
+
+impl Locale {
+  const ALL: &'static [Self] = &[Self::En, Self::Es, Self::De];
+
+  pub(crate) fn code(self) -> &'static str {
+    match self {
+      Self::En => "en",
+      Self::Es => "es",
+      Self::De => "de",
+    }
+  }
+
+  /// Parse a language tag, e.g. `de-AT`, by its primary language subtag.
+  fn from_tag(tag: &str) -> Option<Self> {
+    let language = tag.split('-').next()?.trim();
+    Self::ALL
+      .iter()
+      .copied()
+      .find(|locale| locale.code().eq_ignore_ascii_case(language))
+  }
+
+  /// The locale requested with the `lang` query parameter, or else the
+  /// most preferred supported locale in the `Accept-Language` header, or
+  /// else `default`, or else English.
+  pub(crate) fn new(request: &Request<Body>, default: Option<Self>) -> Self {
+    if let Some(locale) = query_parameter(request, "lang").and_then(|lang| Self::from_tag(&lang)) {
+      return locale;
+    }
+
+    let mut preferences = request
+      .headers()
+      .get_all(header::ACCEPT_LANGUAGE)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .enumerate()
+      .filter_map(|(i, language_range)| {
+        let mut parts = language_range.split(';');
+        let locale = Self::from_tag(parts.next()?)?;
+        let quality = parts
+          .find_map(|parameter| parameter.trim().strip_prefix("q="))
+          .map_or(Some(1.0), |quality| quality.trim().parse::<f64>().ok())?;
+        Some((quality, i, locale))
+      })
+      .filter(|(quality, _, _)| *quality > 0.0)
+      .collect::<Vec<(f64, usize, Self)>>();
+
+    // Prefer higher quality, then earlier position in the header.
+    preferences.sort_by(|(a_quality, a_index, _), (b_quality, b_index, _)| {
+      b_quality
+        .total_cmp(a_quality)
+        .then_with(|| a_index.cmp(b_index))
+    });
+
+    preferences
+      .first()
+      .map(|(_, _, locale)| *locale)
+      .or(default)
+      .unwrap_or(Self::En)
+  }
+
+  /// Number formatting conventions for the locale.
+  pub(crate) fn number_format(self) -> &'static num_format::Locale {
+    match self {
+      Self::En => &num_format::Locale::en,
+      Self::Es => &num_format::Locale::es,
+      Self::De => &num_format::Locale::de,
+    }
+  }
+
+  pub(crate) fn messages(self) -> &'static Messages {
+    match self {
+      Self::En => &Messages::EN,
+      Self::Es => &Messages::ES,
+      Self::De => &Messages::DE,
+    }
+  }
+}
+
+/// The message catalogue of a locale. Messages with parameters are split
+/// around them, e.g. `search_truncated_start` and `search_truncated_end`.
+pub(crate) struct Messages {
+  pub(crate) access_file: &'static str,
+  pub(crate) archive: &'static str,
+  pub(crate) archive_or: &'static str,
+  pub(crate) bad_request: &'static str,
+  pub(crate) breadcrumbs: &'static str,
+  pub(crate) buy: &'static str,
+  pub(crate) download: &'static str,
+  pub(crate) file: &'static str,
+  pub(crate) include_index_pages: &'static str,
+  pub(crate) internal_server_error: &'static str,
+  pub(crate) invoice_instructions_end: &'static str,
+  pub(crate) invoice_instructions_pay_end: &'static str,
+  pub(crate) invoice_instructions_pay_start: &'static str,
+  pub(crate) invoice_instructions_reload: &'static str,
+  pub(crate) invoice_instructions_start: &'static str,
+  pub(crate) invoice_label_middle: &'static str,
+  pub(crate) invoice_label_start: &'static str,
+  pub(crate) invoice_qr_code: &'static str,
+  pub(crate) invoice_title: &'static str,
+  pub(crate) modified: &'static str,
+  pub(crate) name: &'static str,
+  pub(crate) next: &'static str,
+  pub(crate) no_files_found: &'static str,
+  pub(crate) not_found: &'static str,
+  pub(crate) open_invoice_in_wallet: &'static str,
+  pub(crate) paid: &'static str,
+  pub(crate) parent_directory: &'static str,
+  pub(crate) powered_by: &'static str,
+  pub(crate) previous: &'static str,
+  pub(crate) price: &'static str,
+  pub(crate) search: &'static str,
+  pub(crate) search_files: &'static str,
+  pub(crate) search_title: &'static str,
+  pub(crate) search_truncated_end: &'static str,
+  pub(crate) search_truncated_start: &'static str,
+  pub(crate) size: &'static str,
+  pub(crate) sort_by: &'static str,
+  pub(crate) too_many_requests: &'static str,
+  pub(crate) type_: &'static str,
+  pub(crate) view: &'static str,
+  pub(crate) view_source: &'static str,
+}
+
+impl Messages {
+  const EN: Self = Self {
+    access_file: "Access file",
+    archive: "Download free files as ",
+    archive_or: " or ",
+    bad_request: "Bad Request",
+    breadcrumbs: "Breadcrumbs",
+    buy: "Buy",
+    download: "Download",
+    file: "File",
+    include_index_pages: " Include index pages",
+    internal_server_error: "Internal Server Error",
+    invoice_instructions_end: ":",
+    invoice_instructions_pay_end: " above with your Monero wallet by scanning the QR code, \
+      copying the payment request string, or clicking the \"Open invoice in wallet\" link.",
+    invoice_instructions_pay_start: "Pay the invoice for ",
+    invoice_instructions_reload: "Click the \"Access file\" link or reload the page.",
+    invoice_instructions_start: "To access ",
+    invoice_label_middle: " to access ",
+    invoice_label_start: "Monero Payment Request for ",
+    invoice_qr_code: "Monero Network Invoice QR Code",
+    invoice_title: "Invoice for ",
+    modified: "Modified",
+    name: "Name",
+    next: "Next →",
+    no_files_found: "No files found.",
+    not_found: "Not Found",
+    open_invoice_in_wallet: "Open invoice in wallet",
+    paid: "paid",
+    parent_directory: "Parent directory",
+    powered_by: "Powered by ",
+    previous: "← Previous",
+    price: "Price",
+    search: "Search",
+    search_files: "Search files",
+    search_title: "Search for ",
+    search_truncated_end: " results.",
+    search_truncated_start: "Showing the first ",
+    size: "Size",
+    sort_by: "Sort by ",
+    too_many_requests: "Too Many Requests",
+    type_: "Type",
+    view: "View",
+    view_source: "View source",
+  };
+
+  const ES: Self = Self {
+    access_file: "Acceder al archivo",
+    archive: "Descargar los archivos gratuitos como ",
+    archive_or: " o ",
+    bad_request: "Solicitud incorrecta",
+    breadcrumbs: "Ruta de navegación",
+    buy: "Comprar",
+    download: "Descargar",
+    file: "Archivo",
+    include_index_pages: " Incluir páginas de índice",
+    internal_server_error: "Error interno del servidor",
+    invoice_instructions_end: ":",
+    invoice_instructions_pay_end: " con tu monedero Monero escaneando el código QR, \
+      copiando la solicitud de pago o haciendo clic en el enlace \"Abrir factura en el monedero\".",
+    invoice_instructions_pay_start: "Paga la factura de ",
+    invoice_instructions_reload:
+      "Haz clic en el enlace \"Acceder al archivo\" o recarga la página.",
+    invoice_instructions_start: "Para acceder a ",
+    invoice_label_middle: " para acceder a ",
+    invoice_label_start: "Solicitud de pago Monero de ",
+    invoice_qr_code: "Código QR de la factura de Monero",
+    invoice_title: "Factura de ",
+    modified: "Modificado",
+    name: "Nombre",
+    next: "Siguiente →",
+    no_files_found: "No se encontraron archivos.",
+    not_found: "No encontrado",
+    open_invoice_in_wallet: "Abrir factura en el monedero",
+    paid: "de pago",
+    parent_directory: "Directorio superior",
+    powered_by: "Con la tecnología de ",
+    previous: "← Anterior",
+    price: "Precio",
+    search: "Buscar",
+    search_files: "Buscar archivos",
+    search_title: "Búsqueda de ",
+    search_truncated_end: " resultados.",
+    search_truncated_start: "Mostrando los primeros ",
+    size: "Tamaño",
+    sort_by: "Ordenar por ",
+    too_many_requests: "Demasiadas solicitudes",
+    type_: "Tipo",
+    view: "Ver",
+    view_source: "Ver código fuente",
+  };
+
+  const DE: Self = Self {
+    access_file: "Datei abrufen",
+    archive: "Kostenlose Dateien herunterladen als ",
+    archive_or: " oder ",
+    bad_request: "Ungültige Anfrage",
+    breadcrumbs: "Navigationspfad",
+    buy: "Kaufen",
+    download: "Herunterladen",
+    file: "Datei",
+    include_index_pages: " Indexseiten einbeziehen",
+    internal_server_error: "Interner Serverfehler",
+    invoice_instructions_end: " abzurufen:",
+    invoice_instructions_pay_end: " mit deiner Monero-Wallet, indem du den QR-Code scannst, \
+      die Zahlungsanforderung kopierst oder auf den Link \"Rechnung in Wallet öffnen\" klickst.",
+    invoice_instructions_pay_start: "Bezahle die obige Rechnung über ",
+    invoice_instructions_reload: "Klicke auf den Link \"Datei abrufen\" oder lade die Seite neu.",
+    invoice_instructions_start: "Um ",
+    invoice_label_middle: " für ",
+    invoice_label_start: "Monero-Zahlungsanforderung über ",
+    invoice_qr_code: "QR-Code der Monero-Rechnung",
+    invoice_title: "Rechnung für ",
+    modified: "Geändert",
+    name: "Name",
+    next: "Weiter →",
+    no_files_found: "Keine Dateien gefunden.",
+    not_found: "Nicht gefunden",
+    open_invoice_in_wallet: "Rechnung in Wallet öffnen",
+    paid: "kostenpflichtig",
+    parent_directory: "Übergeordnetes Verzeichnis",
+    powered_by: "Betrieben mit ",
+    previous: "← Zurück",
+    price: "Preis",
+    search: "Suchen",
+    search_files: "Dateien suchen",
+    search_title: "Suche nach ",
+    search_truncated_end: " Ergebnisse angezeigt.",
+    search_truncated_start: "Es werden die ersten ",
+    size: "Größe",
+    sort_by: "Sortieren nach ",
+    too_many_requests: "Zu viele Anfragen",
+    type_: "Typ",
+    view: "Ansehen",
+    view_source: "Quelltext ansehen",
+  };
+
+  /// The title of an error page with status `status`.
+  pub(crate) fn status(&self, status: StatusCode) -> &str {
+    match status {
+      StatusCode::BAD_REQUEST => self.bad_request,
+      StatusCode::NOT_FOUND => self.not_found,
+      StatusCode::TOO_MANY_REQUESTS => self.too_many_requests,
+      StatusCode::INTERNAL_SERVER_ERROR => self.internal_server_error,
+      _ => status.canonical_reason().unwrap_or("Error"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn negotiate(accept_language: Option<&str>, uri: &str, default: Option<Locale>) -> Locale {
+    let mut builder = Request::builder().uri(uri);
+    if let Some(accept_language) = accept_language {
+      builder = builder.header(header::ACCEPT_LANGUAGE, accept_language);
+    }
+    Locale::new(&builder.body(Body::empty()).unwrap(), default)
+  }
+
+  #[test]
+  fn defaults_to_english() {
+    assert_eq!(negotiate(None, "/", None), Locale::En);
+    assert_eq!(negotiate(Some("fr, ja"), "/", None), Locale::En);
+  }
+
+  #[test]
+  fn directory_default_applies_without_supported_preference() {
+    assert_eq!(negotiate(None, "/", Some(Locale::De)), Locale::De);
+    assert_eq!(negotiate(Some("fr"), "/", Some(Locale::Es)), Locale::Es);
+    assert_eq!(negotiate(Some("en"), "/", Some(Locale::Es)), Locale::En);
+  }
+
+  #[test]
+  fn accept_language_quality_values() {
+    assert_eq!(negotiate(Some("de-AT, es;q=0.5"), "/", None), Locale::De);
+    assert_eq!(negotiate(Some("de;q=0.3, es;q=0.5"), "/", None), Locale::Es);
+    assert_eq!(
+      negotiate(Some("fr, de;q=0, es;q=0.1"), "/", None),
+      Locale::Es
+    );
+    assert_eq!(negotiate(Some("es, de"), "/", None), Locale::Es);
+  }
+
+  #[test]
+  fn lang_parameter_overrides_everything() {
+    assert_eq!(
+      negotiate(Some("es"), "/?lang=de", Some(Locale::Es)),
+      Locale::De
+    );
+    assert_eq!(negotiate(Some("es"), "/?lang=xx", None), Locale::Es);
+  }
+}
@@ -7,25 +7,53 @@ use {
 #[macro_use]
 mod test_utils;
 
+mod acme;
+mod archive;
 mod arguments;
+mod bandwidth;
+mod bandwidth_limiter;
 mod common;
 mod display_size;
+mod display_time;
 mod environment;
 mod error;
 mod error_page;
+#[cfg(test)]
+mod fake_acme;
+#[cfg(test)]
+mod fake_wallet;
 mod file_stream;
 mod files;
 mod html;
 mod https_redirect_service;
 mod https_request_handler;
 mod input_path;
+mod invoice_limiter;
+mod listeners;
+mod listing;
+mod locale;
+mod page;
+mod proxy_protocol;
+mod query_parameter;
 mod redirect;
 mod request_handler;
+mod search_index;
+mod security_headers;
 mod server;
+mod shutdown_signal;
+mod sites;
+mod sort;
 mod static_assets;
 mod stderr;
+mod template;
 #[cfg(test)]
 mod tests;
+mod theme;
+mod thumbnails;
+mod tls_certificate;
+mod trusted_proxies;
+#[cfg(unix)]
+mod unix_socket;
 mod vfs;
 
 #[tokio::main]
@@ -54,6 +82,7 @@ async fn main() {
 
 async fn run() -> Result<()> {
   let mut environment = Environment::production()?;
+  let shutdown_signal = shutdown_signal()?;
   let server = Server::setup(&mut environment).await?;
-  server.run().await
+  server.run(shutdown_signal).await
 }
@@ -0,0 +1,212 @@
+use {crate::common::*, std::ops::Range};
+
+/// Where a page of a directory listing starts, from the `after` and `before`
+/// query parameters. Both hold the name of an entry, so that pages stay put
+/// when entries are added or removed elsewhere in the directory.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Cursor {
+  Start,
+  After(String),
+  Before(String),
+}
+
+impl Cursor {
+  pub(crate) fn new(request: &Request<Body>) -> Self {
+    if let Some(name) = query_parameter(request, "after") {
+      Self::After(name)
+    } else if let Some(name) = query_parameter(request, "before") {
+      Self::Before(name)
+    } else {
+      Self::Start
+    }
+  }
+
+  /// A link to the page starting at this cursor, keeping all other query
+  /// parameters of `request`.
+  pub(crate) fn link(&self, request: &Request<Body>) -> String {
+    let mut serializer = form_urlencoded::Serializer::for_suffix(String::from("?"), 1);
+    for (key, value) in form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes()) {
+      if key != "after" && key != "before" {
+        serializer.append_pair(&key, &value);
+      }
+    }
+    match self {
+      Self::Start => {}
+      Self::After(name) => {
+        serializer.append_pair("after", name);
+      }
+      Self::Before(name) => {
+        serializer.append_pair("before", name);
+      }
+    }
+    serializer.finish()
+  }
+
+  /// The range of the page of `len` entries, given `position`, which looks
+  /// up the index of an entry by name. If the cursor's entry no longer
+  /// exists, the first page is returned.
+  pub(crate) fn range(
+    &self,
+    len: usize,
+    page_size: usize,
+    position: impl Fn(&str) -> Option<usize>,
+  ) -> Range<usize> {
+    let first = 0..page_size.min(len);
+    match self {
+      Self::Start => first,
+      Self::After(name) => match position(name) {
+        Some(i) => i + 1..(i + 1 + page_size).min(len),
+        None => first,
+      },
+      Self::Before(name) => match position(name) {
+        Some(i) => i.saturating_sub(page_size)..i,
+        None => first,
+      },
+    }
+  }
+}
+
+/// One page of a directory listing, along with the cursors of the previous
+/// and next pages, if any.
+pub(crate) struct Page<T> {
+  pub(crate) entries: Vec<T>,
+  pub(crate) previous: Option<Cursor>,
+  pub(crate) next: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+  pub(crate) fn new(
+    mut entries: Vec<T>,
+    cursor: &Cursor,
+    page_size: usize,
+    name: impl Fn(&T) -> String,
+  ) -> Self {
+    let len = entries.len();
+    let range = cursor.range(len, page_size, |cursor| {
+      entries.iter().position(|entry| name(entry) == cursor)
+    });
+
+    let page = entries.drain(range.clone()).collect::<Vec<T>>();
+
+    Self {
+      previous: page
+        .first()
+        .filter(|_| range.start > 0)
+        .map(|entry| Cursor::Before(name(entry))),
+      next: page
+        .last()
+        .filter(|_| range.end < len)
+        .map(|entry| Cursor::After(name(entry))),
+      entries: page,
+    }
+  }
+
+  pub(crate) fn try_map<U>(self, f: impl FnMut(T) -> Result<U>) -> Result<Page<U>> {
+    Ok(Page {
+      entries: self
+        .entries
+        .into_iter()
+        .map(f)
+        .collect::<Result<Vec<U>>>()?,
+      previous: self.previous,
+      next: self.next,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(
+    names: &[&str],
+    cursor: Cursor,
+    page_size: usize,
+  ) -> (Vec<String>, Option<Cursor>, Option<Cursor>) {
+    let page = Page::new(
+      names.iter().map(|name| name.to_string()).collect(),
+      &cursor,
+      page_size,
+      String::clone,
+    );
+    (page.entries, page.previous, page.next)
+  }
+
+  #[test]
+  fn first_page() {
+    assert_eq!(
+      page(&["a", "b", "c"], Cursor::Start, 2),
+      (
+        vec!["a".into(), "b".into()],
+        None,
+        Some(Cursor::After("b".into()))
+      )
+    );
+  }
+
+  #[test]
+  fn single_page() {
+    assert_eq!(
+      page(&["a", "b"], Cursor::Start, 2),
+      (vec!["a".into(), "b".into()], None, None)
+    );
+    assert_eq!(page(&[], Cursor::Start, 2), (vec![], None, None));
+  }
+
+  #[test]
+  fn pages_after_cursor() {
+    assert_eq!(
+      page(&["a", "b", "c", "d", "e"], Cursor::After("b".into()), 2),
+      (
+        vec!["c".into(), "d".into()],
+        Some(Cursor::Before("c".into())),
+        Some(Cursor::After("d".into()))
+      )
+    );
+    assert_eq!(
+      page(&["a", "b", "c"], Cursor::After("b".into()), 2),
+      (vec!["c".into()], Some(Cursor::Before("c".into())), None)
+    );
+  }
+
+  #[test]
+  fn pages_before_cursor() {
+    assert_eq!(
+      page(&["a", "b", "c", "d", "e"], Cursor::Before("d".into()), 2),
+      (
+        vec!["b".into(), "c".into()],
+        Some(Cursor::Before("b".into())),
+        Some(Cursor::After("c".into()))
+      )
+    );
+    assert_eq!(
+      page(&["a", "b", "c"], Cursor::Before("b".into()), 2),
+      (vec!["a".into()], None, Some(Cursor::After("a".into())))
+    );
+  }
+
+  #[test]
+  fn links_keep_other_query_parameters() {
+    let request = Request::builder()
+      .uri("/files/?sort=size&after=a&format=json")
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(
+      Cursor::Before("foo bar".into()).link(&request),
+      "?sort=size&format=json&before=foo+bar"
+    );
+    assert_eq!(Cursor::Start.link(&request), "?sort=size&format=json");
+  }
+
+  #[test]
+  fn missing_cursor_entries_return_first_page() {
+    assert_eq!(
+      page(&["a", "b", "c"], Cursor::After("x".into()), 2),
+      (
+        vec!["a".into(), "b".into()],
+        None,
+        Some(Cursor::After("b".into()))
+      )
+    );
+  }
+}
@@ -0,0 +1,374 @@
+use {
+  crate::{common::*, trusted_proxies::TrustedProxies},
+  hyper::server::{accept::Accept, conn::AddrStream},
+  std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::OnceLock,
+  },
+  tokio::io::{AsyncRead, AsyncWrite, ReadBuf},
+};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// The source address a PROXY protocol header reported, once it has been
+/// read. Shared between a connection's stream and the handler serving it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProxySource(Arc<OnceLock<SocketAddr>>);
+
+impl ProxySource {
+  /// The client's address according to the PROXY protocol header, or `peer`
+  /// if there was none, or it didn't contain an address.
+  pub(crate) fn or(&self, peer: Option<IpAddr>) -> Option<IpAddr> {
+    self
+      .0
+      .get()
+      .map(|source| source.ip().to_canonical())
+      .or(peer)
+  }
+}
+
+/// The streams that PROXY protocol headers can be read from.
+pub(crate) trait RemoteIp {
+  /// The IP address of the other end, or `None` for Unix domain sockets.
+  fn remote_ip(&self) -> Option<IpAddr>;
+}
+
+impl RemoteIp for AddrStream {
+  fn remote_ip(&self) -> Option<IpAddr> {
+    Some(self.remote_addr().ip())
+  }
+}
+
+#[cfg(unix)]
+impl RemoteIp for UnixStream {
+  fn remote_ip(&self) -> Option<IpAddr> {
+    None
+  }
+}
+
+/// A stream that, if `--proxy-protocol` is given and the peer is a trusted
+/// proxy, starts with a PROXY protocol v1 or v2 header. The header is read
+/// on the first read and removed from the stream.
+pub(crate) struct ProxyProtocolStream<S> {
+  inner: S,
+  header: Option<Vec<u8>>,
+  leftover: Vec<u8>,
+  source: ProxySource,
+}
+
+impl<S> ProxyProtocolStream<S> {
+  const V1_PREFIX: &'static [u8] = b"PROXY ";
+  const V1_MAX_LENGTH: usize = 107;
+  const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+  const V2_HEADER_LENGTH: usize = 16;
+
+  pub(crate) fn new(inner: S, expect_header: bool) -> Self {
+    Self {
+      inner,
+      header: expect_header.then(Vec::new),
+      leftover: Vec::new(),
+      source: ProxySource::default(),
+    }
+  }
+
+  pub(crate) fn inner(&self) -> &S {
+    &self.inner
+  }
+
+  pub(crate) fn source(&self) -> ProxySource {
+    self.source.clone()
+  }
+
+  /// Parse the header at the start of `buffer`. Returns `None` if more data
+  /// is needed, or else the source address, if the header contains one, and
+  /// the length of the header.
+  fn parse(buffer: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, &'static str> {
+    if buffer.starts_with(Self::V1_PREFIX) {
+      Self::parse_v1(buffer)
+    } else if buffer.starts_with(Self::V2_SIGNATURE) {
+      Self::parse_v2(buffer)
+    } else if Self::V1_PREFIX.starts_with(buffer) || Self::V2_SIGNATURE.starts_with(buffer) {
+      Ok(None)
+    } else {
+      Err("connection from trusted proxy did not start with a PROXY protocol header")
+    }
+  }
+
+  fn parse_v1(buffer: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, &'static str> {
+    const INVALID: &str = "invalid PROXY protocol v1 header";
+
+    let end = match buffer.windows(2).position(|window| window == b"\r\n") {
+      Some(end) if end + 2 <= Self::V1_MAX_LENGTH => end,
+      Some(_) => return Err(INVALID),
+      None if buffer.len() >= Self::V1_MAX_LENGTH => return Err(INVALID),
+      None => return Ok(None),
+    };
+
+    let line = str::from_utf8(&buffer[..end]).map_err(|_| INVALID)?;
+
+    let source = match line.split(' ').collect::<Vec<&str>>().as_slice() {
+      ["PROXY", "UNKNOWN", ..] => None,
+      ["PROXY", "TCP4", source, _, port, _] => Some(SocketAddr::new(
+        IpAddr::V4(source.parse().map_err(|_| INVALID)?),
+        port.parse().map_err(|_| INVALID)?,
+      )),
+      ["PROXY", "TCP6", source, _, port, _] => Some(SocketAddr::new(
+        IpAddr::V6(source.parse().map_err(|_| INVALID)?),
+        port.parse().map_err(|_| INVALID)?,
+      )),
+      _ => return Err(INVALID),
+    };
+
+    Ok(Some((source, end + 2)))
+  }
+
+  fn parse_v2(buffer: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, &'static str> {
+    const INVALID: &str = "invalid PROXY protocol v2 header";
+
+    if buffer.len() < Self::V2_HEADER_LENGTH {
+      return Ok(None);
+    }
+
+    let version_command = buffer[12];
+    let family = buffer[13];
+    let length = Self::V2_HEADER_LENGTH + usize::from(u16::from_be_bytes([buffer[14], buffer[15]]));
+
+    if version_command >> 4 != 2 {
+      return Err(INVALID);
+    }
+
+    if buffer.len() < length {
+      return Ok(None);
+    }
+
+    let addresses = &buffer[Self::V2_HEADER_LENGTH..length];
+
+    let source = match (version_command & 0x0F, family >> 4) {
+      // `LOCAL`, e.g. health checks by the proxy itself
+      (0, _) => None,
+      (1, 1) if addresses.len() >= 12 => Some(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(
+          addresses[0],
+          addresses[1],
+          addresses[2],
+          addresses[3],
+        )),
+        u16::from_be_bytes([addresses[8], addresses[9]]),
+      )),
+      (1, 2) if addresses.len() >= 36 => {
+        let mut source = [0; 16];
+        source.copy_from_slice(&addresses[..16]);
+        Some(SocketAddr::new(
+          IpAddr::V6(Ipv6Addr::from(source)),
+          u16::from_be_bytes([addresses[32], addresses[33]]),
+        ))
+      }
+      // `UNSPEC` and `UNIX` families carry no IP address.
+      (1, 0) | (1, 3) => None,
+      _ => return Err(INVALID),
+    };
+
+    Ok(Some((source, length)))
+  }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ProxyProtocolStream<S> {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let stream = self.get_mut();
+
+    while let Some(header) = &mut stream.header {
+      match Self::parse(header) {
+        Ok(Some((source, length))) => {
+          if let Some(source) = source {
+            stream.source.0.set(source).ok();
+          }
+          stream.leftover = header.split_off(length);
+          stream.header = None;
+        }
+        Ok(None) => {
+          let mut chunk = [0; 256];
+          let mut chunk = ReadBuf::new(&mut chunk);
+          futures::ready!(Pin::new(&mut stream.inner).poll_read(cx, &mut chunk))?;
+          if chunk.filled().is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+          }
+          header.extend_from_slice(chunk.filled());
+        }
+        Err(message) => {
+          return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+        }
+      }
+    }
+
+    if !stream.leftover.is_empty() {
+      let length = stream.leftover.len().min(buf.remaining());
+      buf.put_slice(&stream.leftover[..length]);
+      stream.leftover.drain(..length);
+      return Poll::Ready(Ok(()));
+    }
+
+    Pin::new(&mut stream.inner).poll_read(cx, buf)
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ProxyProtocolStream<S> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+  }
+}
+
+/// Wraps the connections `incoming` accepts in `ProxyProtocolStream`s.
+pub(crate) struct ProxyProtocolIncoming<I> {
+  incoming: I,
+  trusted_proxies: TrustedProxies,
+}
+
+impl<I> ProxyProtocolIncoming<I> {
+  pub(crate) fn new(incoming: I, trusted_proxies: TrustedProxies) -> Self {
+    Self {
+      incoming,
+      trusted_proxies,
+    }
+  }
+}
+
+impl<I> Accept for ProxyProtocolIncoming<I>
+where
+  I: Accept + Unpin,
+  I::Conn: RemoteIp,
+{
+  type Conn = ProxyProtocolStream<I::Conn>;
+  type Error = I::Error;
+
+  fn poll_accept(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    let incoming = self.get_mut();
+    Pin::new(&mut incoming.incoming)
+      .poll_accept(cx)
+      .map_ok(|stream| {
+        let expect_header = incoming
+          .trusted_proxies
+          .expect_proxy_protocol(stream.remote_ip());
+        ProxyProtocolStream::new(stream, expect_header)
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, tokio::io::AsyncReadExt};
+
+  type Stream = ProxyProtocolStream<&'static [u8]>;
+
+  fn parse(buffer: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, &'static str> {
+    Stream::parse(buffer)
+  }
+
+  fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = Stream::V2_SIGNATURE.to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+  }
+
+  #[test]
+  fn v1() {
+    let header = b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nGET";
+    assert_eq!(
+      parse(header),
+      Ok(Some((
+        Some("1.2.3.4:1111".parse().unwrap()),
+        header.len() - 3
+      )))
+    );
+    assert_eq!(
+      parse(b"PROXY TCP6 2001:db8::1 ::1 1111 443\r\n"),
+      Ok(Some((Some("[2001:db8::1]:1111".parse().unwrap()), 37)))
+    );
+    assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Ok(Some((None, 15))));
+    assert_eq!(parse(b"PROXY TCP4 1.2.3.4 5.6."), Ok(None));
+    assert_eq!(parse(b"PRO"), Ok(None));
+    assert!(parse(b"PROXY TCP4 1.2.3.4\r\n").is_err());
+    assert!(parse(b"PROXY TCP6 1.2.3.4 5.6.7.8 1111 80\r\n").is_err());
+    assert!(parse(&[b"PROXY ".as_slice(), &[b'x'; 120]].concat()).is_err());
+  }
+
+  #[test]
+  fn v2_addresses() {
+    let mut ipv4 = vec![1, 2, 3, 4, 5, 6, 7, 8];
+    ipv4.extend_from_slice(&1111u16.to_be_bytes());
+    ipv4.extend_from_slice(&80u16.to_be_bytes());
+    let header = v2(1, 0x11, &ipv4);
+    assert_eq!(
+      parse(&header),
+      Ok(Some((Some("1.2.3.4:1111".parse().unwrap()), 28)))
+    );
+    assert_eq!(parse(&header[..20]), Ok(None));
+    assert_eq!(parse(&header[..5]), Ok(None));
+
+    let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+    ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    ipv6.extend_from_slice(&1111u16.to_be_bytes());
+    ipv6.extend_from_slice(&443u16.to_be_bytes());
+    assert_eq!(
+      parse(&v2(1, 0x21, &ipv6)),
+      Ok(Some((Some("[2001:db8::1]:1111".parse().unwrap()), 52)))
+    );
+  }
+
+  #[test]
+  fn v2_without_addresses() {
+    assert_eq!(parse(&v2(0, 0x11, &[0; 12])), Ok(Some((None, 28))));
+    assert_eq!(parse(&v2(1, 0x00, &[])), Ok(Some((None, 16))));
+    assert!(parse(&v2(2, 0x11, &[0; 12])).is_err());
+    assert!(parse(&v2(1, 0x11, &[0; 4])).is_err());
+  }
+
+  #[test]
+  fn missing_header() {
+    assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+  }
+
+  #[tokio::test]
+  async fn header_is_removed_from_stream() {
+    let mut stream = Stream::new(
+      b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nGET / HTTP/1.1\r\n",
+      true,
+    );
+    let source = stream.source();
+    let mut request = String::new();
+    stream.read_to_string(&mut request).await.unwrap();
+    assert_eq!(request, "GET / HTTP/1.1\r\n");
+    assert_eq!(source.or(None), Some([1, 2, 3, 4].into()));
+  }
+
+  #[tokio::test]
+  async fn streams_without_header_are_untouched() {
+    let mut stream = Stream::new(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\n", false);
+    let source = stream.source();
+    let mut request = String::new();
+    stream.read_to_string(&mut request).await.unwrap();
+    assert_eq!(request, "PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\n");
+    assert_eq!(
+      source.or(Some([5, 6, 7, 8].into())),
+      Some([5, 6, 7, 8].into())
+    );
+  }
+}
@@ -0,0 +1,10 @@
+use crate::common::*;
+
+pub(crate) fn query_parameter(request: &Request<Body>, name: &str) -> Option<String> {
+  request.uri().query().and_then(|query| {
+    form_urlencoded::parse(query.as_bytes())
+      .filter(|(key, _value)| key == name)
+      .last()
+      .map(|(_key, value)| value.into_owned())
+  })
+}
@@ -1,27 +1,85 @@
-use crate::{common::*, error_page, files::Files, static_assets::StaticAssets};
+use crate::{
+  acme::Http01Challenges,
+  bandwidth_limiter::Connection,
+  common::*,
+  error_page,
+  locale::Locale,
+  proxy_protocol::{ProxyProtocolStream, ProxySource, RemoteIp},
+  security_headers::SecurityHeaders,
+  sites::{Site, Sites, SitesConfig},
+  theme::Theme,
+  trusted_proxies::TrustedProxies,
+};
 
 #[derive(Clone)]
 pub(crate) struct RequestHandler {
   pub(crate) stderr: Stderr,
-  pub(crate) files: Files,
+  sites: Sites,
+  pub(crate) theme: Theme,
+  trusted_proxies: TrustedProxies,
+  security_headers: SecurityHeaders,
+  http01_challenges: Option<Http01Challenges>,
+  connection: Option<Connection>,
+  proxy_source: ProxySource,
 }
 
 impl RequestHandler {
   pub(crate) fn new(
     environment: &Environment,
-    base_directory: &Path,
+    arguments: &Arguments,
+    sites: &SitesConfig,
     rpc_client: Option<opuza_monero_client::MoneroRpcClient>,
+    http01_challenges: Option<Http01Challenges>,
+  ) -> Result<Self> {
+    let theme = match &arguments.theme_directory {
+      Some(directory) => Theme::load(&environment.working_directory.join(directory))?,
+      None => Theme::default(),
+    };
+    Ok(Self {
+      stderr: environment.stderr.clone(),
+      sites: Sites::new(environment, arguments, sites, rpc_client, &theme)?,
+      theme,
+      trusted_proxies: TrustedProxies::new(arguments),
+      security_headers: SecurityHeaders::new(arguments),
+      http01_challenges,
+      connection: None,
+      proxy_source: ProxySource::default(),
+    })
+  }
+
+  /// A handler for a new connection from `peer`, which is `None` for
+  /// connections over a Unix domain socket, made over HTTPS if `https` is
+  /// true.
+  pub(crate) fn for_connection(
+    &self,
+    peer: Option<IpAddr>,
+    https: bool,
+    proxy_source: ProxySource,
   ) -> Self {
     Self {
-      stderr: environment.stderr.clone(),
-      files: Files::new(InputPath::new(environment, base_directory), rpc_client),
+      connection: Some(Connection::new(peer, https)),
+      proxy_source,
+      ..self.clone()
     }
   }
 
-  async fn response(mut self, request: Request<Body>) -> Result<Response<Body>> {
-    tokio::spawn(async move { self.dispatch(request).await.map(Self::add_global_headers) })
-      .await
-      .context(error::RequestHandlerPanic)?
+  pub(crate) fn trusted_proxies(&self) -> &TrustedProxies {
+    &self.trusted_proxies
+  }
+
+  pub(crate) fn http01_challenges(&self) -> Option<&Http01Challenges> {
+    self.http01_challenges.as_ref()
+  }
+
+  async fn response(self, site: Result<Site>, request: Request<Body>) -> Result<Response<Body>> {
+    tokio::spawn(async move {
+      self
+        .dispatch(site, request)
+        .await
+        .map(Self::add_global_headers)
+    })
+    .await
+    .context(error::RequestHandlerPanic)?
   }
 
   fn add_global_headers(mut response: Response<Body>) -> Response<Body> {
@@ -30,6 +88,9 @@ impl RequestHandler {
       HeaderValue::from_static("no-store, max-age=0"),
     );
     response
+      .headers_mut()
+      .append(header::VARY, HeaderValue::from_static("accept-language"));
+    response
   }
 
   fn decode_invoice_id(invoice_id_hex: &str) -> Result<[u8; 32]> {
@@ -53,7 +114,19 @@ impl RequestHandler {
     result
   }
 
-  async fn dispatch(&mut self, request: Request<Body>) -> Result<Response<Body>> {
+  /// Respond to `request` with `site`, which is an error if the host it was
+  /// made to isn't served. ACME challenges are answered regardless.
+  async fn dispatch(&self, site: Result<Site>, request: Request<Body>) -> Result<Response<Body>> {
+    if let Some(response) = self
+      .http01_challenges
+      .as_ref()
+      .and_then(|challenges| challenges.response(&request))
+    {
+      return Ok(response);
+    }
+
+    let Site { mut files, theme } = site?;
+
     let path = percent_encoding::percent_decode_str(request.uri().path())
       .decode_utf8()
       .context(error::InvalidUriPath {
@@ -61,33 +134,29 @@ impl RequestHandler {
       })?;
     let components = Self::split_path_inclusive(&path);
 
-    let invoice_parameter = request.uri().query().and_then(|query| {
-      form_urlencoded::parse(query.as_bytes())
-        .filter(|(key, _value)| key == "invoice")
-        .last()
-        .map(|(_key, value)| value.into_owned())
-    });
+    let invoice_parameter = query_parameter(&request, "invoice");
 
     match components.as_slice() {
       ["/"] => redirect(String::from(request.uri().path()) + "files/"),
       ["/", asset] if ["apple-touch-icon.png", "favicon.ico"].contains(asset) => {
-        StaticAssets::serve(&[asset])
+        theme.serve_static(&[asset]).await
       }
-      ["/", "static/", tail @ ..] => StaticAssets::serve(tail),
+      ["/", "static/", tail @ ..] => theme.serve_static(tail).await,
       ["/", "files"] => redirect(String::from(request.uri().path()) + "/"),
       ["/", "files/", tail @ ..] if invoice_parameter.is_some() => {
         let invoice_id = invoice_parameter.expect("invoice_parameter is some");
         let invoice_id = Self::decode_invoice_id(&invoice_id)?;
-        self.files.serve_invoice(&request, tail, invoice_id).await
+        files.serve_invoice(&request, tail, invoice_id).await
       }
-      ["/", "files/", tail @ ..] => self.files.serve(&request, tail).await,
+      ["/", "files/", tail @ ..] => files.serve(&request, tail).await,
+      ["/", "search"] => files.search(&request).await,
       ["/", "invoice/", file_name] if file_name.ends_with(".svg") => {
         let invoice_id = Self::decode_invoice_id(
           file_name
             .strip_suffix(".svg")
             .expect("file_name ends with `.svg`"),
         )?;
-        self.files.serve_invoice_qr_code(&request, invoice_id).await
+        files.serve_invoice_qr_code(&request, invoice_id).await
       }
       _ => Err(Error::RouteNotFound {
         uri_path: request.uri().path().to_owned(),
@@ -105,14 +174,60 @@ impl Service<Request<Body>> for RequestHandler {
     Ok(()).into()
   }
 
-  fn call(&mut self, request: Request<Body>) -> Self::Future {
-    log::debug!("Incoming: {:?}", request);
+  fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+    let mut forwarded_host = None;
+    if let Some(mut connection) = self.connection {
+      let forwarded = self.trusted_proxies.forwarded(
+        self.proxy_source.or(connection.client),
+        connection.https,
+        request.headers(),
+      );
+      connection.client = forwarded.client;
+      connection.https = forwarded.https;
+      forwarded_host = forwarded.host;
+      request.extensions_mut().insert(connection);
+    }
+    let site = self
+      .sites
+      .site(
+        forwarded_host
+          .as_deref()
+          .or_else(|| {
+            request
+              .headers()
+              .get(header::HOST)
+              .and_then(|host| host.to_str().ok())
+          })
+          .or_else(|| {
+            request
+              .uri()
+              .authority()
+              .map(|authority| authority.as_str())
+          }),
+      )
+      .cloned();
+    log::debug!(
+      "Incoming from {:?}: {:?}",
+      request.extensions().get::<Connection>(),
+      request
+    );
     let stderr = self.stderr.clone();
+    let theme = match &site {
+      Ok(site) => site.theme.clone(),
+      Err(_) => self.theme.clone(),
+    };
+    let security_headers = self.security_headers.clone();
+    let https = request
+      .extensions()
+      .get::<Connection>()
+      .is_some_and(|connection| connection.https);
+    let locale = Locale::new(&request, None);
     self
       .clone()
-      .response(request)
+      .response(site, request)
       .map(move |result| {
-        let response = error_page::map_error(stderr, result);
+        let mut response = error_page::map_error(stderr, &theme, locale, result);
+        security_headers.apply(response.headers_mut(), https);
         log::debug!("Outgoing: {:?}", response);
         Ok(response)
       })
@@ -120,6 +235,24 @@ impl Service<Request<Body>> for RequestHandler {
   }
 }
 
+impl<'a, S: RemoteIp> Service<&'a ProxyProtocolStream<S>> for RequestHandler {
+  type Response = RequestHandler;
+  type Error = Infallible;
+  type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Ok(()).into()
+  }
+
+  fn call(&mut self, stream: &'a ProxyProtocolStream<S>) -> Self::Future {
+    future::ready(Ok(self.for_connection(
+      stream.inner().remote_ip(),
+      false,
+      stream.source(),
+    )))
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -0,0 +1,174 @@
+use {
+  crate::{common::*, vfs::Vfs},
+  notify::{RecommendedWatcher, RecursiveMode, Watcher},
+  std::sync::atomic::{AtomicBool, Ordering},
+  tokio::sync::Mutex,
+};
+
+/// An in-memory index of every file and directory in the served tree, used
+/// to answer searches. It is built on the first search, and rebuilt on the
+/// next search after a filesystem watcher reports a change.
+#[derive(Clone, Debug)]
+pub(crate) struct SearchIndex {
+  vfs: Vfs,
+  base_directory: InputPath,
+  state: Arc<Mutex<State>>,
+  stale: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  entries: Option<Vec<IndexEntry>>,
+  watch: Watch,
+}
+
+#[derive(Debug, Default)]
+enum Watch {
+  #[default]
+  Pending,
+  // The watcher stops watching once dropped.
+  Watching {
+    _watcher: RecommendedWatcher,
+  },
+  Failed,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IndexEntry {
+  /// The `/`-separated path relative to the base directory, ending with a
+  /// `/` for directories.
+  pub(crate) path: String,
+  pub(crate) size: Option<u64>,
+  pub(crate) price: Option<Piconero>,
+  /// The contents of the directory's `.index.md`, lowercased.
+  index_md: Option<String>,
+}
+
+impl SearchIndex {
+  pub(crate) const MAX_RESULTS: usize = 100;
+
+  pub(crate) fn new(vfs: Vfs, base_directory: InputPath) -> Self {
+    Self {
+      vfs,
+      base_directory,
+      state: Arc::new(Mutex::new(State::default())),
+      stale: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  /// Find the entries whose path contains every whitespace-separated term of
+  /// `query`, ignoring case. If `include_index` is set, directories whose
+  /// `.index.md` contains a term match that term as well. Returns at most
+  /// `MAX_RESULTS` entries, and whether more matched.
+  pub(crate) async fn search(
+    &self,
+    query: &str,
+    include_index: bool,
+  ) -> Result<(Vec<IndexEntry>, bool)> {
+    let terms = query
+      .split_whitespace()
+      .map(str::to_lowercase)
+      .collect::<Vec<String>>();
+
+    if terms.is_empty() {
+      return Ok((Vec::new(), false));
+    }
+
+    let mut state = self.state.lock().await;
+
+    if let Watch::Pending = state.watch {
+      state.watch = self.watch();
+    }
+
+    let stale = self.stale.swap(false, Ordering::SeqCst);
+    if state.entries.is_none() || stale || matches!(state.watch, Watch::Failed) {
+      state.entries = Some(self.build().await?);
+    }
+
+    let mut matches = state
+      .entries
+      .iter()
+      .flatten()
+      .filter(|entry| {
+        let path = entry.path.to_lowercase();
+        terms.iter().all(|term| {
+          path.contains(term)
+            || (include_index
+              && matches!(&entry.index_md, Some(index_md) if index_md.contains(term)))
+        })
+      })
+      .take(Self::MAX_RESULTS + 1)
+      .cloned()
+      .collect::<Vec<IndexEntry>>();
+
+    let truncated = matches.len() > Self::MAX_RESULTS;
+    matches.truncate(Self::MAX_RESULTS);
+
+    Ok((matches, truncated))
+  }
+
+  /// Watch the base directory, marking the index as stale on any change.
+  /// Reads don't count as changes, so that downloads don't cause rebuilds.
+  fn watch(&self) -> Watch {
+    let stale = self.stale.clone();
+    let result = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+      if !matches!(&event, Ok(event) if event.kind.is_access()) {
+        stale.store(true, Ordering::SeqCst);
+      }
+    })
+    .and_then(|mut watcher| {
+      watcher.watch(self.base_directory.as_ref(), RecursiveMode::Recursive)?;
+      Ok(watcher)
+    });
+
+    match result {
+      Ok(watcher) => Watch::Watching { _watcher: watcher },
+      Err(error) => {
+        log::warn!(
+          "Failed to watch `{}` for changes, rebuilding the search index on every search: {}",
+          self.base_directory.display_path().display(),
+          error
+        );
+        Watch::Failed
+      }
+    }
+  }
+
+  async fn build(&self) -> Result<Vec<IndexEntry>> {
+    let mut entries = vec![IndexEntry {
+      path: String::new(),
+      size: None,
+      price: None,
+      index_md: self.index_md(&self.base_directory)?,
+    }];
+
+    for walk_entry in self.vfs.walk(&self.base_directory).await? {
+      let is_dir = walk_entry.metadata.is_dir();
+      entries.push(IndexEntry {
+        index_md: if is_dir {
+          self.index_md(&walk_entry.input_path)?
+        } else {
+          None
+        },
+        size: if is_dir {
+          None
+        } else {
+          Some(walk_entry.metadata.len())
+        },
+        price: walk_entry.entry.price,
+        path: walk_entry.path,
+      });
+    }
+
+    Ok(entries)
+  }
+
+  fn index_md(&self, dir: &InputPath) -> Result<Option<String>> {
+    Ok(
+      self
+        .vfs
+        .index_file_markdown(dir)?
+        .map(|markdown| markdown.to_lowercase()),
+    )
+  }
+}
@@ -0,0 +1,128 @@
+use crate::common::*;
+
+/// Headers that tell browsers to lock down the pages they're sent with.
+#[derive(Clone, Debug)]
+pub(crate) struct SecurityHeaders {
+  content_security_policy: HeaderValue,
+  referrer_policy: HeaderValue,
+  strict_transport_security: HeaderValue,
+}
+
+impl SecurityHeaders {
+  /// Only same-origin scripts, styles and images, and no framing. Pages
+  /// contain no inline script, so that injected markup can't run any.
+  pub(crate) const CONTENT_SECURITY_POLICY: &'static str = "default-src 'self'; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'";
+
+  /// Invoice IDs in URLs mustn't leak to other sites via links.
+  pub(crate) const REFERRER_POLICY: &'static str = "no-referrer";
+
+  /// One year.
+  pub(crate) const STRICT_TRANSPORT_SECURITY: &'static str = "max-age=31536000";
+
+  pub(crate) fn new(arguments: &Arguments) -> Self {
+    Self {
+      content_security_policy: arguments.content_security_policy.clone(),
+      referrer_policy: arguments.referrer_policy.clone(),
+      strict_transport_security: arguments.strict_transport_security.clone(),
+    }
+  }
+
+  /// Add the headers to `headers`, including `Strict-Transport-Security` if
+  /// the response is sent over HTTPS. Headers set to the empty string are
+  /// left out.
+  pub(crate) fn apply(&self, headers: &mut header::HeaderMap, https: bool) {
+    headers.insert(
+      header::X_CONTENT_TYPE_OPTIONS,
+      HeaderValue::from_static("nosniff"),
+    );
+
+    let mut insert = |name, value: &HeaderValue| {
+      if !value.is_empty() {
+        headers.insert(name, value.clone());
+      }
+    };
+
+    insert(
+      header::CONTENT_SECURITY_POLICY,
+      &self.content_security_policy,
+    );
+    insert(header::REFERRER_POLICY, &self.referrer_policy);
+    if https {
+      insert(
+        header::STRICT_TRANSPORT_SECURITY,
+        &self.strict_transport_security,
+      );
+    }
+  }
+}
+
+pub(crate) fn parse_header_value(value: &str) -> Result<HeaderValue, String> {
+  HeaderValue::from_str(value).map_err(|_| format!("invalid header value `{}`", value))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn security_headers(arguments: &[&str]) -> SecurityHeaders {
+    SecurityHeaders::new(
+      &Arguments::try_parse_from(
+        ["opuza", "--directory=www", "--http-port=0"]
+          .iter()
+          .chain(arguments),
+      )
+      .unwrap(),
+    )
+  }
+
+  #[test]
+  fn defaults() {
+    let mut headers = header::HeaderMap::new();
+    security_headers(&[]).apply(&mut headers, false);
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(
+      headers[header::CONTENT_SECURITY_POLICY],
+      SecurityHeaders::CONTENT_SECURITY_POLICY
+    );
+    assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+    assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+  }
+
+  #[test]
+  fn strict_transport_security_is_only_sent_over_https() {
+    let mut headers = header::HeaderMap::new();
+    security_headers(&[]).apply(&mut headers, true);
+    assert_eq!(
+      headers[header::STRICT_TRANSPORT_SECURITY],
+      "max-age=31536000"
+    );
+  }
+
+  #[test]
+  fn overrides() {
+    let mut headers = header::HeaderMap::new();
+    security_headers(&[
+      "--content-security-policy=default-src 'self' https://cdn.example.com",
+      "--referrer-policy=",
+      "--strict-transport-security=max-age=60; includeSubDomains",
+    ])
+    .apply(&mut headers, true);
+    assert_eq!(
+      headers[header::CONTENT_SECURITY_POLICY],
+      "default-src 'self' https://cdn.example.com"
+    );
+    assert!(!headers.contains_key(header::REFERRER_POLICY));
+    assert_eq!(
+      headers[header::STRICT_TRANSPORT_SECURITY],
+      "max-age=60; includeSubDomains"
+    );
+  }
+
+  #[test]
+  fn invalid_header_values_are_rejected() {
+    assert_eq!(
+      parse_header_value("foo\nbar").unwrap_err(),
+      "invalid header value `foo\nbar`"
+    );
+  }
+}
@@ -1,12 +1,23 @@
+#[cfg(unix)]
+use crate::unix_socket::UnixSocket;
 use opuza_monero_client::{MoneroRpcClient, OpuzaRpcError};
 use std::time::Duration;
-use {crate::common::*, tower::make::Shared};
+use {
+  crate::{
+    acme::Acme, common::*, listeners, proxy_protocol::ProxyProtocolIncoming, sites::SitesConfig,
+  },
+  tokio_util::sync::CancellationToken,
+};
 
 pub(crate) struct Server {
-  http_request_handler: Option<hyper::Server<AddrIncoming, Shared<RequestHandler>>>,
+  http_servers: Vec<listeners::TcpServer<RequestHandler>>,
+  #[cfg(unix)]
+  http_socket_server: Option<hyper::Server<ProxyProtocolIncoming<UnixSocket>, RequestHandler>>,
   https_request_handler: Option<HttpsRequestHandler>,
-  https_redirect_server: Option<hyper::Server<AddrIncoming, Shared<HttpsRedirectService>>>,
+  https_redirect_servers: Vec<listeners::TcpServer<HttpsRedirectService>>,
   transaction_listener: Option<TransactionListener>,
+  shutdown_timeout: Duration,
+  stderr: Stderr,
   #[cfg(test)]
   directory: std::path::PathBuf,
 }
@@ -15,84 +26,116 @@ impl Server {
   pub(crate) async fn setup(environment: &mut Environment) -> Result<Self> {
     let arguments = environment.arguments()?;
 
-    let directory = environment.working_directory.join(&arguments.directory);
-    let _ = tokio::fs::read_dir(&directory)
-      .await
-      .context(error::FilesystemIo { path: &directory })?;
+    let sites = SitesConfig::load(environment, &arguments)?;
+
+    let directories = sites
+      .directories(&arguments)
+      .into_iter()
+      .map(|directory| environment.working_directory.join(directory))
+      .collect::<Vec<PathBuf>>();
+    for directory in &directories {
+      let _ = tokio::fs::read_dir(directory)
+        .await
+        .context(error::FilesystemIo { path: directory })?;
+    }
+
+    let rpc_client = Self::setup_rpc_client(environment, &arguments).await?;
 
-    let http_request_handler = match arguments.http_port {
+    // HTTP and HTTPS share a single request handler, so that bandwidth and
+    // invoice limits apply across both.
+    let acme = Acme::new(environment, &arguments, &sites)?;
+
+    let request_handler = RequestHandler::new(
+      environment,
+      &arguments,
+      &sites,
+      rpc_client.clone(),
+      acme.as_ref().and_then(Acme::http01_challenges),
+    )?;
+
+    let http_servers = match arguments.http_port {
       Some(http_port) => {
-        Some(Self::setup_http_request_handler(environment, &arguments, http_port).await?)
+        Self::setup_http_servers(environment, &arguments, http_port, request_handler.clone())?
       }
-      None => None,
+      None => Vec::new(),
     };
 
-    let rpc_client = Self::setup_rpc_client(environment, &arguments).await?;
-    let transaction_listener = Some(TransactionListener::new(rpc_client).await?);
-
-    let (https_request_handler, https_redirect_server) =
-      if let Some(https_port) = arguments.https_port {
-        let acme_cache_directory = arguments
-          .acme_cache_directory
-          .as_ref()
-          .expect("<https-port> requires <acme-cache-directory>");
-        let rpc_client = Self::setup_rpc_client(environment, &arguments).await?;
-        let https_request_handler = HttpsRequestHandler::new(
-          environment,
-          &arguments,
-          acme_cache_directory,
-          https_port,
-          rpc_client,
+    #[cfg(unix)]
+    let http_socket_server = arguments
+      .http_socket
+      .as_ref()
+      .map(|path| {
+        Ok::<_, Error>(
+          hyper::Server::builder(ProxyProtocolIncoming::new(
+            UnixSocket::bind(environment, &arguments, path, "HTTP")?,
+            request_handler.trusted_proxies().clone(),
+          ))
+          .http1_header_read_timeout(Duration::from_secs(arguments.header_read_timeout))
+          .serve(request_handler.clone()),
+        )
+      })
+      .transpose()?;
+
+    let transaction_listener = match rpc_client {
+      Some(rpc_client) => Some(
+        TransactionListener::new(
+          sites
+            .monero_accounts(&arguments)
+            .into_iter()
+            .map(|account| rpc_client.clone().with_account(account))
+            .collect(),
         )
-        .await?;
-        let https_redirect_server =
-          HttpsRedirectService::new_server(environment, &arguments, &https_request_handler)?;
-        (Some(https_request_handler), https_redirect_server)
-      } else {
-        (None, None)
-      };
+        .await?,
+      ),
+      None => None,
+    };
+
+    let (https_request_handler, https_redirect_servers) = if arguments.https() {
+      let https_request_handler =
+        HttpsRequestHandler::new(environment, &arguments, request_handler, acme).await?;
+      let https_redirect_servers =
+        HttpsRedirectService::new_servers(environment, &arguments, &https_request_handler)?;
+      (Some(https_request_handler), https_redirect_servers)
+    } else {
+      (None, Vec::new())
+    };
 
     Ok(Self {
-      http_request_handler,
+      http_servers,
+      #[cfg(unix)]
+      http_socket_server,
       https_request_handler,
-      https_redirect_server,
+      https_redirect_servers,
       transaction_listener,
+      shutdown_timeout: Duration::from_secs(arguments.shutdown_timeout),
+      stderr: environment.stderr.clone(),
       #[cfg(test)]
-      directory,
+      directory: directories[0].clone(),
     })
   }
 
-  async fn setup_http_request_handler(
+  fn setup_http_servers(
     environment: &mut Environment,
     arguments: &Arguments,
     http_port: u16,
-  ) -> Result<hyper::Server<AddrIncoming, Shared<RequestHandler>>> {
-    let rpc_client = Self::setup_rpc_client(environment, arguments).await?;
-
-    let socket_addr = (arguments.address.as_str(), http_port)
-      .to_socket_addrs()
-      .context(error::AddressResolutionIo {
-        input: &arguments.address,
-      })?
-      .next()
-      .ok_or_else(|| {
-        error::AddressResolutionNoAddresses {
-          input: arguments.address.clone(),
-        }
-        .build()
-      })?;
-
-    let request_handler = hyper::Server::bind(&socket_addr).serve(Shared::new(
-      RequestHandler::new(environment, &arguments.directory, rpc_client),
-    ));
-
-    writeln!(
-      environment.stderr,
-      "Listening for HTTP connections on `{}`",
-      request_handler.local_addr()
+    request_handler: RequestHandler,
+  ) -> Result<Vec<listeners::TcpServer<RequestHandler>>> {
+    Ok(
+      listeners::incoming(environment, arguments, http_port, "HTTP")?
+        .into_iter()
+        .map(|(local_addr, incoming)| {
+          (
+            local_addr,
+            hyper::Server::builder(ProxyProtocolIncoming::new(
+              incoming,
+              request_handler.trusted_proxies().clone(),
+            ))
+            .http1_header_read_timeout(Duration::from_secs(arguments.header_read_timeout))
+            .serve(request_handler.clone()),
+          )
+        })
+        .collect(),
     )
-    .context(error::StderrWrite)?;
-    Ok(request_handler)
   }
 
   async fn setup_rpc_client(
@@ -130,14 +173,80 @@ impl Server {
     Ok(client)
   }
 
-  pub(crate) async fn run(self) -> Result<()> {
+  /// Serve until `shutdown_signal` resolves, then stop accepting
+  /// connections, give downloads in progress up to `shutdown_timeout` to
+  /// finish, and let the transaction listener finish its current scan.
+  pub(crate) async fn run(self, shutdown_signal: impl Future<Output = Result<()>>) -> Result<()> {
+    let Self {
+      http_servers,
+      #[cfg(unix)]
+      http_socket_server,
+      https_request_handler,
+      https_redirect_servers,
+      transaction_listener,
+      shutdown_timeout,
+      mut stderr,
+      ..
+    } = self;
+
+    let shutdown = CancellationToken::new();
+
+    let mut http_servers = http_servers
+      .into_iter()
+      .map(|(_, server)| {
+        server
+          .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+          .boxed()
+      })
+      .collect::<Vec<BoxFuture<hyper::Result<()>>>>();
+
+    #[cfg(unix)]
+    http_servers.extend(http_socket_server.map(|server| {
+      server
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .boxed()
+    }));
+
+    let servers = async {
+      futures::try_join!(
+        futures::future::try_join_all(http_servers).map(|result| result.context(error::ServerRun)),
+        OptionFuture::from(https_request_handler.map(|x| x.run(shutdown.clone()))).map(Ok),
+        futures::future::try_join_all(
+          https_redirect_servers
+            .into_iter()
+            .map(|(_, server)| server.with_graceful_shutdown(shutdown.clone().cancelled_owned()))
+        )
+        .map(|result| result.context(error::ServerRun)),
+      )
+      .map(|_| ())
+    };
+
+    let deadline = async {
+      shutdown_signal.await?;
+      writeln!(
+        stderr,
+        "Shutting down, waiting up to {} seconds for downloads to finish",
+        shutdown_timeout.as_secs(),
+      )
+      .context(error::StderrWrite)?;
+      shutdown.cancel();
+      tokio::time::sleep(shutdown_timeout).await;
+      writeln!(
+        stderr,
+        "Shutdown timeout elapsed, closing remaining connections"
+      )
+      .context(error::StderrWrite)?;
+      Ok(())
+    };
+
     futures::try_join!(
-      OptionFuture::from(self.http_request_handler)
-        .map(|option| option.unwrap_or(Ok(())).context(error::ServerRun)),
-      OptionFuture::from(self.https_request_handler.map(|x| x.run())).map(Ok),
-      OptionFuture::from(self.https_redirect_server)
-        .map(|option| option.unwrap_or(Ok(())).context(error::ServerRun)),
-      OptionFuture::from(self.transaction_listener.map(|x| x.run())).map(Ok),
+      async {
+        tokio::select! {
+          result = servers => result,
+          result = deadline => result,
+        }
+      },
+      OptionFuture::from(transaction_listener.map(|x| x.run(shutdown.clone()))).map(Ok),
     )?;
 
     Ok(())
@@ -147,12 +256,7 @@ impl Server {
   pub(crate) fn test_context(&self) -> TestContext {
     let http_url = reqwest::Url::parse(&format!(
       "http://localhost:{}",
-      self
-        .http_request_handler
-        .as_ref()
-        .unwrap()
-        .local_addr()
-        .port()
+      self.http_servers[0].0.port()
     ))
     .unwrap();
     TestContext {
@@ -162,7 +266,7 @@ impl Server {
       https_files_url: self
         .https_request_handler
         .as_ref()
-        .map(|handler| handler.https_port())
+        .and_then(|handler| handler.https_port())
         .map(|https_port| {
           let mut url = http_url.join("files/").unwrap();
           url.set_scheme("https").unwrap();
@@ -170,34 +274,40 @@ impl Server {
           url
         }),
       https_redirect_port: self
-        .https_redirect_server
-        .as_ref()
-        .map(|server| server.local_addr().port()),
+        .https_redirect_servers
+        .first()
+        .map(|(local_addr, _)| local_addr.port()),
       files_directory: self.directory.to_owned(),
     }
   }
 }
 
+/// Scans for payments to the wallet accounts of `rpc_clients`, one client
+/// per account.
 pub struct TransactionListener {
-  rpc_client: Option<MoneroRpcClient>,
+  rpc_clients: Vec<MoneroRpcClient>,
 }
 
 impl TransactionListener {
-  pub(crate) async fn new(
-    rpc_client: Option<opuza_monero_client::MoneroRpcClient>,
-  ) -> Result<TransactionListener> {
-    Ok(Self { rpc_client })
+  pub(crate) async fn new(rpc_clients: Vec<MoneroRpcClient>) -> Result<TransactionListener> {
+    Ok(Self { rpc_clients })
   }
 
-  pub async fn run(self) {
+  /// Scan for payments until `shutdown` is cancelled. A scan in progress is
+  /// never interrupted, so payments are always recorded completely.
+  pub async fn run(self, shutdown: CancellationToken) {
     loop {
       println!("Looking for new transactions..");
-      tokio::time::sleep(Duration::from_secs(2)).await;
-      let ping_result = self.rpc_client.as_ref().unwrap().ping().await;
+      if Self::sleep(Duration::from_secs(2), &shutdown).await {
+        return;
+      }
+      let ping_result = self.rpc_clients[0].ping().await;
 
       if ping_result.is_err() {
         println!("Could not connect to monero-wallet-rpc server, retrying in 10 seconds..");
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        if Self::sleep(Duration::from_secs(10), &shutdown).await {
+          return;
+        }
         continue;
       }
 
@@ -205,13 +315,25 @@ impl TransactionListener {
 
       if scan_result.is_err() {
         println!("Error during transaction scanning, retrying in 10 seconds..");
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        if Self::sleep(Duration::from_secs(10), &shutdown).await {
+          return;
+        }
       }
     }
   }
 
+  /// Sleep for `duration`, returning early with `true` on shutdown.
+  async fn sleep(duration: Duration, shutdown: &CancellationToken) -> bool {
+    tokio::select! {
+      () = tokio::time::sleep(duration) => shutdown.is_cancelled(),
+      () = shutdown.cancelled() => true,
+    }
+  }
+
   pub async fn scan_transactions(&self) -> std::result::Result<(), OpuzaRpcError> {
-    self.rpc_client.as_ref().unwrap().update_payments().await?;
+    for rpc_client in &self.rpc_clients {
+      rpc_client.update_payments().await?;
+    }
     Ok(())
   }
 }
@@ -276,7 +398,7 @@ mod tests {
       .unwrap()
       .block_on(async {
         let server = Server::setup(&mut environment).await.unwrap();
-        let ip = server.http_request_handler.unwrap().local_addr().ip();
+        let ip = server.http_servers[0].0.ip();
         assert!(
           ip == IpAddr::from([127, 0, 0, 1])
             || ip == IpAddr::from([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
@@ -0,0 +1,29 @@
+use crate::common::*;
+
+/// Install handlers for SIGINT and SIGTERM, and return a future that resolves
+/// once either is received. Handlers are installed right away, so signals
+/// arriving while the server is still being set up aren't lost.
+pub(crate) fn shutdown_signal() -> Result<impl Future<Output = Result<()>>> {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt()).context(error::SignalHandlerInstall)?;
+    let mut terminate = signal(SignalKind::terminate()).context(error::SignalHandlerInstall)?;
+
+    Ok(async move {
+      tokio::select! {
+        _ = interrupt.recv() => {}
+        _ = terminate.recv() => {}
+      }
+      Ok(())
+    })
+  }
+
+  #[cfg(not(unix))]
+  Ok(async {
+    tokio::signal::ctrl_c()
+      .await
+      .context(error::SignalHandlerInstall)
+  })
+}
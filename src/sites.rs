@@ -0,0 +1,317 @@
+use {
+  crate::{
+    bandwidth_limiter::BandwidthLimiter,
+    common::*,
+    files::Files,
+    invoice_limiter::{InvoiceLimiter, InvoiceLimits},
+    theme::Theme,
+    thumbnails::Thumbnails,
+  },
+  opuza_monero_client::MoneroRpcClient,
+  std::collections::{BTreeSet, HashMap},
+};
+
+/// The `--sites` file, which maps the hosts that requests are made to onto
+/// the directories served for them.
+#[derive(PartialEq, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SitesConfig {
+  pub(crate) sites: Vec<SiteConfig>,
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct SiteConfig {
+  pub(crate) hosts: Vec<String>,
+  pub(crate) directory: PathBuf,
+  #[serde(default)]
+  pub(crate) theme_directory: Option<PathBuf>,
+  #[serde(default)]
+  pub(crate) monero_account: u32,
+}
+
+impl SitesConfig {
+  /// Load the `--sites` file, if any. Hosts are lowercased, and relative
+  /// paths are resolved against the directory the file is in.
+  pub(crate) fn load(environment: &Environment, arguments: &Arguments) -> Result<Self> {
+    let path = match &arguments.sites {
+      Some(path) => path,
+      None => return Ok(Self::default()),
+    };
+
+    let full_path = environment.working_directory.join(path);
+    let yaml = fs::read_to_string(&full_path).context(error::FilesystemIo { path: &full_path })?;
+    let mut config =
+      serde_yaml::from_str::<Self>(&yaml).context(error::ConfigDeserialize { path: &full_path })?;
+
+    if config.sites.is_empty() {
+      return Err(error::SitesMissing { path: full_path }.build());
+    }
+
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut hosts = HashSet::new();
+    for site in &mut config.sites {
+      if site.hosts.is_empty() {
+        return Err(
+          error::SitesHostsMissing {
+            path: &full_path,
+            directory: &site.directory,
+          }
+          .build(),
+        );
+      }
+      for host in &mut site.hosts {
+        *host = host.to_lowercase();
+        if !hosts.insert(host.clone()) {
+          return Err(
+            error::SitesHostDuplicate {
+              path: &full_path,
+              host: host.as_str(),
+            }
+            .build(),
+          );
+        }
+      }
+      site.directory = parent.join(&site.directory);
+      site.theme_directory = site
+        .theme_directory
+        .as_ref()
+        .map(|directory| parent.join(directory));
+    }
+
+    Ok(config)
+  }
+
+  /// The directories that are served, `--directory` first.
+  pub(crate) fn directories<'a>(&'a self, arguments: &'a Arguments) -> Vec<&'a Path> {
+    arguments
+      .directory
+      .as_deref()
+      .into_iter()
+      .chain(self.sites.iter().map(|site| site.directory.as_path()))
+      .collect()
+  }
+
+  /// The wallet accounts that invoices are created in.
+  pub(crate) fn monero_accounts(&self, arguments: &Arguments) -> BTreeSet<u32> {
+    arguments
+      .directory
+      .iter()
+      .map(|_| 0)
+      .chain(self.sites.iter().map(|site| site.monero_account))
+      .collect()
+  }
+}
+
+/// What is served for a host: its files, and the theme pages are rendered
+/// with.
+#[derive(Clone, Debug)]
+pub(crate) struct Site {
+  pub(crate) files: Files,
+  pub(crate) theme: Theme,
+}
+
+/// The sites served, looked up by the host that a request is made to. Hosts
+/// without a site of their own get `--directory`, if given.
+///
+/// Bandwidth and invoice limits are shared by all sites, so that clients
+/// can't get around them by using another host.
+#[derive(Clone, Debug)]
+pub(crate) struct Sites {
+  inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+  default: Option<Site>,
+  hosts: HashMap<String, Site>,
+}
+
+impl Sites {
+  pub(crate) fn new(
+    environment: &Environment,
+    arguments: &Arguments,
+    config: &SitesConfig,
+    rpc_client: Option<MoneroRpcClient>,
+    theme: &Theme,
+  ) -> Result<Self> {
+    let bandwidth_limiter = BandwidthLimiter::default();
+    let invoice_limiter = InvoiceLimiter::new(InvoiceLimits::new(arguments));
+    let thumbnail_cache_directory = arguments
+      .thumbnail_cache_directory
+      .as_ref()
+      .map(|directory| environment.working_directory.join(directory));
+
+    let site = |directory: &Path, theme: Theme, monero_account: u32, memo_prefix: String| {
+      let base_directory = InputPath::new(environment, directory);
+      let thumbnails = Thumbnails::new(thumbnail_cache_directory.clone(), base_directory.as_ref())?;
+      Ok::<_, Error>(Site {
+        files: Files::new(
+          base_directory,
+          rpc_client
+            .clone()
+            .map(|rpc_client| rpc_client.with_account(monero_account)),
+          invoice_limiter.clone(),
+          bandwidth_limiter.clone(),
+          thumbnails,
+          theme.clone(),
+          memo_prefix,
+        ),
+        theme,
+      })
+    };
+
+    let default = arguments
+      .directory
+      .as_ref()
+      .map(|directory| site(directory, theme.clone(), 0, String::new()))
+      .transpose()?;
+
+    let mut hosts = HashMap::new();
+    for config in &config.sites {
+      let theme = match &config.theme_directory {
+        Some(directory) => Theme::load(&environment.working_directory.join(directory))?,
+        None => theme.clone(),
+      };
+      let site = site(
+        &config.directory,
+        theme,
+        config.monero_account,
+        format!("{}/", config.hosts[0]),
+      )?;
+      for host in &config.hosts {
+        hosts.insert(host.clone(), site.clone());
+      }
+    }
+
+    Ok(Self {
+      inner: Arc::new(Inner { default, hosts }),
+    })
+  }
+
+  /// The site for requests made to `authority`, e.g. `music.example:8080`.
+  pub(crate) fn site(&self, authority: Option<&str>) -> Result<&Site> {
+    let host = authority
+      .and_then(|authority| authority.parse::<Authority>().ok())
+      .map(|authority| authority.host().trim_end_matches('.').to_lowercase());
+
+    host
+      .as_ref()
+      .and_then(|host| self.inner.hosts.get(host))
+      .or(self.inner.default.as_ref())
+      .ok_or_else(|| {
+        error::SiteUnknown {
+          host: host.unwrap_or_default(),
+        }
+        .build()
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::test_utils::assert_contains};
+
+  fn load(yaml: &str) -> Result<SitesConfig> {
+    let environment = Environment::test();
+    let config = environment.working_directory.join("config");
+    fs::create_dir(&config).unwrap();
+    fs::write(config.join("sites.yaml"), yaml).unwrap();
+    SitesConfig::load(
+      &environment,
+      &Arguments::try_parse_from(["opuza", "--http-port=0", "--sites=config/sites.yaml"]).unwrap(),
+    )
+  }
+
+  #[test]
+  fn relative_paths_are_resolved_against_the_sites_file() {
+    assert_eq!(
+      load(
+        "
+sites:
+  - hosts: [Music.Example]
+    directory: music
+    theme-directory: /themes/music
+    monero-account: 1
+",
+      )
+      .unwrap(),
+      SitesConfig {
+        sites: vec![SiteConfig {
+          hosts: vec!["music.example".into()],
+          directory: "config/music".into(),
+          theme_directory: Some("/themes/music".into()),
+          monero_account: 1,
+        }],
+      }
+    );
+  }
+
+  #[test]
+  fn sites_are_required() {
+    assert_contains(&load("sites: []").unwrap_err().to_string(), "No sites in");
+  }
+
+  #[test]
+  fn hosts_are_required() {
+    assert_contains(
+      &load("sites: [{hosts: [], directory: music}]")
+        .unwrap_err()
+        .to_string(),
+      "Site for `music` in",
+    );
+  }
+
+  #[test]
+  fn hosts_must_be_unique() {
+    assert_contains(
+      &load(
+        "
+sites:
+  - {hosts: [music.example], directory: music}
+  - {hosts: [MUSIC.example], directory: books}
+",
+      )
+      .unwrap_err()
+      .to_string(),
+      "Host `music.example` appears more than once in",
+    );
+  }
+
+  #[test]
+  fn unknown_fields_are_rejected() {
+    assert!(matches!(
+      load("sites: [{hosts: [a], directory: a, paid: true}]"),
+      Err(Error::ConfigDeserialize { .. })
+    ));
+  }
+
+  #[test]
+  fn monero_accounts() {
+    let yaml = "
+sites:
+  - {hosts: [a], directory: a, monero-account: 2}
+  - {hosts: [b], directory: b}
+  - {hosts: [c], directory: c, monero-account: 2}
+";
+    let arguments = Arguments::try_parse_from(["opuza", "--http-port=0", "--sites=s"]).unwrap();
+    assert_eq!(
+      load(yaml).unwrap().monero_accounts(&arguments),
+      [0, 2].iter().copied().collect()
+    );
+
+    let yaml = "sites: [{hosts: [a], directory: a, monero-account: 2}]";
+    assert_eq!(
+      load(yaml).unwrap().monero_accounts(&arguments),
+      [2].iter().copied().collect()
+    );
+
+    let arguments =
+      Arguments::try_parse_from(["opuza", "--http-port=0", "--sites=s", "--directory=www"])
+        .unwrap();
+    assert_eq!(
+      load(yaml).unwrap().monero_accounts(&arguments),
+      [0, 2].iter().copied().collect()
+    );
+  }
+}
@@ -0,0 +1,251 @@
+use {
+  crate::{common::*, locale::Messages, vfs::DirEntry},
+  std::{cmp::Ordering, str::FromStr},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SortKey {
+  Name,
+  Size,
+  Mtime,
+  Price,
+}
+
+impl SortKey {
+  pub(crate) const ALL: &'static [Self] = &[Self::Name, Self::Size, Self::Mtime, Self::Price];
+
+  pub(crate) fn as_str(self) -> &'static str {
+    match self {
+      Self::Name => "name",
+      Self::Size => "size",
+      Self::Mtime => "mtime",
+      Self::Price => "price",
+    }
+  }
+
+  pub(crate) fn label(self, messages: &Messages) -> &'static str {
+    match self {
+      Self::Name => messages.name,
+      Self::Size => messages.size,
+      Self::Mtime => messages.modified,
+      Self::Price => messages.price,
+    }
+  }
+}
+
+impl FromStr for SortKey {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Self::ALL
+      .iter()
+      .copied()
+      .find(|key| key.as_str() == s)
+      .ok_or_else(|| error::SortKey { key: s.to_owned() }.build())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SortOrder {
+  Asc,
+  Desc,
+}
+
+impl SortOrder {
+  pub(crate) fn as_str(self) -> &'static str {
+    match self {
+      Self::Asc => "asc",
+      Self::Desc => "desc",
+    }
+  }
+
+  pub(crate) fn reverse(self) -> Self {
+    match self {
+      Self::Asc => Self::Desc,
+      Self::Desc => Self::Asc,
+    }
+  }
+}
+
+impl FromStr for SortOrder {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "asc" => Ok(Self::Asc),
+      "desc" => Ok(Self::Desc),
+      _ => Err(
+        error::SortOrder {
+          order: s.to_owned(),
+        }
+        .build(),
+      ),
+    }
+  }
+}
+
+/// The default sort order of a directory listing, from `.opuza.yaml`.
+#[derive(PartialEq, Debug, Default, Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SortConfig {
+  pub(crate) by: Option<SortKey>,
+  pub(crate) order: Option<SortOrder>,
+}
+
+impl SortConfig {
+  pub(crate) fn merge_parent(self, parent: Self) -> Self {
+    Self {
+      by: self.by.or(parent.by),
+      order: self.order.or(parent.order),
+    }
+  }
+}
+
+/// How to sort a directory listing. Directories always come first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Sort {
+  pub(crate) key: SortKey,
+  pub(crate) order: SortOrder,
+}
+
+impl Sort {
+  /// The sort order requested with the `sort` and `order` query
+  /// parameters, falling back to the directory's configured default.
+  pub(crate) fn new(request: &Request<Body>, config: SortConfig) -> Result<Self> {
+    let key = query_parameter(request, "sort")
+      .map(|key| key.parse())
+      .transpose()?;
+    let order = query_parameter(request, "order")
+      .map(|order| order.parse())
+      .transpose()?;
+    Ok(Self {
+      key: key.or(config.by).unwrap_or(SortKey::Name),
+      order: order.or(config.order).unwrap_or(SortOrder::Asc),
+    })
+  }
+
+  pub(crate) fn sort(self, entries: &mut [DirEntry]) {
+    entries.sort_by(|a, b| self.compare(a, b));
+  }
+
+  fn compare(self, a: &DirEntry, b: &DirEntry) -> Ordering {
+    let by_name = || {
+      natural_cmp(
+        &a.file_name.to_string_lossy(),
+        &b.file_name.to_string_lossy(),
+      )
+    };
+
+    let ordering = match self.key {
+      SortKey::Name => by_name(),
+      SortKey::Size => a.file_size.cmp(&b.file_size).then_with(by_name),
+      SortKey::Mtime => a.modified.cmp(&b.modified).then_with(by_name),
+      SortKey::Price => a.price.cmp(&b.price).then_with(by_name),
+    };
+
+    b.file_type
+      .is_dir()
+      .cmp(&a.file_type.is_dir())
+      .then(match self.order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+      })
+  }
+}
+
+/// Compare strings the way a human would, ignoring case and comparing runs
+/// of digits by their numeric value, so that `file2` sorts before `File10`.
+/// Strings that only differ in case or leading zeros fall back to a bytewise
+/// comparison, so the order is total.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+  let mut a_chars = a.chars().peekable();
+  let mut b_chars = b.chars().peekable();
+
+  loop {
+    match (a_chars.peek().copied(), b_chars.peek().copied()) {
+      (None, None) => return a.cmp(b),
+      (None, Some(_)) => return Ordering::Less,
+      (Some(_), None) => return Ordering::Greater,
+      (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+        let take_digits = |chars: &mut std::iter::Peekable<str::Chars>| {
+          let mut digits = String::new();
+          while let Some(c) = chars.next_if(char::is_ascii_digit) {
+            digits.push(c);
+          }
+          digits.trim_start_matches('0').to_owned()
+        };
+        let x = take_digits(&mut a_chars);
+        let y = take_digits(&mut b_chars);
+        let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+        if ordering != Ordering::Equal {
+          return ordering;
+        }
+      }
+      (Some(x), Some(y)) => {
+        let ordering = x.to_lowercase().cmp(y.to_lowercase());
+        if ordering != Ordering::Equal {
+          return ordering;
+        }
+        a_chars.next();
+        b_chars.next();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sorted(names: &[&str]) -> Vec<String> {
+    let mut names = names
+      .iter()
+      .map(|name| name.to_string())
+      .collect::<Vec<_>>();
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names
+  }
+
+  #[test]
+  fn numbers_are_compared_by_value() {
+    assert_eq!(
+      sorted(&["file10", "file2", "file1"]),
+      &["file1", "file2", "file10"]
+    );
+  }
+
+  #[test]
+  fn case_is_ignored() {
+    assert_eq!(sorted(&["b", "A", "a", "C"]), &["A", "a", "b", "C"]);
+  }
+
+  #[test]
+  fn leading_zeros_are_ignored() {
+    assert_eq!(
+      sorted(&["track 10", "track 02", "track 1"]),
+      &["track 1", "track 02", "track 10"]
+    );
+    assert_eq!(natural_cmp("01", "1"), Ordering::Less);
+  }
+
+  #[test]
+  fn prefixes_sort_first() {
+    assert_eq!(sorted(&["foo.txt", "foo"]), &["foo", "foo.txt"]);
+  }
+
+  #[test]
+  fn parses_sort_keys_and_orders() {
+    assert_eq!("mtime".parse::<SortKey>().unwrap(), SortKey::Mtime);
+    assert_eq!("desc".parse::<SortOrder>().unwrap(), SortOrder::Desc);
+    assert_matches!(
+      "color".parse::<SortKey>(),
+      Err(Error::SortKey { key, .. }) if key == "color"
+    );
+    assert_matches!(
+      "up".parse::<SortOrder>(),
+      Err(Error::SortOrder { order, .. }) if order == "up"
+    );
+  }
+}
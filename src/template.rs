@@ -0,0 +1,137 @@
+use {crate::common::*, maud::PreEscaped};
+
+/// A template of operator-supplied HTML with `{{ name }}` placeholders.
+/// Templates can't run code or read files, and are checked against their
+/// allowed variables when loaded. Variable values are `Markup`, so text is
+/// escaped before it is ever substituted.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Template {
+  segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+  Text(String),
+  Variable(String),
+}
+
+impl Template {
+  pub(crate) fn load(path: &Path, variables: &[&str]) -> Result<Option<Self>> {
+    match fs::read_to_string(path) {
+      Ok(text) => Self::parse(&text, variables)
+        .map(Some)
+        .map_err(|message| error::ThemeTemplate { path, message }.build()),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(source) => Err(error::FilesystemIo { path }.into_error(source)),
+    }
+  }
+
+  fn parse(mut text: &str, variables: &[&str]) -> Result<Self, String> {
+    let mut segments = Vec::new();
+
+    while let Some(start) = text.find("{{") {
+      if start > 0 {
+        segments.push(Segment::Text(text[..start].to_owned()));
+      }
+      let rest = &text[start + 2..];
+      let end = rest
+        .find("}}")
+        .ok_or_else(|| "unterminated `{{`".to_owned())?;
+      let name = rest[..end].trim();
+      if !variables.contains(&name) {
+        return Err(format!(
+          "unknown variable `{}`, expected one of {}",
+          name,
+          variables
+            .iter()
+            .map(|variable| format!("`{}`", variable))
+            .collect::<Vec<String>>()
+            .join(", ")
+        ));
+      }
+      segments.push(Segment::Variable(name.to_owned()));
+      text = &rest[end + 2..];
+    }
+
+    if !text.is_empty() {
+      segments.push(Segment::Text(text.to_owned()));
+    }
+
+    Ok(Self { segments })
+  }
+
+  pub(crate) fn render(&self, values: &[(&str, &Markup)]) -> Markup {
+    let mut html = String::new();
+    for segment in &self.segments {
+      match segment {
+        Segment::Text(text) => html.push_str(text),
+        Segment::Variable(name) => {
+          if let Some((_, value)) = values.iter().find(|(key, _)| key == name) {
+            html.push_str(&value.0);
+          }
+        }
+      }
+    }
+    PreEscaped(html)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, maud::html};
+
+  #[test]
+  fn substitutes_variables() {
+    let template = Template::parse("<p>{{ title }} and {{body}}</p>", &["title", "body"]).unwrap();
+    assert_eq!(
+      template
+        .render(&[
+          ("title", &html! { ("<script>") }),
+          ("body", &html! { b { "bold" } }),
+        ])
+        .into_string(),
+      "<p>&lt;script&gt; and <b>bold</b></p>"
+    );
+  }
+
+  #[test]
+  fn text_without_variables() {
+    assert_eq!(
+      Template::parse("plain", &[])
+        .unwrap()
+        .render(&[])
+        .into_string(),
+      "plain"
+    );
+  }
+
+  #[test]
+  fn rejects_unknown_variables() {
+    assert_eq!(
+      Template::parse("{{ secret }}", &["title", "body"]).unwrap_err(),
+      "unknown variable `secret`, expected one of `title`, `body`"
+    );
+  }
+
+  #[test]
+  fn rejects_unterminated_placeholders() {
+    assert_eq!(
+      Template::parse("{{ title", &["title"]).unwrap_err(),
+      "unterminated `{{`"
+    );
+  }
+
+  #[test]
+  fn loads_missing_templates_as_none() {
+    let temp_dir = TempDir::new().unwrap();
+    assert_eq!(
+      Template::load(&temp_dir.path().join("page.html"), &[]).unwrap(),
+      None
+    );
+    fs::write(temp_dir.path().join("page.html"), "{{ foo }}").unwrap();
+    assert_matches!(
+      Template::load(&temp_dir.path().join("page.html"), &[]),
+      Err(Error::ThemeTemplate { .. })
+    );
+  }
+}
@@ -30,14 +30,32 @@ pub(crate) fn assert_contains(haystack: &str, needle: &str) {
   );
 }
 
+/// The ACME directory that the certificate cached by
+/// `set_up_test_certificate` is filed under.
+pub(crate) const TEST_ACME_DIRECTORY_URL: &str = rustls_acme::acme::LETS_ENCRYPT_STAGING_DIRECTORY;
+
 pub(crate) fn set_up_test_certificate() -> (TempDir, Certificate) {
-  use rcgen::{
-    BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair, SanType,
-    PKCS_ECDSA_P256_SHA256,
+  let tempdir = TempDir::new().unwrap();
+  let root_certificate = cache_test_certificate(tempdir.path(), "localhost");
+  (tempdir, root_certificate)
+}
+
+/// Cache a certificate for `domain` in the ACME cache directory `directory`,
+/// signed by a root certificate of its own, which is returned.
+pub(crate) fn cache_test_certificate(directory: &Path, domain: &str) -> Certificate {
+  use {
+    rcgen::{
+      BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair,
+      PKCS_ECDSA_P256_SHA256,
+    },
+    rustls_acme::{caches::DirCache, CertCache},
   };
 
   let root_certificate = {
     let mut params: CertificateParams = Default::default();
+    params
+      .distinguished_name
+      .push(DnType::CommonName, "opuza test root");
     params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap());
     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
     Certificate::from_params(params).unwrap()
@@ -46,17 +64,12 @@ pub(crate) fn set_up_test_certificate() -> (TempDir, Certificate) {
   let certificate_keys = KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap();
   let certificate_keys_pem = certificate_keys.serialize_pem();
   let certificate = {
-    let mut params = CertificateParams::from_ca_cert_pem(
-      &root_certificate.serialize_pem().unwrap(),
-      certificate_keys,
-    )
-    .unwrap();
-    params
-      .subject_alt_names
-      .push(SanType::DnsName("localhost".to_string()));
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.distinguished_name.push(DnType::CommonName, domain);
+    params.key_pair = Some(certificate_keys);
     Certificate::from_params(params).unwrap()
   };
-  let certificate_file = vec![
+  let certificate_file = [
     certificate_keys_pem,
     certificate
       .serialize_pem_with_signer(&root_certificate)
@@ -64,18 +77,71 @@ pub(crate) fn set_up_test_certificate() -> (TempDir, Certificate) {
     root_certificate.serialize_pem().unwrap(),
   ]
   .join("\r\n");
-  let tempdir = TempDir::new().unwrap();
+  futures::executor::block_on(DirCache::new(directory).store_cert(
+    &[domain.to_string()],
+    TEST_ACME_DIRECTORY_URL,
+    certificate_file.as_bytes(),
+  ))
+  .unwrap();
+  reqwest::Certificate::from_pem(root_certificate.serialize_pem().unwrap().as_bytes()).unwrap()
+}
+
+/// Write a certificate chain for `localhost`, signed by a root via an
+/// intermediate certificate, to `cert.pem`, its RSA or ECDSA private key to
+/// `key.pem`, and the root certificate to `root.pem`. Returns the root
+/// certificate.
+pub(crate) fn write_test_certificate_files(directory: &Path, rsa: bool) -> Certificate {
+  use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair,
+    PKCS_ECDSA_P256_SHA256, PKCS_RSA_SHA256,
+  };
+
+  let ca = |name: &str, signer: Option<&Certificate>| {
+    let mut params: CertificateParams = Default::default();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap());
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let certificate = Certificate::from_params(params).unwrap();
+    let pem = match signer {
+      Some(signer) => certificate.serialize_pem_with_signer(signer).unwrap(),
+      None => certificate.serialize_pem().unwrap(),
+    };
+    (certificate, pem)
+  };
+
+  let (root, root_pem) = ca("Opuza Test Root", None);
+  let (intermediate, intermediate_pem) = ca("Opuza Test Intermediate", Some(&root));
+
+  let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+  params
+    .distinguished_name
+    .push(DnType::CommonName, "localhost");
+  if rsa {
+    let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+    let pem = openssl::pkey::PKey::from_rsa(rsa)
+      .unwrap()
+      .private_key_to_pem_pkcs8()
+      .unwrap();
+    params.alg = &PKCS_RSA_SHA256;
+    params.key_pair = Some(KeyPair::from_pem(str::from_utf8(&pem).unwrap()).unwrap());
+  } else {
+    params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap());
+  }
+  let leaf = Certificate::from_params(params).unwrap();
+
   fs::write(
-    tempdir
-      .path()
-      .join("cached_cert_83kei_h4oopqh8sXFFlhGeQJIS_pkJJv-y5XDpnLtyw"),
-    certificate_file,
+    directory.join("cert.pem"),
+    [
+      leaf.serialize_pem_with_signer(&intermediate).unwrap(),
+      intermediate_pem,
+    ]
+    .join(""),
   )
   .unwrap();
-  (
-    tempdir,
-    reqwest::Certificate::from_pem(root_certificate.serialize_pem().unwrap().as_bytes()).unwrap(),
-  )
+  fs::write(directory.join("key.pem"), leaf.serialize_private_key_pem()).unwrap();
+  fs::write(directory.join("root.pem"), &root_pem).unwrap();
+
+  reqwest::Certificate::from_pem(root_pem.as_bytes()).unwrap()
 }
 
 pub(crate) async fn https_client(context: &TestContext, root_certificate: Certificate) -> Client {
@@ -152,7 +218,7 @@ where
     .block_on(async {
       let server = Server::setup(environment).await.unwrap();
       let test_context = server.test_context();
-      let server_join_handle = tokio::spawn(async { server.run().await.unwrap() });
+      let server_join_handle = tokio::spawn(async { server.run(future::pending()).await.unwrap() });
       let test_result = tokio::task::LocalSet::new()
         .run_until(async move { tokio::task::spawn_local(test_function(test_context)).await })
         .await;
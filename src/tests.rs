@@ -2,11 +2,16 @@ use {
   crate::{
     common::*,
     environment::Environment,
+    fake_acme::FakeAcme,
+    fake_wallet::FakeWallet,
+    server::TestContext,
     test_utils::{
-      https_client, set_up_test_certificate, test_with_arguments, test_with_environment,
+      cache_test_certificate, https_client, set_up_test_certificate, test_with_arguments,
+      test_with_environment, write_test_certificate_files, TEST_ACME_DIRECTORY_URL,
     },
   },
   pretty_assertions::assert_eq,
+  tokio::io::{AsyncReadExt, AsyncWriteExt},
 };
 
 #[cfg(feature = "slow-tests")]
@@ -34,6 +39,8 @@ fn serves_https_requests_with_cert_from_cache_directory() {
       certificate_cache.path().to_str().unwrap(),
       "--https-port=0",
       "--acme-domain=localhost",
+      "--acme-directory-url",
+      TEST_ACME_DIRECTORY_URL,
     ],
     |context| async move {
       context.write("file", "encrypted content");
@@ -49,6 +56,400 @@ fn serves_https_requests_with_cert_from_cache_directory() {
   );
 }
 
+#[test]
+fn http01_challenges_leave_https_and_http_unaffected() {
+  let (certificate_cache, root_certificate) = set_up_test_certificate();
+
+  test_with_arguments(
+    &[
+      "--acme-cache-directory",
+      certificate_cache.path().to_str().unwrap(),
+      "--https-port=0",
+      "--acme-domain=localhost",
+      "--acme-directory-url",
+      TEST_ACME_DIRECTORY_URL,
+      "--acme-challenge=http-01",
+    ],
+    |context| async move {
+      context.write("file", "encrypted content");
+      let client = https_client(&context, root_certificate).await;
+      let response = client
+        .get(context.https_files_url().join("file").unwrap())
+        .send()
+        .await
+        .unwrap();
+      assert_eq!(response.text().await.unwrap(), "encrypted content");
+
+      let response = reqwest::get(
+        context
+          .files_url()
+          .join("/.well-known/acme-challenge/unknown-token")
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+      assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    },
+  );
+}
+
+#[test]
+fn http01_challenges_are_answered_on_http_and_redirect_ports_for_any_host() {
+  let acme = FakeAcme::new();
+  let cache_directory = TempDir::new().unwrap();
+  let mut environment = sites_environment(&[
+    "--https-port=0",
+    "--https-redirect-port=0",
+    "--acme-cache-directory",
+    cache_directory.path().to_str().unwrap(),
+    "--acme-domain=localhost",
+    "--acme-directory-url",
+    acme.directory_url(),
+    "--acme-root-certificate",
+    acme.root_certificate().to_str().unwrap(),
+    "--acme-challenge=http-01",
+  ]);
+  environment
+    .arguments
+    .retain(|argument| argument != "--directory=www");
+
+  test_with_environment(&mut environment, |context| async move {
+    let key_authorization = acme.key_authorization("token-0").await;
+    assert_eq!(
+      reqwest::get(context.files_url().clone())
+        .await
+        .unwrap()
+        .status(),
+      StatusCode::MISDIRECTED_REQUEST
+    );
+
+    let mut redirect_url = context.files_url().clone();
+    redirect_url
+      .set_port(Some(context.https_redirect_port()))
+      .unwrap();
+
+    for url in [context.files_url(), &redirect_url] {
+      let response = reqwest::get(url.join("/.well-known/acme-challenge/token-0").unwrap())
+        .await
+        .unwrap();
+      assert_eq!(response.status(), StatusCode::OK);
+      assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+      );
+      assert_eq!(response.text().await.unwrap(), key_authorization);
+    }
+  });
+}
+
+fn serves_https_with_certificate_files(rsa: bool) {
+  let tempdir = TempDir::new().unwrap();
+  let root_certificate = write_test_certificate_files(tempdir.path(), rsa);
+
+  test_with_arguments(
+    &[
+      "--https-port=0",
+      "--tls-certificate",
+      tempdir.path().join("cert.pem").to_str().unwrap(),
+      "--tls-key",
+      tempdir.path().join("key.pem").to_str().unwrap(),
+    ],
+    |context| async move {
+      context.write("file", "encrypted content");
+      https_client(&context, root_certificate.clone()).await;
+      // Unlike `https_client`, verify the certificate chain.
+      let client = reqwest::Client::builder()
+        .add_root_certificate(root_certificate)
+        .build()
+        .unwrap();
+      let response = client
+        .get(context.https_files_url().join("file").unwrap())
+        .send()
+        .await
+        .unwrap();
+      assert_eq!(response.text().await.unwrap(), "encrypted content");
+    },
+  );
+}
+
+#[test]
+fn serves_https_with_ecdsa_certificate_files() {
+  serves_https_with_certificate_files(false);
+}
+
+#[test]
+fn serves_https_with_rsa_certificate_files() {
+  serves_https_with_certificate_files(true);
+}
+
+#[test]
+fn replaced_certificate_files_are_reloaded() {
+  let tempdir = TempDir::new().unwrap();
+  let root_certificate = write_test_certificate_files(tempdir.path(), false);
+
+  test_with_arguments(
+    &[
+      "--https-port=0",
+      "--tls-certificate",
+      tempdir.path().join("cert.pem").to_str().unwrap(),
+      "--tls-key",
+      tempdir.path().join("key.pem").to_str().unwrap(),
+    ],
+    |context| async move {
+      context.write("file", "encrypted content");
+      https_client(&context, root_certificate.clone()).await;
+      let file = context.https_files_url().join("file").unwrap();
+      // A new client for every request, so that each makes a new handshake.
+      let get = |root_certificate: reqwest::Certificate| {
+        reqwest::Client::builder()
+          .add_root_certificate(root_certificate)
+          .build()
+          .unwrap()
+          .get(file.clone())
+          .send()
+      };
+
+      fs::write(tempdir.path().join("cert.pem"), "invalid").unwrap();
+      tokio::time::sleep(Duration::from_secs(1)).await;
+      assert_eq!(
+        get(root_certificate.clone())
+          .await
+          .unwrap()
+          .text()
+          .await
+          .unwrap(),
+        "encrypted content"
+      );
+
+      let replacement = TempDir::new().unwrap();
+      let new_root_certificate = write_test_certificate_files(replacement.path(), false);
+      for file in ["cert.pem", "key.pem"] {
+        fs::rename(replacement.path().join(file), tempdir.path().join(file)).unwrap();
+      }
+
+      let mut reloaded = false;
+      for _ in 0..50 {
+        if get(new_root_certificate.clone()).await.is_ok() {
+          reloaded = true;
+          break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+      }
+      assert!(reloaded, "certificate was not reloaded");
+      assert!(get(root_certificate).await.is_err());
+    },
+  );
+}
+
+fn test_with_certificate_files<Function, F>(arguments: &[&str], f: Function)
+where
+  Function: FnOnce(TestContext, reqwest::Certificate) -> F,
+  F: Future<Output = ()> + 'static,
+{
+  let tempdir = TempDir::new().unwrap();
+  let root_certificate = write_test_certificate_files(tempdir.path(), false);
+  let certificate = tempdir.path().join("cert.pem");
+  let key = tempdir.path().join("key.pem");
+  let mut arguments = arguments.to_vec();
+  arguments.extend([
+    "--https-port=0",
+    "--tls-certificate",
+    certificate.to_str().unwrap(),
+    "--tls-key",
+    key.to_str().unwrap(),
+  ]);
+  test_with_arguments(&arguments, |context| f(context, root_certificate));
+}
+
+fn https_address(context: &TestContext) -> String {
+  format!("localhost:{}", context.https_files_url().port().unwrap())
+}
+
+#[test]
+fn https_survives_clients_sending_garbage() {
+  test_with_certificate_files(&[], |context, root_certificate| async move {
+    context.write("file", "encrypted content");
+    for _ in 0..3 {
+      let mut stream = tokio::net::TcpStream::connect(https_address(&context))
+        .await
+        .unwrap();
+      stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+      let mut response = Vec::new();
+      stream.read_to_end(&mut response).await.ok();
+    }
+    let client = https_client(&context, root_certificate).await;
+    let response = client
+      .get(context.https_files_url().join("file").unwrap())
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(response.text().await.unwrap(), "encrypted content");
+  });
+}
+
+#[test]
+fn stalled_tls_handshakes_time_out() {
+  test_with_certificate_files(&["--tls-handshake-timeout=1"], |context, _| async move {
+    let mut stream = tokio::net::TcpStream::connect(https_address(&context))
+      .await
+      .unwrap();
+    let mut response = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+      .await
+      .expect("connection was not closed");
+    assert_eq!(read.unwrap(), 0);
+  });
+}
+
+#[test]
+fn https_connections_are_capped() {
+  test_with_certificate_files(
+    &["--https-max-connections=1"],
+    |context, root_certificate| async move {
+      context.write("file", "encrypted content");
+      let client = reqwest::Client::builder()
+        .add_root_certificate(root_certificate)
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+      let url = context.https_files_url().join("file").unwrap();
+
+      let idle = tokio::net::TcpStream::connect(https_address(&context))
+        .await
+        .unwrap();
+      assert!(
+        tokio::time::timeout(Duration::from_millis(500), client.get(url.clone()).send())
+          .await
+          .is_err()
+      );
+
+      drop(idle);
+      let response = tokio::time::timeout(Duration::from_secs(5), client.get(url).send())
+        .await
+        .unwrap()
+        .unwrap();
+      assert_eq!(response.text().await.unwrap(), "encrypted content");
+    },
+  );
+}
+
+#[test]
+#[cfg(unix)]
+fn https_over_unix_socket() {
+  use std::convert::TryFrom;
+  use tokio_rustls::{
+    rustls::{
+      pki_types::{pem::PemObject, CertificateDer, ServerName},
+      ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+  };
+
+  let tempdir = TempDir::new().unwrap();
+  write_test_certificate_files(tempdir.path(), false);
+  let socket = tempdir.path().join("https.sock");
+  let certificate = tempdir.path().join("cert.pem");
+  let key = tempdir.path().join("key.pem");
+  let directory = tempdir.path().to_owned();
+  test_with_arguments(
+    &[
+      "--https-socket",
+      socket.to_str().unwrap(),
+      "--tls-certificate",
+      certificate.to_str().unwrap(),
+      "--tls-key",
+      key.to_str().unwrap(),
+    ],
+    |context| async move {
+      context.write("file", "encrypted content");
+      let mut roots = RootCertStore::empty();
+      roots
+        .add(CertificateDer::from_pem_file(directory.join("root.pem")).unwrap())
+        .unwrap();
+      let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+          .with_root_certificates(roots)
+          .with_no_client_auth(),
+      ));
+      let stream = tokio::net::UnixStream::connect(directory.join("https.sock"))
+        .await
+        .unwrap();
+      let mut stream = connector
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+      stream
+        .write_all(b"GET /files/file HTTP/1.0\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).await.ok();
+      assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+      assert!(
+        response.ends_with("\r\n\r\nencrypted content"),
+        "{}",
+        response
+      );
+    },
+  );
+}
+
+fn https_protocol_test(http1_only: bool) -> reqwest::Version {
+  let (sender, receiver) = std::sync::mpsc::channel();
+  test_with_certificate_files(&[], |context, root_certificate| async move {
+    context.write("file", "encrypted content");
+    let mut builder = reqwest::Client::builder()
+      .use_rustls_tls()
+      .add_root_certificate(root_certificate);
+    if http1_only {
+      builder = builder.http1_only();
+    }
+    let response = builder
+      .build()
+      .unwrap()
+      .get(context.https_files_url().join("file").unwrap())
+      .send()
+      .await
+      .unwrap();
+    sender.send(response.version()).unwrap();
+    assert_eq!(response.text().await.unwrap(), "encrypted content");
+  });
+  receiver.recv().unwrap()
+}
+
+#[test]
+fn https_negotiates_http2() {
+  assert_eq!(https_protocol_test(false), reqwest::Version::HTTP_2);
+}
+
+#[test]
+fn https_falls_back_to_http1() {
+  assert_eq!(https_protocol_test(true), reqwest::Version::HTTP_11);
+}
+
+#[test]
+fn http2_requests_beyond_max_concurrent_streams_are_queued() {
+  test_with_certificate_files(
+    &["--http2-max-concurrent-streams=1"],
+    |context, root_certificate| async move {
+      context.write("file", "encrypted content");
+      let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(root_certificate)
+        .build()
+        .unwrap();
+      let url = context.https_files_url().join("file").unwrap();
+      let responses =
+        futures::future::join_all((0..4).map(|_| client.get(url.clone()).send())).await;
+      for response in responses {
+        let response = response.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "encrypted content");
+      }
+    },
+  );
+}
+
 #[test]
 fn redirects_requests_from_port_80_to_443() {
   let (certificate_cache, root_certificate) = set_up_test_certificate();
@@ -60,6 +461,8 @@ fn redirects_requests_from_port_80_to_443() {
       "--https-port=0",
       "--https-redirect-port=0",
       "--acme-domain=localhost",
+      "--acme-directory-url",
+      TEST_ACME_DIRECTORY_URL,
     ],
     |context| async move {
       context.write("file", "encrypted content");
@@ -79,6 +482,111 @@ fn redirects_requests_from_port_80_to_443() {
   );
 }
 
+#[test]
+fn https_redirects_follow_forwarded_host() {
+  test_with_certificate_files(
+    &["--https-redirect-port=0", "--trusted-proxy=127.0.0.1"],
+    |context, _| async move {
+      let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!(
+          "http://127.0.0.1:{}/files/file",
+          context.https_redirect_port()
+        ))
+        .header("x-forwarded-host", "example.com")
+        .send()
+        .await
+        .unwrap();
+      assert_eq!(
+        response.headers()[reqwest::header::LOCATION],
+        format!(
+          "https://example.com:{}/files/file",
+          context.https_files_url().port().unwrap()
+        )
+      );
+    },
+  );
+}
+
+#[test]
+fn responses_have_security_headers() {
+  test_with_arguments(&[], |context| async move {
+    context.write("file", "content");
+    for url in [
+      context.files_url().join("file").unwrap(),
+      context.files_url().join("missing").unwrap(),
+    ] {
+      let response = reqwest::get(url).await.unwrap();
+      let headers = response.headers();
+      assert_eq!(headers["x-content-type-options"], "nosniff");
+      assert_eq!(headers["referrer-policy"], "no-referrer");
+      let policy = headers["content-security-policy"].to_str().unwrap();
+      assert!(policy.contains("default-src 'self'"), "{}", policy);
+      assert!(policy.contains("frame-ancestors 'none'"), "{}", policy);
+      assert!(!headers.contains_key("strict-transport-security"));
+    }
+  });
+}
+
+#[test]
+fn security_headers_can_be_overridden() {
+  test_with_arguments(
+    &[
+      "--content-security-policy=default-src 'self' https://example.com",
+      "--referrer-policy=",
+    ],
+    |context| async move {
+      let response = reqwest::get(context.files_url().clone()).await.unwrap();
+      let headers = response.headers();
+      assert_eq!(
+        headers["content-security-policy"],
+        "default-src 'self' https://example.com"
+      );
+      assert!(!headers.contains_key("referrer-policy"));
+    },
+  );
+}
+
+#[test]
+fn strict_transport_security_is_sent_over_https() {
+  test_with_certificate_files(&[], |context, root_certificate| async move {
+    let client = https_client(&context, root_certificate).await;
+    let response = client
+      .get(context.https_files_url().clone())
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(
+      response.headers()["strict-transport-security"],
+      "max-age=31536000"
+    );
+
+    let response = reqwest::get(context.files_url().clone()).await.unwrap();
+    assert!(!response.headers().contains_key("strict-transport-security"));
+  });
+}
+
+#[test]
+fn strict_transport_security_is_sent_when_trusted_proxy_terminates_tls() {
+  test_with_arguments(
+    &["--trusted-proxy=127.0.0.1", "--trusted-proxy=::1"],
+    |context| async move {
+      let response = reqwest::Client::new()
+        .get(context.files_url().clone())
+        .header("x-forwarded-proto", "https")
+        .send()
+        .await
+        .unwrap();
+      assert_eq!(
+        response.headers()["strict-transport-security"],
+        "max-age=31536000"
+      );
+    },
+  );
+}
+
 fn symlink(contents: impl AsRef<Path>, link: impl AsRef<Path>) {
   #[cfg(unix)]
   std::os::unix::fs::symlink(contents, link).unwrap();
@@ -127,3 +635,705 @@ fn bugfix_symlink_with_relative_base_directory() {
     assert_eq!(link, "precious content");
   });
 }
+
+#[test]
+fn directories_can_be_downloaded_as_zip_archives() {
+  test_with_arguments(&[], |context| async move {
+    context.write("foo/a.txt", "a");
+    context.write("foo/bar/b.txt", "b");
+    context.write("foo/.hidden", "hidden");
+    context.write("foo/paid/.opuza.yaml", "{paid: true, base-price: 1 XMR}");
+    context.write("foo/paid/c.txt", "c");
+
+    let response = get(&context.files_url().join("foo/?archive=zip").unwrap()).await;
+    assert_eq!(
+      response.headers().get(header::CONTENT_TYPE).unwrap(),
+      "application/zip"
+    );
+    assert_eq!(
+      response.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+      "attachment; filename*=UTF-8''foo.zip"
+    );
+
+    let bytes = response.bytes().await.unwrap().to_vec();
+    let zip = async_zip::base::read::mem::ZipFileReader::new(bytes)
+      .await
+      .unwrap();
+    let mut files = Vec::new();
+    for (index, entry) in zip.file().entries().iter().enumerate() {
+      let mut contents = String::new();
+      zip
+        .reader_with_entry(index)
+        .await
+        .unwrap()
+        .read_to_string_checked(&mut contents)
+        .await
+        .unwrap();
+      files.push((entry.filename().as_str().unwrap().to_owned(), contents));
+    }
+    assert_eq!(
+      files,
+      [("a.txt", "a"), ("bar/b.txt", "b")]
+        .iter()
+        .map(|(name, contents)| (name.to_string(), contents.to_string()))
+        .collect::<Vec<(String, String)>>()
+    );
+  });
+}
+
+#[test]
+fn directories_can_be_downloaded_as_tar_gz_archives() {
+  use {async_compression::tokio::bufread::GzipDecoder, tokio::io::AsyncReadExt};
+
+  test_with_arguments(&[], |context| async move {
+    context.write("a.txt", "a");
+    context.write("bar/b.txt", "b");
+
+    let response = get(&context.files_url().join("?archive=tar.gz").unwrap()).await;
+    assert_eq!(
+      response.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+      "attachment; filename*=UTF-8''www.tar.gz"
+    );
+
+    let bytes = response.bytes().await.unwrap().to_vec();
+    let mut archive = tokio_tar::Archive::new(GzipDecoder::new(bytes.as_slice()));
+    let mut entries = archive.entries().unwrap();
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next().await {
+      let mut entry = entry.unwrap();
+      let mut contents = String::new();
+      entry.read_to_string(&mut contents).await.unwrap();
+      files.push((entry.path().unwrap().display().to_string(), contents));
+    }
+    assert_eq!(
+      files,
+      vec![
+        ("a.txt".to_owned(), "a".to_owned()),
+        ("bar/b.txt".to_owned(), "b".to_owned())
+      ]
+    );
+  });
+}
+
+#[test]
+#[cfg(unix)]
+fn archives_skip_escaping_symlinks_and_symlink_cycles() {
+  test_with_arguments(&[], |context| async move {
+    context.write("../outside", "outside");
+    context.write("dir/file", "file");
+    symlink(
+      "../../outside",
+      context.files_directory().join("dir/escaping"),
+    );
+    symlink("..", context.files_directory().join("dir/parent"));
+
+    let response = get(&context.files_url().join("?archive=zip").unwrap()).await;
+    let bytes = response.bytes().await.unwrap().to_vec();
+    let zip = async_zip::base::read::mem::ZipFileReader::new(bytes)
+      .await
+      .unwrap();
+    let names = zip
+      .file()
+      .entries()
+      .iter()
+      .map(|entry| entry.filename().as_str().unwrap().to_owned())
+      .collect::<Vec<String>>();
+    assert_eq!(names, vec!["dir/file".to_owned()]);
+  });
+}
+
+#[test]
+#[cfg(unix)]
+fn archives_skip_dangling_symlinks() {
+  test_with_arguments(&[], |context| async move {
+    context.write("sub/a.txt", "a");
+    symlink("missing", context.files_directory().join("dangling"));
+
+    let response = get(&context.files_url().join("?archive=zip").unwrap()).await;
+    let bytes = response.bytes().await.unwrap().to_vec();
+    let zip = async_zip::base::read::mem::ZipFileReader::new(bytes)
+      .await
+      .unwrap();
+    let names = zip
+      .file()
+      .entries()
+      .iter()
+      .map(|entry| entry.filename().as_str().unwrap().to_owned())
+      .collect::<Vec<String>>();
+    assert_eq!(names, vec!["sub/a.txt".to_owned()]);
+  });
+}
+
+#[test]
+fn unknown_archive_formats_are_rejected() {
+  test_with_arguments(&[], |context| async move {
+    let response = reqwest::get(context.files_url().join("?archive=rar").unwrap())
+      .await
+      .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+  });
+}
+
+#[test]
+fn free_downloads_are_throttled_by_bandwidth_limit() {
+  test_with_arguments(&[], |context| async move {
+    context.write(
+      ".opuza.yaml",
+      "bandwidth-limit: {free: {connection: 10 KiB}}",
+    );
+    context.write("foo", &"a".repeat(20 * 1024));
+    let start = std::time::Instant::now();
+    let body = text(&context.files_url().join("foo").unwrap()).await;
+    assert_eq!(body.len(), 20 * 1024);
+    let elapsed = start.elapsed();
+    assert!(
+      elapsed >= Duration::from_millis(900),
+      "download took {:?}",
+      elapsed
+    );
+  });
+}
+
+#[test]
+fn archive_downloads_are_throttled_by_bandwidth_limit() {
+  test_with_arguments(&[], |context| async move {
+    context.write(
+      "foo/.opuza.yaml",
+      "bandwidth-limit: {free: {connection: 10 KiB}}",
+    );
+    context.write("foo/a.txt", &"a".repeat(20 * 1024));
+    let start = std::time::Instant::now();
+    let bytes = get(&context.files_url().join("foo/?archive=zip").unwrap())
+      .await
+      .bytes()
+      .await
+      .unwrap();
+    assert!(bytes.len() > 20 * 1024);
+    let elapsed = start.elapsed();
+    assert!(
+      elapsed >= Duration::from_millis(900),
+      "download took {:?}",
+      elapsed
+    );
+  });
+}
+
+async fn invoice_redirect(url: &reqwest::Url) -> String {
+  let client = reqwest::Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .build()
+    .unwrap();
+  let response = client.get(url.clone()).send().await.unwrap();
+  assert_eq!(response.status(), reqwest::StatusCode::FOUND);
+  let location = response.headers()[reqwest::header::LOCATION]
+    .to_str()
+    .unwrap()
+    .to_owned();
+  location
+    .split_once("?invoice=")
+    .unwrap_or_else(|| panic!("not an invoice redirect: {}", location))
+    .1
+    .to_owned()
+}
+
+#[test]
+fn repeated_requests_for_paid_files_reuse_unpaid_invoices() {
+  let wallet = FakeWallet::new();
+  test_with_arguments(
+    &["--monero-rpc-address", wallet.url()],
+    |context| async move {
+      context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+      context.write("foo", "precious content");
+      context.write("bar", "other content");
+      let foo = context.files_url().join("foo").unwrap();
+      let bar = context.files_url().join("bar").unwrap();
+
+      let first = invoice_redirect(&foo).await;
+      assert_eq!(invoice_redirect(&foo).await, first);
+      assert_ne!(invoice_redirect(&bar).await, first);
+    },
+  );
+}
+
+#[test]
+fn paid_invoices_are_not_reused() {
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_arguments(&["--monero-rpc-address", &url], |context| async move {
+    context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+    context.write("foo", "precious content");
+    let foo = context.files_url().join("foo").unwrap();
+
+    let first = invoice_redirect(&foo).await;
+    wallet.settle(&first);
+
+    let paid = context
+      .files_url()
+      .join(&format!("foo?invoice={}", first))
+      .unwrap();
+    assert_eq!(text(&paid).await, "precious content");
+
+    let second = invoice_redirect(&foo).await;
+    assert_ne!(second, first);
+  });
+}
+
+#[test]
+fn invoices_are_not_reused_once_paid_elsewhere() {
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_arguments(&["--monero-rpc-address", &url], |context| async move {
+    context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+    context.write("foo", "precious content");
+    let foo = context.files_url().join("foo").unwrap();
+
+    let first = invoice_redirect(&foo).await;
+    wallet.settle(&first);
+
+    assert_ne!(invoice_redirect(&foo).await, first);
+  });
+}
+
+async fn invoice_status(url: &reqwest::Url, forwarded_for: &str) -> reqwest::StatusCode {
+  reqwest::Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .build()
+    .unwrap()
+    .get(url.clone())
+    .header("x-forwarded-for", forwarded_for)
+    .send()
+    .await
+    .unwrap()
+    .status()
+}
+
+#[test]
+fn invoices_are_limited_per_forwarded_client() {
+  let wallet = FakeWallet::new();
+  test_with_arguments(
+    &[
+      "--monero-rpc-address",
+      wallet.url(),
+      "--invoice-burst=1",
+      "--trusted-proxy=127.0.0.1",
+      "--trusted-proxy=::1",
+    ],
+    |context| async move {
+      context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+      context.write("foo", "precious content");
+      context.write("bar", "other content");
+      let foo = context.files_url().join("foo").unwrap();
+      let bar = context.files_url().join("bar").unwrap();
+
+      assert_eq!(
+        invoice_status(&foo, "203.0.113.1").await,
+        reqwest::StatusCode::FOUND
+      );
+      assert_eq!(
+        invoice_status(&bar, "203.0.113.1").await,
+        reqwest::StatusCode::TOO_MANY_REQUESTS
+      );
+      assert_eq!(
+        invoice_status(&bar, "203.0.113.2").await,
+        reqwest::StatusCode::FOUND
+      );
+    },
+  );
+}
+
+#[test]
+fn forwarded_headers_from_untrusted_peers_are_ignored() {
+  let wallet = FakeWallet::new();
+  test_with_arguments(
+    &["--monero-rpc-address", wallet.url(), "--invoice-burst=1"],
+    |context| async move {
+      context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+      context.write("foo", "precious content");
+      context.write("bar", "other content");
+      let foo = context.files_url().join("foo").unwrap();
+      let bar = context.files_url().join("bar").unwrap();
+
+      assert_eq!(
+        invoice_status(&foo, "203.0.113.1").await,
+        reqwest::StatusCode::FOUND
+      );
+      assert_eq!(
+        invoice_status(&bar, "203.0.113.2").await,
+        reqwest::StatusCode::TOO_MANY_REQUESTS
+      );
+    },
+  );
+}
+
+/// Send an HTTP/1.0 request for `path` to `url`'s host and port, preceded by
+/// a PROXY protocol v1 header claiming that it comes from `client`.
+async fn proxy_protocol_get(url: &reqwest::Url, client: &str, path: &str) -> String {
+  let mut stream = tokio::net::TcpStream::connect((
+    url
+      .host_str()
+      .unwrap()
+      .trim_matches(|c| c == '[' || c == ']'),
+    url.port().unwrap(),
+  ))
+  .await
+  .unwrap();
+  stream
+    .write_all(
+      format!(
+        "PROXY TCP4 {} 127.0.0.1 56324 80\r\nGET {} HTTP/1.0\r\nhost: localhost\r\n\r\n",
+        client, path
+      )
+      .as_bytes(),
+    )
+    .await
+    .unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).await.unwrap();
+  response
+}
+
+#[test]
+fn proxy_protocol_headers_set_client_address() {
+  let wallet = FakeWallet::new();
+  test_with_arguments(
+    &[
+      "--monero-rpc-address",
+      wallet.url(),
+      "--invoice-burst=1",
+      "--trusted-proxy=127.0.0.1",
+      "--trusted-proxy=::1",
+      "--proxy-protocol",
+    ],
+    |context| async move {
+      context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+      context.write("foo", "precious content");
+      context.write("bar", "other content");
+      let url = context.files_url();
+
+      let response = proxy_protocol_get(url, "203.0.113.1", "/files/foo").await;
+      assert!(
+        response.starts_with("HTTP/1.0 302 Found\r\n"),
+        "{}",
+        response
+      );
+      let response = proxy_protocol_get(url, "203.0.113.1", "/files/bar").await;
+      assert!(
+        response.starts_with("HTTP/1.0 429 Too Many Requests\r\n"),
+        "{}",
+        response
+      );
+      let response = proxy_protocol_get(url, "203.0.113.2", "/files/bar").await;
+      assert!(
+        response.starts_with("HTTP/1.0 302 Found\r\n"),
+        "{}",
+        response
+      );
+    },
+  );
+}
+
+#[test]
+fn invoice_pages_have_breadcrumbs() {
+  use scraper::{Html, Selector};
+
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_arguments(&["--monero-rpc-address", &url], |context| async move {
+    context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+    context.write("music/foo", "precious content");
+    let foo = context.files_url().join("music/foo").unwrap();
+
+    let invoice = invoice_redirect(&foo).await;
+    let html = Html::parse_document(
+      &text(
+        &context
+          .files_url()
+          .join(&format!("music/foo?invoice={}", invoice))
+          .unwrap(),
+      )
+      .await,
+    );
+    let links = html
+      .select(&Selector::parse(".breadcrumbs > a:not(.parent)").unwrap())
+      .map(|a| a.value().attr("href").unwrap())
+      .collect::<Vec<&str>>();
+    assert_eq!(links, ["/files/", "/files/music/"]);
+  });
+}
+
+#[test]
+fn invoice_pages_copy_payment_requests_without_inline_script() {
+  use scraper::{Html, Selector};
+
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_arguments(&["--monero-rpc-address", &url], |context| async move {
+    context.write(".opuza.yaml", "{paid: true, base-price: 1 XMR}");
+    context.write("foo", "precious content");
+    let foo = context.files_url().join("foo").unwrap();
+
+    let invoice = invoice_redirect(&foo).await;
+    let html = Html::parse_document(
+      &text(
+        &context
+          .files_url()
+          .join(&format!("foo?invoice={}", invoice))
+          .unwrap(),
+      )
+      .await,
+    );
+    let button = html
+      .select(&Selector::parse(".clipboard-copy").unwrap())
+      .next()
+      .unwrap();
+    assert_eq!(button.value().attr("onclick"), None);
+    assert!(button
+      .value()
+      .attr("data-clipboard-text")
+      .unwrap()
+      .starts_with("monero:"));
+  });
+}
+
+#[test]
+fn invoice_pages_are_localized() {
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_arguments(&["--monero-rpc-address", &url], |context| async move {
+    context.write(
+      ".opuza.yaml",
+      "{paid: true, base-price: 1000.5 XMR, lang: de}",
+    );
+    context.write("foo", "precious content");
+    let foo = context.files_url().join("foo").unwrap();
+
+    let invoice = invoice_redirect(&foo).await;
+    let html = text(
+      &context
+        .files_url()
+        .join(&format!("foo?invoice={}", invoice))
+        .unwrap(),
+    )
+    .await;
+    assert!(html.contains("Monero-Zahlungsanforderung über 1.000,5 XMR"));
+    assert!(html.contains("Rechnung in Wallet öffnen"));
+  });
+}
+
+/// An environment serving `music.example` from `music`, `books.example` and
+/// `www.books.example` from `books`, and any other host from `www`.
+fn sites_environment(arguments: &[&str]) -> Environment {
+  let mut environment = Environment::test();
+  environment
+    .arguments
+    .extend(arguments.iter().cloned().map(OsString::from));
+  environment.arguments.push("--sites=sites.yaml".into());
+  for directory in ["www", "music", "books"] {
+    fs::create_dir(environment.working_directory.join(directory)).unwrap();
+  }
+  fs::write(
+    environment.working_directory.join("sites.yaml"),
+    "
+sites:
+  - hosts: [music.example]
+    directory: music
+    monero-account: 1
+  - hosts: [books.example, www.books.example]
+    directory: books
+",
+  )
+  .unwrap();
+  environment
+}
+
+async fn get_with_host(url: &reqwest::Url, host: &str) -> reqwest::Response {
+  reqwest::Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .build()
+    .unwrap()
+    .get(url.clone())
+    .header(reqwest::header::HOST, host)
+    .send()
+    .await
+    .unwrap()
+}
+
+#[test]
+fn sites_are_served_by_host() {
+  test_with_environment(&mut sites_environment(&[]), |context| async move {
+    context.write("file", "default");
+    context.write("../music/file", "music");
+    context.write("../books/file", "books");
+    let file = context.files_url().join("file").unwrap();
+
+    for (host, expected) in [
+      ("music.example", "music"),
+      ("music.example:8080", "music"),
+      ("MUSIC.example.", "music"),
+      ("books.example", "books"),
+      ("www.books.example", "books"),
+      ("localhost", "default"),
+      ("movies.example", "default"),
+    ] {
+      let response = get_with_host(&file, host).await;
+      assert_eq!(response.status(), StatusCode::OK, "{}", host);
+      assert_eq!(response.text().await.unwrap(), expected, "{}", host);
+    }
+  });
+}
+
+#[test]
+fn sites_follow_forwarded_host_from_trusted_proxies() {
+  test_with_environment(
+    &mut sites_environment(&["--trusted-proxy=127.0.0.1"]),
+    |context| async move {
+      context.write("../music/file", "music");
+      let response = reqwest::Client::new()
+        .get(context.files_url().join("file").unwrap())
+        .header("x-forwarded-host", "music.example")
+        .send()
+        .await
+        .unwrap();
+      assert_eq!(response.text().await.unwrap(), "music");
+    },
+  );
+}
+
+#[test]
+fn unknown_hosts_are_misdirected_without_directory() {
+  let mut environment = sites_environment(&[]);
+  environment
+    .arguments
+    .retain(|argument| argument != "--directory=www");
+  test_with_environment(&mut environment, |context| async move {
+    context.write("file", "music");
+    let file = context.files_url().join("file").unwrap();
+    assert_eq!(
+      get_with_host(&file, "music.example")
+        .await
+        .text()
+        .await
+        .unwrap(),
+      "music"
+    );
+    assert_eq!(
+      get_with_host(&file, "movies.example").await.status(),
+      StatusCode::MISDIRECTED_REQUEST
+    );
+  });
+}
+
+#[test]
+fn invoices_are_created_in_the_account_of_their_site_and_only_valid_there() {
+  let wallet = FakeWallet::new();
+  let url = wallet.url().to_owned();
+  test_with_environment(
+    &mut sites_environment(&["--monero-rpc-address", &url]),
+    |context| async move {
+      for directory in ["", "../music/", "../books/"] {
+        context.write(
+          &format!("{}.opuza.yaml", directory),
+          "{paid: true, base-price: 1 XMR}",
+        );
+        context.write(&format!("{}foo", directory), directory);
+      }
+      let foo = context.files_url().join("foo").unwrap();
+
+      let response = get_with_host(&foo, "music.example").await;
+      assert_eq!(response.status(), StatusCode::FOUND);
+      let invoice = response.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .split_once("?invoice=")
+        .unwrap()
+        .1
+        .to_owned();
+      assert_eq!(wallet.accounts(), [1]);
+      wallet.settle(&invoice);
+
+      let paid = context
+        .files_url()
+        .join(&format!("foo?invoice={}", invoice))
+        .unwrap();
+      let response = get_with_host(&paid, "music.example").await;
+      assert_eq!(response.text().await.unwrap(), "../music/");
+      for host in ["books.example", "localhost"] {
+        assert_eq!(
+          get_with_host(&paid, host).await.status(),
+          StatusCode::BAD_REQUEST,
+          "{}",
+          host
+        );
+      }
+
+      assert_eq!(
+        get_with_host(&foo, "books.example").await.status(),
+        StatusCode::FOUND
+      );
+      assert_eq!(wallet.accounts(), [1, 0]);
+    },
+  );
+}
+
+#[test]
+fn sites_get_certificates_of_their_own() {
+  let (certificate_cache, root_certificate) = set_up_test_certificate();
+  let music_root_certificate = cache_test_certificate(certificate_cache.path(), "music.localhost");
+
+  let mut environment = sites_environment(&[
+    "--acme-cache-directory",
+    certificate_cache.path().to_str().unwrap(),
+    "--https-port=0",
+    "--acme-domain=localhost",
+    "--acme-directory-url",
+    TEST_ACME_DIRECTORY_URL,
+  ]);
+  fs::write(
+    environment.working_directory.join("sites.yaml"),
+    "sites: [{hosts: [music.localhost], directory: music}]",
+  )
+  .unwrap();
+
+  test_with_environment(&mut environment, |context| async move {
+    context.write("file", "default");
+    context.write("../music/file", "music");
+    https_client(&context, root_certificate.clone()).await;
+
+    let https_port = context.https_files_url().port().unwrap();
+    let get = |host: &'static str, root_certificate: reqwest::Certificate| {
+      let mut url = context.https_files_url().join("file").unwrap();
+      url.set_host(Some(host)).unwrap();
+      async move {
+        reqwest::Client::builder()
+          .add_root_certificate(root_certificate)
+          .resolve(host, ([127, 0, 0, 1], https_port).into())
+          .build()
+          .unwrap()
+          .get(url)
+          .send()
+          .await
+      }
+    };
+
+    assert_eq!(
+      get("localhost", root_certificate.clone())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap(),
+      "default"
+    );
+    assert_eq!(
+      get("music.localhost", music_root_certificate.clone())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap(),
+      "music"
+    );
+    assert!(get("music.localhost", root_certificate).await.is_err());
+    assert!(get("localhost", music_root_certificate).await.is_err());
+  });
+}
@@ -2,7 +2,9 @@ use tokio::task;
 use {
   crate::{
     common::*,
-    test_utils::{set_up_test_certificate, test_with_arguments, test_with_lnd},
+    test_utils::{
+      set_up_test_certificate, test_with_arguments, test_with_lnd, TEST_ACME_DIRECTORY_URL,
+    },
   },
   chromiumoxide::{
     browser::BrowserConfig,
@@ -92,6 +94,8 @@ fn copy_payment_request_to_clipboard() {
       certificate_cache.path().to_str().unwrap(),
       "--https-port=0",
       "--acme-domain=localhost",
+      "--acme-directory-url",
+      TEST_ACME_DIRECTORY_URL,
     ],
     |context| async move {
       context.write(".opuza.yaml", "{paid: true, base-price: 1000 sat}");
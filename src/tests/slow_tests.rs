@@ -429,6 +429,8 @@ fn serves_multiple_clients_through_https() {
       certificate_cache.path().to_str().unwrap(),
       "--https-port=0",
       "--acme-domain=localhost",
+      "--acme-directory-url",
+      TEST_ACME_DIRECTORY_URL,
     ],
     |context| async move {
       context.write("file", "encrypted content");
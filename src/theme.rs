@@ -0,0 +1,108 @@
+use {
+  crate::{common::*, static_assets::StaticAssets, template::Template},
+  std::path::Component,
+};
+
+/// Operator-supplied branding, loaded from `--theme-directory`. Anything a
+/// theme doesn't provide falls back to the built-in page layout and the
+/// embedded static assets.
+///
+/// A theme directory may contain:
+///
+/// - `static/`, whose files are served under `/static/` in preference to the
+///   embedded ones, e.g. `static/index.css` replaces the stylesheet.
+/// - `static/logo.svg` or `static/logo.png`, shown in the default header.
+/// - `header.html` and `footer.html`, with the variable `title`.
+/// - `page.html`, with the variables `title`, `head`, `header`, `body`, and
+///   `footer`. `head` holds the stylesheet and script tags, but no `<title>`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Theme {
+  static_directory: Option<PathBuf>,
+  logo: Option<&'static str>,
+  header: Option<Arc<Template>>,
+  footer: Option<Arc<Template>>,
+  page: Option<Arc<Template>>,
+}
+
+impl Theme {
+  const LOGOS: &'static [&'static str] = &["logo.svg", "logo.png"];
+
+  pub(crate) fn load(directory: &Path) -> Result<Self> {
+    fs::read_dir(directory).context(error::FilesystemIo { path: directory })?;
+
+    let static_directory = directory.join("static");
+
+    Ok(Self {
+      logo: Self::LOGOS
+        .iter()
+        .copied()
+        .find(|logo| static_directory.join(logo).is_file()),
+      header: Template::load(&directory.join("header.html"), &["title"])?.map(Arc::new),
+      footer: Template::load(&directory.join("footer.html"), &["title"])?.map(Arc::new),
+      page: Template::load(
+        &directory.join("page.html"),
+        &["title", "head", "header", "body", "footer"],
+      )?
+      .map(Arc::new),
+      static_directory: Some(static_directory),
+    })
+  }
+
+  pub(crate) fn page(&self) -> Option<&Template> {
+    self.page.as_deref()
+  }
+
+  /// The contents of the page header, if any.
+  pub(crate) fn header(&self, title: &Markup) -> Option<Markup> {
+    match (&self.header, self.logo) {
+      (Some(header), _) => Some(header.render(&[("title", title)])),
+      (None, Some(logo)) => Some(maud::html! {
+        a href="/files/" {
+          img class="logo" src={"/static/" (logo)} alt="Logo";
+        }
+      }),
+      (None, None) => None,
+    }
+  }
+
+  /// The contents of the page footer, if the theme replaces the default.
+  pub(crate) fn footer(&self, title: &Markup) -> Option<Markup> {
+    self
+      .footer
+      .as_ref()
+      .map(|footer| footer.render(&[("title", title)]))
+  }
+
+  /// Serve the static asset at `tail` from the theme, or from the embedded
+  /// assets if the theme doesn't have it.
+  pub(crate) async fn serve_static(&self, tail: &[&str]) -> Result<Response<Body>> {
+    let path = tail.join("");
+
+    if let Some(static_directory) = &self.static_directory {
+      let relative = Path::new(&path);
+      let safe = relative.components().all(|component| {
+        matches!(component, Component::Normal(name) if !name.to_string_lossy().starts_with('.'))
+      });
+      if safe && !path.is_empty() {
+        let file = static_directory.join(relative);
+        match tokio::fs::read(&file).await {
+          Ok(bytes) => {
+            let mut builder = Response::builder();
+            if let Some(guess) = mime_guess::from_path(&file).first() {
+              builder = builder.header(header::CONTENT_TYPE, guess.essence_str());
+            }
+            return builder.body(bytes.into()).map_err(|error| {
+              Error::internal(format!("Failed to construct response: {}", error))
+            });
+          }
+          Err(error)
+            if error.kind() == io::ErrorKind::NotFound
+              || error.kind() == io::ErrorKind::IsADirectory => {}
+          Err(source) => return Err(error::FilesystemIo { path: file }.into_error(source)),
+        }
+      }
+    }
+
+    StaticAssets::serve(tail)
+  }
+}
@@ -0,0 +1,288 @@
+use {
+  crate::common::*,
+  image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageFormat, ImageReader, Rgb,
+    RgbImage,
+  },
+  std::time::UNIX_EPOCH,
+  uuid::Uuid,
+};
+
+/// Generates JPEG thumbnails of images, and caches them in a directory
+/// outside of the served tree, keyed by path, size and modification time.
+#[derive(Clone, Debug)]
+pub(crate) struct Thumbnails {
+  cache_directory: Option<PathBuf>,
+}
+
+/// What kind of thumbnail to generate. Paid images only get a low-resolution
+/// preview, so that the thumbnail doesn't give away the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Variant {
+  Full,
+  Preview { watermark: bool },
+}
+
+impl Variant {
+  fn as_str(self) -> &'static str {
+    match self {
+      Self::Full => "full",
+      Self::Preview { watermark: false } => "preview",
+      Self::Preview { watermark: true } => "preview-watermark",
+    }
+  }
+}
+
+impl Thumbnails {
+  /// The maximum width and height of a thumbnail.
+  pub(crate) const SIZE: u32 = 256;
+
+  /// The maximum width and height that previews of paid images are reduced
+  /// to before being scaled back up to thumbnail size.
+  const PREVIEW_RESOLUTION: u32 = 32;
+
+  /// Use `cache_directory`, which is created if it doesn't exist, to cache
+  /// thumbnails. It may not be inside of `base_directory`, since cached
+  /// thumbnails of paid images would otherwise be served for free.
+  pub(crate) fn new(cache_directory: Option<PathBuf>, base_directory: &Path) -> Result<Self> {
+    if let Some(cache_directory) = &cache_directory {
+      fs::create_dir_all(cache_directory).context(error::FilesystemIo {
+        path: cache_directory,
+      })?;
+      let canonical = cache_directory
+        .canonicalize()
+        .context(error::FilesystemIo {
+          path: cache_directory,
+        })?;
+      let base_directory = base_directory.canonicalize().context(error::FilesystemIo {
+        path: base_directory,
+      })?;
+      if canonical.starts_with(&base_directory) {
+        return Err(
+          error::ThumbnailCacheDirectory {
+            path: cache_directory,
+          }
+          .build(),
+        );
+      }
+    }
+
+    Ok(Self { cache_directory })
+  }
+
+  /// Whether thumbnails can be generated for the file named `path`, judging
+  /// by its extension.
+  pub(crate) fn supported(path: impl AsRef<Path>) -> bool {
+    ImageFormat::from_path(path)
+      .map(|format| format.reading_enabled())
+      .unwrap_or(false)
+  }
+
+  pub(crate) async fn serve(&self, path: &InputPath, variant: Variant) -> Result<Response<Body>> {
+    if !Self::supported(path.display_path()) {
+      return Err(
+        error::ThumbnailUnsupported {
+          path: path.display_path(),
+        }
+        .build(),
+      );
+    }
+
+    let thumbnails = self.clone();
+    let path = path.clone();
+    let jpeg = tokio::task::spawn_blocking(move || thumbnails.thumbnail(&path, variant))
+      .await
+      .map_err(|error| Error::internal(format!("Thumbnail generation failed: {}", error)))??;
+
+    Response::builder()
+      .header(header::CONTENT_TYPE, "image/jpeg")
+      .body(Body::from(jpeg))
+      .map_err(|error| Error::internal(format!("Failed to construct response: {}", error)))
+  }
+
+  fn thumbnail(&self, path: &InputPath, variant: Variant) -> Result<Vec<u8>> {
+    let cache_path = match &self.cache_directory {
+      Some(cache_directory) => Some(cache_directory.join(Self::cache_key(path, variant)?)),
+      None => None,
+    };
+
+    if let Some(cache_path) = &cache_path {
+      match fs::read(cache_path) {
+        Ok(jpeg) => return Ok(jpeg),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(source) => return Err(error::FilesystemIo { path: cache_path }.into_error(source)),
+      }
+    }
+
+    let jpeg = Self::generate(path, variant)?;
+
+    if let Some(cache_path) = &cache_path {
+      // Write to a temporary file first, so that concurrent requests never
+      // read a partially written thumbnail.
+      let temporary = cache_path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+      fs::write(&temporary, &jpeg).context(error::FilesystemIo { path: &temporary })?;
+      fs::rename(&temporary, cache_path).context(error::FilesystemIo { path: cache_path })?;
+    }
+
+    Ok(jpeg)
+  }
+
+  /// The name of the cached thumbnail of `path`, which changes whenever the
+  /// file is modified.
+  fn cache_key(path: &InputPath, variant: Variant) -> Result<String> {
+    let metadata = path
+      .as_ref()
+      .metadata()
+      .with_context(|| Error::filesystem_io(path))?;
+    let modified = metadata
+      .modified()
+      .ok()
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .unwrap_or_default();
+
+    let key = format!(
+      "{}\0{}\0{}\0{}",
+      path.as_ref().display(),
+      modified.as_nanos(),
+      metadata.len(),
+      variant.as_str(),
+    );
+
+    Ok(format!(
+      "{}.jpg",
+      hex::encode(openssl::sha::sha256(key.as_bytes()))
+    ))
+  }
+
+  fn generate(path: &InputPath, variant: Variant) -> Result<Vec<u8>> {
+    let image = ImageReader::open(path)
+      .with_context(|| Error::filesystem_io(path))?
+      .with_guessed_format()
+      .with_context(|| Error::filesystem_io(path))?
+      .decode()
+      .context(error::ThumbnailImage {
+        path: path.display_path(),
+      })?;
+
+    let mut thumbnail = if image.width() > Self::SIZE || image.height() > Self::SIZE {
+      image.thumbnail(Self::SIZE, Self::SIZE)
+    } else {
+      image
+    };
+
+    if let Variant::Preview { watermark } = variant {
+      let (width, height) = (thumbnail.width(), thumbnail.height());
+      thumbnail = thumbnail
+        .thumbnail(Self::PREVIEW_RESOLUTION, Self::PREVIEW_RESOLUTION)
+        .resize_exact(width, height, FilterType::Triangle);
+      if watermark {
+        thumbnail = DynamicImage::ImageRgb8(Self::watermark(Self::flatten(&thumbnail)));
+      }
+    }
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 80)
+      .encode_image(&Self::flatten(&thumbnail))
+      .context(error::ThumbnailImage {
+        path: path.display_path(),
+      })?;
+    Ok(jpeg)
+  }
+
+  /// Drop the alpha channel, compositing transparent pixels onto white,
+  /// since JPEG doesn't support transparency.
+  fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+      let [r, g, b, a] = rgba.get_pixel(x, y).0;
+      let blend = |channel: u8| {
+        ((u16::from(channel) * u16::from(a) + 255 * (255 - u16::from(a))) / 255) as u8
+      };
+      Rgb([blend(r), blend(g), blend(b)])
+    })
+  }
+
+  /// Stamp `PREVIEW` across the middle of `image`.
+  fn watermark(mut image: RgbImage) -> RgbImage {
+    // Rows of a 5×7 bitmap font, most significant of the five bits first.
+    const GLYPHS: [[u8; 7]; 7] = [
+      // P
+      [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+      // R
+      [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+      // E
+      [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+      // V
+      [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+      // I
+      [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+      // E
+      [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+      // W
+      [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+    ];
+
+    let columns = GLYPHS.len() as u32 * 6 - 1;
+    let scale = (image.width() * 4 / 5 / columns).max(1);
+    let left = image.width().saturating_sub(columns * scale) / 2;
+    let top = image.height().saturating_sub(7 * scale) / 2;
+
+    for (i, glyph) in GLYPHS.iter().enumerate() {
+      for (row, bits) in glyph.iter().enumerate() {
+        for column in 0..5 {
+          if bits & (0x10 >> column) == 0 {
+            continue;
+          }
+          let x0 = left + (i as u32 * 6 + column) * scale;
+          let y0 = top + row as u32 * scale;
+          for y in y0..(y0 + scale).min(image.height()) {
+            for x in x0..(x0 + scale).min(image.width()) {
+              let pixel = image.get_pixel_mut(x, y);
+              for channel in pixel.0.iter_mut() {
+                *channel = ((u16::from(*channel) + 255 * 2) / 3) as u8;
+              }
+            }
+          }
+        }
+      }
+    }
+
+    image
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn supported_formats() {
+    assert!(Thumbnails::supported("foo.png"));
+    assert!(Thumbnails::supported("foo.JPG"));
+    assert!(Thumbnails::supported("foo.webp"));
+    assert!(!Thumbnails::supported("foo.txt"));
+    assert!(!Thumbnails::supported("foo"));
+  }
+
+  #[test]
+  fn cache_directory_may_not_be_inside_served_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    assert_matches!(
+      Thumbnails::new(Some(temp_dir.path().join("thumbnails")), temp_dir.path()),
+      Err(Error::ThumbnailCacheDirectory { .. })
+    );
+    fs::create_dir(temp_dir.path().join("www")).unwrap();
+    Thumbnails::new(
+      Some(temp_dir.path().join("thumbnails")),
+      &temp_dir.path().join("www"),
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn watermark_lightens_the_middle_of_the_image() {
+    let image = Thumbnails::watermark(RgbImage::new(100, 100));
+    assert_eq!(image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    assert!(image.pixels().any(|pixel| pixel != &Rgb([0, 0, 0])));
+  }
+}
@@ -0,0 +1,210 @@
+use {
+  crate::common::*,
+  notify::{RecommendedWatcher, RecursiveMode, Watcher},
+  rustls_acme::futures_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    InconsistentKeys, ServerConfig,
+  },
+  std::sync::RwLock,
+  tokio::sync::mpsc,
+  tokio_util::sync::CancellationToken,
+};
+
+/// The PEM-encoded certificate chain and private key in `--tls-certificate`
+/// and `--tls-key`. New handshakes use whichever was loaded last.
+#[derive(Debug)]
+pub(crate) struct TlsCertificate {
+  certificate: PathBuf,
+  key: PathBuf,
+  certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCertificate {
+  pub(crate) fn load(certificate: PathBuf, key: PathBuf) -> Result<Arc<Self>> {
+    let certified_key = Self::certified_key(&certificate, &key)?;
+    Ok(Arc::new(Self {
+      certificate,
+      key,
+      certified_key: RwLock::new(Arc::new(certified_key)),
+    }))
+  }
+
+  /// Load the certificate chain and key. Fails if the key doesn't belong to
+  /// the first certificate in the chain.
+  fn certified_key(certificate: &Path, key: &Path) -> Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+      .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+      .context(error::TlsPem { path: certificate })?;
+    if chain.is_empty() {
+      return Err(error::TlsCertificateMissing { path: certificate }.build());
+    }
+
+    let key_der = PrivateKeyDer::from_pem_file(key).context(error::TlsPem { path: key })?;
+
+    let certified_key = ServerConfig::builder()
+      .crypto_provider()
+      .key_provider
+      .load_private_key(key_der)
+      .map(|signing_key| CertifiedKey::new(chain, signing_key))
+      .and_then(|certified_key| match certified_key.keys_match() {
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {
+          Ok(certified_key)
+        }
+        Err(error) => Err(error),
+      })
+      .map_err(|source| match source {
+        rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => {
+          error::TlsKeyMismatch { certificate, key }.build()
+        }
+        source => error::TlsConfig { certificate, key }.into_error(source),
+      })?;
+
+    Ok(certified_key)
+  }
+
+  /// Load the files again, keeping the previous certificate if they're
+  /// invalid, e.g. because only one of them has been replaced so far.
+  fn reload(&self) {
+    match Self::certified_key(&self.certificate, &self.key) {
+      Ok(certified_key) => {
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        log::info!(
+          "Reloaded TLS certificate from `{}`",
+          self.certificate.display()
+        );
+      }
+      Err(error) => log::error!(
+        "Failed to reload TLS certificate, continuing with the previous one: {}",
+        error
+      ),
+    }
+  }
+}
+
+impl ResolvesServerCert for TlsCertificate {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.certified_key.read().unwrap().clone())
+  }
+}
+
+/// Reloads a `TlsCertificate` when its files change, or when the process
+/// receives SIGHUP.
+pub(crate) struct TlsCertificateReloader {
+  certificate: Arc<TlsCertificate>,
+  changes: mpsc::UnboundedReceiver<()>,
+  _watcher: Option<RecommendedWatcher>,
+  hangup: Hangup,
+}
+
+impl TlsCertificateReloader {
+  /// How long to wait after a file changes before reloading, so that both
+  /// files have been replaced by then.
+  const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+  /// Start watching the files, and install the SIGHUP handler, so that
+  /// SIGHUP doesn't terminate the process from here on.
+  pub(crate) fn new(certificate: Arc<TlsCertificate>) -> Result<Self> {
+    let hangup = Hangup::new()?;
+
+    let (sender, changes) = mpsc::unbounded_channel();
+    let watcher = Self::watch(&certificate, sender)
+      .map_err(|error| {
+        log::warn!(
+          "Failed to watch `{}` and `{}` for changes, reloading them on SIGHUP only: {}",
+          certificate.certificate.display(),
+          certificate.key.display(),
+          error
+        )
+      })
+      .ok();
+
+    Ok(Self {
+      certificate,
+      changes,
+      _watcher: watcher,
+      hangup,
+    })
+  }
+
+  /// Watch the directories the files are in, rather than the files
+  /// themselves, so that files replaced by renaming or by updating a
+  /// symlink are noticed too.
+  fn watch(
+    certificate: &TlsCertificate,
+    sender: mpsc::UnboundedSender<()>,
+  ) -> notify::Result<RecommendedWatcher> {
+    let paths = [certificate.certificate.clone(), certificate.key.clone()];
+    let file_names = paths
+      .iter()
+      .filter_map(|path| path.file_name().map(ToOwned::to_owned))
+      .collect::<Vec<OsString>>();
+
+    let mut watcher =
+      notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if event.kind.is_access() => {}
+        Ok(event)
+          if !event.paths.iter().any(|path| {
+            path
+              .file_name()
+              .is_some_and(|file_name| file_names.iter().any(|name| name == file_name))
+          }) => {}
+        _ => {
+          sender.send(()).ok();
+        }
+      })?;
+
+    for directory in paths
+      .iter()
+      .filter_map(|path| path.parent())
+      .collect::<HashSet<&Path>>()
+    {
+      watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(watcher)
+  }
+
+  pub(crate) async fn run(mut self, shutdown: CancellationToken) {
+    loop {
+      tokio::select! {
+        Some(()) = self.changes.recv() => {
+          tokio::select! {
+            () = tokio::time::sleep(Self::SETTLE_TIME) => {}
+            () = shutdown.cancelled() => return,
+          }
+          while self.changes.try_recv().is_ok() {}
+        }
+        () = self.hangup.recv() => log::info!("Received SIGHUP"),
+        () = shutdown.cancelled() => return,
+      }
+      self.certificate.reload();
+    }
+  }
+}
+
+/// Receives SIGHUP, on platforms that have it.
+struct Hangup {
+  #[cfg(unix)]
+  signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+  fn new() -> Result<Self> {
+    Ok(Self {
+      #[cfg(unix)]
+      signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .context(error::SignalHandlerInstall)?,
+    })
+  }
+
+  async fn recv(&mut self) {
+    #[cfg(unix)]
+    self.signal.recv().await;
+
+    #[cfg(not(unix))]
+    future::pending::<()>().await;
+  }
+}
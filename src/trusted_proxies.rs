@@ -0,0 +1,459 @@
+use {crate::common::*, hyper::HeaderMap, std::str::FromStr};
+
+/// A `--trusted-proxy`: an IP address, a CIDR range, or `unix` for peers
+/// connecting over a Unix domain socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TrustedProxy {
+  Network { address: IpAddr, prefix_length: u8 },
+  Unix,
+}
+
+impl TrustedProxy {
+  fn contains(self, ip: IpAddr) -> bool {
+    match (self, ip.to_canonical()) {
+      (
+        Self::Network {
+          address: IpAddr::V4(address),
+          prefix_length,
+        },
+        IpAddr::V4(ip),
+      ) => {
+        let mask = u32::MAX
+          .checked_shl(32 - u32::from(prefix_length))
+          .unwrap_or(0);
+        u32::from(address) & mask == u32::from(ip) & mask
+      }
+      (
+        Self::Network {
+          address: IpAddr::V6(address),
+          prefix_length,
+        },
+        IpAddr::V6(ip),
+      ) => {
+        let mask = u128::MAX
+          .checked_shl(128 - u32::from(prefix_length))
+          .unwrap_or(0);
+        u128::from(address) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for TrustedProxy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s == "unix" {
+      return Ok(Self::Unix);
+    }
+
+    let invalid = || {
+      format!(
+        "expected an IP address, a CIDR range like `10.0.0.0/8`, or `unix`, got `{}`",
+        s
+      )
+    };
+
+    let (address, prefix_length) = match s.split_once('/') {
+      Some((address, prefix_length)) => (address, Some(prefix_length)),
+      None => (s, None),
+    };
+
+    let address = address
+      .parse::<IpAddr>()
+      .map_err(|_| invalid())?
+      .to_canonical();
+
+    let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+
+    let prefix_length = match prefix_length {
+      Some(prefix_length) => prefix_length
+        .parse::<u8>()
+        .ok()
+        .filter(|prefix_length| *prefix_length <= max_prefix_length)
+        .ok_or_else(invalid)?,
+      None => max_prefix_length,
+    };
+
+    Ok(Self::Network {
+      address,
+      prefix_length,
+    })
+  }
+}
+
+/// What trusted proxies report about a request.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Forwarded {
+  /// The client's IP address, or `None` if unknown, e.g. for connections
+  /// over a Unix domain socket without a proxy in front.
+  pub(crate) client: Option<IpAddr>,
+  /// Whether the client made the request over HTTPS.
+  pub(crate) https: bool,
+  /// The host the client made the request to, if a proxy reported one.
+  pub(crate) host: Option<String>,
+}
+
+/// The `--trusted-proxy` arguments, and whether they send a PROXY protocol
+/// header.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies {
+  proxies: Arc<[TrustedProxy]>,
+  proxy_protocol: bool,
+}
+
+impl TrustedProxies {
+  pub(crate) fn new(arguments: &Arguments) -> Self {
+    Self {
+      proxies: arguments.trusted_proxy.clone().into(),
+      proxy_protocol: arguments.proxy_protocol,
+    }
+  }
+
+  /// Whether `peer` is a trusted proxy. `None` is a peer connecting over a
+  /// Unix domain socket.
+  pub(crate) fn trusts(&self, peer: Option<IpAddr>) -> bool {
+    self.proxies.iter().any(|proxy| match peer {
+      Some(ip) => proxy.contains(ip),
+      None => *proxy == TrustedProxy::Unix,
+    })
+  }
+
+  /// Whether connections from `peer` start with a PROXY protocol header.
+  pub(crate) fn expect_proxy_protocol(&self, peer: Option<IpAddr>) -> bool {
+    self.proxy_protocol && self.trusts(peer)
+  }
+
+  /// Determine who made a request that `peer` sent over a connection that
+  /// used HTTPS if `https` is true.
+  ///
+  /// The `Forwarded` header, or if it's missing the `X-Forwarded-*` headers,
+  /// are only honoured if `peer` is trusted. Addresses the proxies appended
+  /// are then walked from the right, until an address that isn't a trusted
+  /// proxy is found, so that addresses made up by the client are ignored.
+  pub(crate) fn forwarded(
+    &self,
+    peer: Option<IpAddr>,
+    https: bool,
+    headers: &HeaderMap,
+  ) -> Forwarded {
+    let mut forwarded = Forwarded {
+      client: peer,
+      https,
+      host: None,
+    };
+
+    if !self.trusts(peer) {
+      return forwarded;
+    }
+
+    let (clients, proto, host) = if headers.contains_key(header::FORWARDED) {
+      Self::forwarded_header(headers)
+    } else {
+      Self::x_forwarded_headers(headers)
+    };
+
+    for client in clients.into_iter().rev() {
+      forwarded.client = client;
+      match client {
+        Some(ip) if self.trusts(Some(ip)) => {}
+        _ => break,
+      }
+    }
+
+    match proto.as_deref().map(str::to_ascii_lowercase).as_deref() {
+      Some("https") => forwarded.https = true,
+      Some("http") => forwarded.https = false,
+      _ => {}
+    }
+
+    forwarded.host = host;
+
+    forwarded
+  }
+
+  /// Parse the `Forwarded` header defined by RFC 7239. The protocol and host
+  /// are taken from the last element, which the closest proxy appended.
+  fn forwarded_header(
+    headers: &HeaderMap,
+  ) -> (Vec<Option<IpAddr>>, Option<String>, Option<String>) {
+    let elements = Self::header_values(headers, header::FORWARDED.as_str())
+      .map(|element| {
+        element
+          .split(';')
+          .filter_map(|pair| pair.split_once('='))
+          .map(|(key, value)| {
+            (
+              key.trim().to_ascii_lowercase(),
+              value.trim().trim_matches('"').to_owned(),
+            )
+          })
+          .collect::<Vec<(String, String)>>()
+      })
+      .collect::<Vec<Vec<(String, String)>>>();
+
+    let clients = elements
+      .iter()
+      .map(|pairs| {
+        pairs
+          .iter()
+          .find(|(key, _)| key == "for")
+          .and_then(|(_, node)| Self::parse_node(node))
+      })
+      .collect();
+
+    let last = |name: &str| {
+      elements.last().and_then(|pairs| {
+        pairs
+          .iter()
+          .find(|(key, _)| key == name)
+          .map(|(_, value)| value.clone())
+      })
+    };
+
+    (clients, last("proto"), last("host"))
+  }
+
+  /// Parse the de-facto standard `X-Forwarded-For`, `X-Forwarded-Proto` and
+  /// `X-Forwarded-Host` headers.
+  fn x_forwarded_headers(
+    headers: &HeaderMap,
+  ) -> (Vec<Option<IpAddr>>, Option<String>, Option<String>) {
+    let clients = Self::header_values(headers, "x-forwarded-for")
+      .map(Self::parse_node)
+      .collect();
+
+    let last = |name: &str| Self::header_values(headers, name).last().map(str::to_owned);
+
+    (clients, last("x-forwarded-proto"), last("x-forwarded-host"))
+  }
+
+  /// The comma-separated values of all `name` headers, in order.
+  fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+      .get_all(name)
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .map(str::trim)
+      .filter(|value| !value.is_empty())
+  }
+
+  /// Parse an IP address, optionally with a port, and IPv6 addresses
+  /// optionally in brackets. Yields `None` for `unknown`, obfuscated
+  /// identifiers, and anything else that isn't an IP address.
+  fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(socket_addr) = node.parse::<SocketAddr>() {
+      return Some(socket_addr.ip().to_canonical());
+    }
+
+    node
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .parse::<IpAddr>()
+      .ok()
+      .map(|ip| ip.to_canonical())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn proxies(proxies: &[&str]) -> TrustedProxies {
+    TrustedProxies {
+      proxies: proxies
+        .iter()
+        .map(|proxy| proxy.parse().unwrap())
+        .collect::<Vec<TrustedProxy>>()
+        .into(),
+      proxy_protocol: false,
+    }
+  }
+
+  fn headers(headers: &[(&str, &str)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+      map.append(
+        header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+        HeaderValue::from_str(value).unwrap(),
+      );
+    }
+    map
+  }
+
+  fn ip(s: &str) -> Option<IpAddr> {
+    Some(s.parse().unwrap())
+  }
+
+  #[test]
+  fn parse_trusted_proxy() {
+    assert_eq!(
+      "10.0.0.0/8".parse::<TrustedProxy>().unwrap(),
+      TrustedProxy::Network {
+        address: [10, 0, 0, 0].into(),
+        prefix_length: 8
+      }
+    );
+    assert_eq!(
+      "::1".parse::<TrustedProxy>().unwrap(),
+      TrustedProxy::Network {
+        address: "::1".parse().unwrap(),
+        prefix_length: 128
+      }
+    );
+    assert_eq!("unix".parse::<TrustedProxy>().unwrap(), TrustedProxy::Unix);
+    for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "localhost", ""] {
+      assert!(invalid.parse::<TrustedProxy>().is_err(), "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn trusts() {
+    let proxies = proxies(&["10.0.0.0/8", "fd00::/8", "192.168.1.1"]);
+    assert!(proxies.trusts(ip("10.1.2.3")));
+    assert!(proxies.trusts(ip("::ffff:10.1.2.3")));
+    assert!(proxies.trusts(ip("fd12::1")));
+    assert!(proxies.trusts(ip("192.168.1.1")));
+    assert!(!proxies.trusts(ip("192.168.1.2")));
+    assert!(!proxies.trusts(ip("11.0.0.1")));
+    assert!(!proxies.trusts(ip("fe80::1")));
+    assert!(!proxies.trusts(None));
+    assert!(self::proxies(&["unix"]).trusts(None));
+    assert!(self::proxies(&["0.0.0.0/0"]).trusts(ip("1.2.3.4")));
+  }
+
+  #[test]
+  fn untrusted_peers_are_not_believed() {
+    assert_eq!(
+      proxies(&["10.0.0.1"]).forwarded(
+        ip("1.2.3.4"),
+        false,
+        &headers(&[
+          ("x-forwarded-for", "5.6.7.8"),
+          ("x-forwarded-proto", "https"),
+          ("x-forwarded-host", "example.com"),
+        ])
+      ),
+      Forwarded {
+        client: ip("1.2.3.4"),
+        https: false,
+        host: None,
+      }
+    );
+  }
+
+  #[test]
+  fn x_forwarded_headers() {
+    assert_eq!(
+      proxies(&["10.0.0.1"]).forwarded(
+        ip("10.0.0.1"),
+        false,
+        &headers(&[
+          ("x-forwarded-for", "5.6.7.8"),
+          ("x-forwarded-proto", "https"),
+          ("x-forwarded-host", "example.com"),
+        ])
+      ),
+      Forwarded {
+        client: ip("5.6.7.8"),
+        https: true,
+        host: Some("example.com".into()),
+      }
+    );
+  }
+
+  #[test]
+  fn addresses_made_up_by_clients_are_skipped() {
+    let proxies = proxies(&["10.0.0.0/8"]);
+    assert_eq!(
+      proxies
+        .forwarded(
+          ip("10.0.0.1"),
+          false,
+          &headers(&[
+            ("x-forwarded-for", "1.1.1.1, 5.6.7.8"),
+            ("x-forwarded-for", "10.0.0.2"),
+          ])
+        )
+        .client,
+      ip("5.6.7.8")
+    );
+  }
+
+  #[test]
+  fn forwarded_header() {
+    let proxies = proxies(&["10.0.0.0/8"]);
+    assert_eq!(
+      proxies.forwarded(
+        ip("10.0.0.1"),
+        true,
+        &headers(&[
+          ("forwarded", "for=1.1.1.1;proto=https"),
+          (
+            "forwarded",
+            "for=\"[2001:db8::1]:4711\";proto=http;host=example.com, for=10.0.0.2",
+          ),
+          ("x-forwarded-for", "9.9.9.9"),
+        ])
+      ),
+      Forwarded {
+        client: ip("2001:db8::1"),
+        https: true,
+        host: None,
+      }
+    );
+    assert_eq!(
+      proxies.forwarded(
+        ip("10.0.0.1"),
+        false,
+        &headers(&[(
+          "forwarded",
+          "For=\"1.2.3.4:80\";Proto=HTTPS;Host=example.com"
+        )])
+      ),
+      Forwarded {
+        client: ip("1.2.3.4"),
+        https: true,
+        host: Some("example.com".into()),
+      }
+    );
+  }
+
+  #[test]
+  fn unknown_clients() {
+    assert_eq!(
+      proxies(&["10.0.0.1"])
+        .forwarded(
+          ip("10.0.0.1"),
+          false,
+          &headers(&[("forwarded", "for=unknown")])
+        )
+        .client,
+      None,
+    );
+    assert_eq!(
+      proxies(&["10.0.0.1"])
+        .forwarded(ip("10.0.0.1"), false, &headers(&[]))
+        .client,
+      ip("10.0.0.1"),
+    );
+  }
+
+  #[test]
+  fn unix_socket_peers() {
+    assert_eq!(
+      proxies(&["unix"])
+        .forwarded(None, false, &headers(&[("x-forwarded-for", "5.6.7.8")]))
+        .client,
+      ip("5.6.7.8"),
+    );
+    assert_eq!(
+      proxies(&["10.0.0.1"])
+        .forwarded(None, false, &headers(&[("x-forwarded-for", "5.6.7.8")]))
+        .client,
+      None,
+    );
+  }
+}
@@ -0,0 +1,246 @@
+use {
+  crate::{
+    common::*,
+    listeners::{Listen, Peer},
+  },
+  hyper::server::accept::Accept,
+  nix::unistd::{Group, User},
+  std::os::unix::fs::{FileTypeExt, PermissionsExt},
+  tokio::{
+    net::{UnixListener, UnixStream},
+    time::Sleep,
+  },
+};
+
+/// A listening Unix domain socket. The socket file is removed again when
+/// the socket is dropped, i.e. once the server shuts down.
+pub(crate) struct UnixSocket {
+  listener: UnixListener,
+  path: PathBuf,
+  backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl UnixSocket {
+  const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+  /// Bind the socket at `path`, apply `--socket-mode` and `--socket-owner`,
+  /// and log it as listening for `protocol` connections.
+  pub(crate) fn bind(
+    environment: &mut Environment,
+    arguments: &Arguments,
+    path: &Path,
+    protocol: &str,
+  ) -> Result<Self> {
+    let path = environment.working_directory.join(path);
+
+    Self::remove_stale(&path)?;
+
+    let socket = Self {
+      listener: UnixListener::bind(&path).context(error::UnixSocketIo { path: &path })?,
+      path,
+      backoff: None,
+    };
+
+    if let Some(mode) = arguments.socket_mode {
+      fs::set_permissions(&socket.path, fs::Permissions::from_mode(mode))
+        .context(error::UnixSocketIo { path: &socket.path })?;
+    }
+
+    if let Some(owner) = &arguments.socket_owner {
+      let (user, group) = Self::owner(owner)?;
+      std::os::unix::fs::chown(&socket.path, user, group)
+        .context(error::UnixSocketIo { path: &socket.path })?;
+    }
+
+    writeln!(
+      environment.stderr,
+      "Listening for {} connections on `{}`",
+      protocol,
+      socket.path.display(),
+    )
+    .context(error::StderrWrite)?;
+
+    Ok(socket)
+  }
+
+  /// Remove a socket left behind by a previous run that didn't shut down
+  /// cleanly. Anything but a socket that nobody is listening on is left
+  /// alone.
+  fn remove_stale(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+      Ok(metadata) => metadata,
+      Err(source) if source.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(source) => return Err(error::UnixSocketIo { path }.into_error(source)),
+    };
+
+    if !metadata.file_type().is_socket() {
+      return Err(error::UnixSocketNotSocket { path }.build());
+    }
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+      return Err(error::UnixSocketInUse { path }.build());
+    }
+
+    fs::remove_file(path).context(error::UnixSocketIo { path })
+  }
+
+  /// Resolve `--socket-owner`, given as `user`, `user:group` or `:group`,
+  /// to a user and group ID.
+  fn owner(owner: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match owner.split_once(':') {
+      Some((user, group)) => (user, Some(group)),
+      None => (owner, None),
+    };
+
+    let user = match user {
+      "" => None,
+      user => Some(match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => User::from_name(user)
+          .map_err(io::Error::from)
+          .context(error::SocketOwnerLookup { name: user })?
+          .ok_or_else(|| {
+            error::SocketOwnerUnknown {
+              kind: "user",
+              name: user,
+            }
+            .build()
+          })?
+          .uid
+          .as_raw(),
+      }),
+    };
+
+    let group = match group {
+      None | Some("") => None,
+      Some(group) => Some(match group.parse() {
+        Ok(gid) => gid,
+        Err(_) => Group::from_name(group)
+          .map_err(io::Error::from)
+          .context(error::SocketOwnerLookup { name: group })?
+          .ok_or_else(|| {
+            error::SocketOwnerUnknown {
+              kind: "group",
+              name: group,
+            }
+            .build()
+          })?
+          .gid
+          .as_raw(),
+      }),
+    };
+
+    Ok((user, group))
+  }
+}
+
+impl Drop for UnixSocket {
+  fn drop(&mut self) {
+    if let Err(source) = fs::remove_file(&self.path) {
+      log::warn!(
+        "{}",
+        error::UnixSocketIo { path: &self.path }.into_error(source)
+      );
+    }
+  }
+}
+
+impl Accept for UnixSocket {
+  type Conn = UnixStream;
+  type Error = io::Error;
+
+  fn poll_accept(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<io::Result<UnixStream>>> {
+    let socket = self.get_mut();
+    loop {
+      if let Some(backoff) = &mut socket.backoff {
+        futures::ready!(backoff.as_mut().poll(cx));
+        socket.backoff = None;
+      }
+
+      match futures::ready!(socket.listener.poll_accept(cx)) {
+        Ok((stream, _)) => return Poll::Ready(Some(Ok(stream))),
+        // Like hyper does for TCP, keep serving after errors like `EMFILE`,
+        // which usually clear up once other connections close.
+        Err(source) => {
+          log::error!("{}", socket.accept_error(source));
+          socket.backoff = Some(Box::pin(tokio::time::sleep(Self::ACCEPT_BACKOFF)));
+        }
+      }
+    }
+  }
+}
+
+impl Listen for UnixSocket {
+  type Stream = UnixStream;
+
+  async fn accept(&self) -> io::Result<(UnixStream, Peer)> {
+    let (stream, _) = self.listener.accept().await?;
+    Ok((stream, Peer::Unix(self.path.clone())))
+  }
+
+  fn accept_error(&self, source: io::Error) -> Error {
+    error::UnixSocketIo { path: &self.path }.into_error(source)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn owner() {
+    assert_eq!(UnixSocket::owner("0").unwrap(), (Some(0), None));
+    assert_eq!(UnixSocket::owner("root").unwrap(), (Some(0), None));
+    assert_eq!(UnixSocket::owner("1:2").unwrap(), (Some(1), Some(2)));
+    assert_eq!(UnixSocket::owner(":0").unwrap(), (None, Some(0)));
+    assert_eq!(UnixSocket::owner("root:").unwrap(), (Some(0), None));
+  }
+
+  #[test]
+  fn unknown_owner() {
+    assert_matches!(
+      UnixSocket::owner("opuza-no-such-user").unwrap_err(),
+      Error::SocketOwnerUnknown { kind: "user", name, .. } if name == "opuza-no-such-user"
+    );
+    assert_matches!(
+      UnixSocket::owner(":opuza-no-such-group").unwrap_err(),
+      Error::SocketOwnerUnknown { kind: "group", name, .. } if name == "opuza-no-such-group"
+    );
+  }
+
+  #[test]
+  fn stale_sockets_are_removed() {
+    let tempdir = TempDir::new().unwrap();
+    let path = tempdir.path().join("socket");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    UnixSocket::remove_stale(&path).unwrap();
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn sockets_in_use_are_not_removed() {
+    let tempdir = TempDir::new().unwrap();
+    let path = tempdir.path().join("socket");
+    let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    assert_matches!(
+      UnixSocket::remove_stale(&path).unwrap_err(),
+      Error::UnixSocketInUse { .. }
+    );
+    assert!(path.exists());
+  }
+
+  #[test]
+  fn other_files_are_not_removed() {
+    let tempdir = TempDir::new().unwrap();
+    let path = tempdir.path().join("socket");
+    fs::write(&path, "").unwrap();
+    assert_matches!(
+      UnixSocket::remove_stale(&path).unwrap_err(),
+      Error::UnixSocketNotSocket { .. }
+    );
+    assert!(path.exists());
+  }
+}
@@ -1,7 +1,19 @@
-use crate::common::*;
+use {
+  crate::{
+    bandwidth::BandwidthLimits,
+    common::*,
+    locale::Locale,
+    page::{Cursor, Page},
+    sort::{Sort, SortConfig, SortKey},
+    thumbnails::Variant,
+  },
+  mime_guess::Mime,
+  std::time::SystemTime,
+};
 mod config;
 
 use config::Config;
+pub(crate) use config::View;
 
 #[derive(Debug, Clone)]
 pub(crate) struct Vfs {
@@ -46,6 +58,59 @@ impl Vfs {
     Ok(self.config(path)?.base_price)
   }
 
+  pub(crate) fn bandwidth_limits(&self, path: &InputPath, paid: bool) -> Result<BandwidthLimits> {
+    self.check_path(path)?;
+    let config = self.config(path)?;
+    Ok(if paid {
+      config.bandwidth_limit.paid
+    } else {
+      config.bandwidth_limit.free
+    })
+  }
+
+  /// Free bandwidth limits configured for the directory `dir` itself, which
+  /// apply to archive downloads of it.
+  pub(crate) fn dir_bandwidth_limits(&self, dir: &InputPath) -> Result<BandwidthLimits> {
+    self.check_path(dir)?;
+    Ok(
+      Config::for_dir(self.base_directory.as_ref(), dir.as_ref())?
+        .bandwidth_limit
+        .free,
+    )
+  }
+
+  /// How the directory `dir` itself is listed.
+  pub(crate) fn listing_config(&self, dir: &InputPath) -> Result<ListingConfig> {
+    self.check_path(dir)?;
+    let config = Config::for_dir(self.base_directory.as_ref(), dir.as_ref())?;
+    Ok(ListingConfig {
+      sort: config.sort,
+      page_size: config.page_size(),
+      view: config.view(),
+      lang: config.lang,
+    })
+  }
+
+  /// The default locale of pages about the file at `path`.
+  pub(crate) fn lang(&self, path: &InputPath) -> Result<Option<Locale>> {
+    self.check_path(path)?;
+    Ok(self.config(path)?.lang)
+  }
+
+  /// Which thumbnail to show of the image at `path`. Paid images only get a
+  /// low-resolution preview.
+  pub(crate) fn thumbnail_variant(&self, path: &InputPath) -> Result<Variant> {
+    self.check_path(path)?;
+    let config = self.config(path)?;
+    Ok(if config.paid() {
+      Variant::Preview {
+        watermark: config.preview_watermark(),
+      }
+    } else {
+      Variant::Full
+    })
+  }
+
   pub(crate) fn file_path(&self, path: &str) -> Result<InputPath> {
     self.base_directory.join_file_path(path)
   }
@@ -109,45 +174,235 @@ impl Vfs {
     Ok(())
   }
 
+  /// Read every entry of the directory `path`, sorted by file name.
   pub(crate) async fn read_dir(&self, path: &InputPath) -> Result<Vec<DirEntry>> {
-    let mut read_dir = tokio::fs::read_dir(path)
+    let path = path.clone();
+    self
+      .blocking(move |vfs| {
+        let config = Config::for_dir(vfs.base_directory.as_ref(), path.as_ref())?;
+        let mut entries = vfs
+          .scan_dir(&path)?
+          .into_iter()
+          .map(|entry| {
+            let entry_path = path.join_relative(Path::new(&entry.file_name))?;
+            vfs.stat(&config, &entry_path, entry)
+          })
+          .collect::<Result<Vec<DirEntry>>>()?;
+        entries.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        Ok(entries)
+      })
       .await
-      .with_context(|| Error::filesystem_io(path))?;
-    let mut entries = Vec::new();
-    while let Some(entry) = read_dir
-      .next_entry()
+  }
+
+  /// Read one page of the directory `path`, sorted by `sort`. When sorting
+  /// by name, only the entries on the page are stat-ed.
+  pub(crate) async fn read_dir_page(
+    &self,
+    path: &InputPath,
+    sort: Sort,
+    cursor: Cursor,
+    page_size: usize,
+  ) -> Result<Page<DirEntry>> {
+    let path = path.clone();
+    self
+      .blocking(move |vfs| {
+        let config = Config::for_dir(vfs.base_directory.as_ref(), path.as_ref())?;
+        let stat = |entry: DirEntry| {
+          let entry_path = path.join_relative(Path::new(&entry.file_name))?;
+          vfs.stat(&config, &entry_path, entry)
+        };
+        let name = |entry: &DirEntry| entry.file_name.to_string_lossy().into_owned();
+        let mut entries = vfs.scan_dir(&path)?;
+        if sort.key == SortKey::Name {
+          sort.sort(&mut entries);
+          Page::new(entries, &cursor, page_size, name).try_map(stat)
+        } else {
+          let mut entries = entries
+            .into_iter()
+            .map(stat)
+            .collect::<Result<Vec<DirEntry>>>()?;
+          sort.sort(&mut entries);
+          Ok(Page::new(entries, &cursor, page_size, name))
+        }
+      })
       .await
+  }
+
+  /// Look up the listing entry for a single file or directory.
+  pub(crate) fn entry(&self, path: &InputPath) -> Result<DirEntry> {
+    self.check_path(path)?;
+    let file_type = path
+      .as_ref()
+      .symlink_metadata()
       .with_context(|| Error::filesystem_io(path))?
-    {
+      .file_type();
+    self.stat(
+      &self.config(path)?,
+      path,
+      DirEntry::new(
+        path.as_ref().file_name().unwrap_or_default().to_owned(),
+        file_type,
+      ),
+    )
+  }
+
+  /// Run `f`, which may block on filesystem access, on a blocking thread.
+  async fn blocking<T: Send + 'static>(
+    &self,
+    f: impl FnOnce(Vfs) -> Result<T> + Send + 'static,
+  ) -> Result<T> {
+    let vfs = self.clone();
+    tokio::task::spawn_blocking(move || f(vfs))
+      .await
+      .map_err(|error| Error::internal(format!("Directory scan failed: {}", error)))?
+  }
+
+  /// List the names and file types of the entries of the directory `path`,
+  /// skipping hidden files and escaping symlinks.
+  fn scan_dir(&self, path: &InputPath) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| Error::filesystem_io(path))? {
+      let entry = entry.with_context(|| Error::filesystem_io(path))?;
       let input_path = path.join_relative(Path::new(&entry.file_name()))?;
       if self.check_path(&input_path).is_err() {
         continue;
       }
-      let metadata = entry
-        .metadata()
-        .await
+      let file_type = entry
+        .file_type()
         .with_context(|| Error::filesystem_io(&input_path))?;
-      let file_type = metadata.file_type();
-      let file_size = if metadata.is_dir() {
-        None
-      } else {
-        Some(metadata.len())
+      entries.push(DirEntry::new(entry.file_name(), file_type));
+    }
+    Ok(entries)
+  }
+
+  /// Fill in the metadata of `entry`, found at `path`, in a directory whose
+  /// configuration is `config`.
+  fn stat(&self, config: &Config, path: &InputPath, mut entry: DirEntry) -> Result<DirEntry> {
+    let metadata = path
+      .as_ref()
+      .symlink_metadata()
+      .with_context(|| Error::filesystem_io(path))?;
+    if !metadata.is_dir() {
+      entry.file_size = Some(metadata.len());
+      entry.mime_type = path.mime_guess().first();
+    }
+    entry.modified = metadata.modified().ok();
+    entry.paid = config.paid();
+    if entry.paid && !metadata.is_dir() {
+      entry.price = config.base_price;
+    }
+    Ok(entry)
+  }
+
+  /// Recursively collect the files and directories below `path`. Hidden
+  /// files, escaping symlinks and entries that can't be read, like dangling
+  /// symlinks, are skipped, and directories reachable through more than one
+  /// local symlink are only visited once.
+  pub(crate) async fn walk(&self, path: &InputPath) -> Result<Vec<WalkEntry>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(String::new(), path.clone())];
+
+    while let Some((prefix, dir)) = pending.pop() {
+      let canonical = match tokio::fs::canonicalize(&dir).await {
+        Ok(canonical) => canonical,
+        Err(source) => {
+          log::warn!(
+            "Skipping unreadable entry: {}",
+            Error::filesystem_io(&dir).into_error(source)
+          );
+          continue;
+        }
       };
-      entries.push(DirEntry {
-        file_name: entry.file_name(),
-        file_type,
-        file_size,
-        paid: self.paid(&path.join_relative(entry.file_name().as_ref())?)?,
-      });
+      if !visited.insert(canonical) {
+        continue;
+      }
+
+      for entry in self.read_dir(&dir).await? {
+        let input_path = dir.join_relative(Path::new(&entry.file_name))?;
+        let mut name = format!("{}{}", prefix, entry.file_name.to_string_lossy());
+        let metadata = match tokio::fs::metadata(&input_path).await {
+          Ok(metadata) => metadata,
+          Err(source) => {
+            log::warn!(
+              "Skipping unreadable entry: {}",
+              Error::filesystem_io(&input_path).into_error(source)
+            );
+            continue;
+          }
+        };
+        if metadata.is_dir() {
+          name.push('/');
+          pending.push((name.clone(), input_path.clone()));
+        }
+        entries.push(WalkEntry {
+          path: name,
+          input_path,
+          metadata,
+          entry,
+        });
+      }
     }
-    entries.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
     Ok(entries)
   }
+
+  /// Recursively collect the free files below `path`, paired with their
+  /// `/`-separated path relative to `path`. Paid files, hidden files and
+  /// escaping symlinks are skipped, and directories reachable through more
+  /// than one local symlink are only visited once.
+  pub(crate) async fn free_files(&self, path: &InputPath) -> Result<Vec<(String, InputPath)>> {
+    Ok(
+      self
+        .walk(path)
+        .await?
+        .into_iter()
+        .filter(|walk_entry| walk_entry.metadata.is_file() && !walk_entry.entry.paid)
+        .map(|walk_entry| (walk_entry.path, walk_entry.input_path))
+        .collect(),
+    )
+  }
+}
+
+pub(crate) struct ListingConfig {
+  pub(crate) sort: SortConfig,
+  pub(crate) page_size: usize,
+  pub(crate) view: View,
+  pub(crate) lang: Option<Locale>,
+}
+
+/// A file or directory found by `Vfs::walk`.
+pub(crate) struct WalkEntry {
+  /// The `/`-separated path relative to the walked directory, ending with a
+  /// `/` for directories.
+  pub(crate) path: String,
+  pub(crate) input_path: InputPath,
+  /// Metadata of the file or directory, with symlinks followed.
+  pub(crate) metadata: fs::Metadata,
+  pub(crate) entry: DirEntry,
 }
 
 pub(crate) struct DirEntry {
   pub(crate) file_name: OsString,
   pub(crate) file_type: FileType,
   pub(crate) file_size: Option<u64>,
+  pub(crate) modified: Option<SystemTime>,
+  pub(crate) mime_type: Option<Mime>,
   pub(crate) paid: bool,
+  pub(crate) price: Option<Piconero>,
+}
+
+impl DirEntry {
+  fn new(file_name: OsString, file_type: FileType) -> Self {
+    Self {
+      file_name,
+      file_type,
+      file_size: None,
+      modified: None,
+      mime_type: None,
+      paid: false,
+      price: None,
+    }
+  }
 }
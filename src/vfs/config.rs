@@ -1,17 +1,57 @@
-use crate::common::*;
+use {
+  crate::{bandwidth::BandwidthLimits, common::*, locale::Locale, sort::SortConfig},
+  std::num::NonZeroUsize,
+};
 
 #[derive(PartialEq, Debug, Default, Deserialize)]
 #[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub(crate) struct Config {
   paid: Option<bool>,
   pub(super) base_price: Option<Piconero>,
+  pub(super) bandwidth_limit: BandwidthLimitConfig,
+  pub(super) sort: SortConfig,
+  page_size: Option<NonZeroUsize>,
+  view: Option<View>,
+  preview_watermark: Option<bool>,
+  pub(super) lang: Option<Locale>,
+}
+
+/// How a directory listing is displayed.
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum View {
+  List,
+  Gallery,
+}
+
+#[derive(PartialEq, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BandwidthLimitConfig {
+  pub(super) free: BandwidthLimits,
+  pub(super) paid: BandwidthLimits,
 }
 
 impl Config {
+  const DEFAULT_PAGE_SIZE: usize = 1000;
+
   pub(super) fn paid(&self) -> bool {
     self.paid.unwrap_or(false)
   }
 
+  pub(super) fn page_size(&self) -> usize {
+    self
+      .page_size
+      .map_or(Self::DEFAULT_PAGE_SIZE, NonZeroUsize::get)
+  }
+
+  pub(super) fn view(&self) -> View {
+    self.view.unwrap_or(View::List)
+  }
+
+  pub(super) fn preview_watermark(&self) -> bool {
+    self.preview_watermark.unwrap_or(false)
+  }
+
   pub(super) fn for_dir(base_directory: &Path, path: &Path) -> Result<Self> {
     if !path.starts_with(base_directory) {
       return Err(Error::internal(format!(
@@ -44,6 +84,21 @@ impl Config {
     *self = Self {
       paid: self.paid.or(parent.paid),
       base_price: self.base_price.or(parent.base_price),
+      bandwidth_limit: BandwidthLimitConfig {
+        free: self
+          .bandwidth_limit
+          .free
+          .merge_parent(parent.bandwidth_limit.free),
+        paid: self
+          .bandwidth_limit
+          .paid
+          .merge_parent(parent.bandwidth_limit.paid),
+      },
+      sort: self.sort.merge_parent(parent.sort),
+      page_size: self.page_size.or(parent.page_size),
+      view: self.view.or(parent.view),
+      preview_watermark: self.preview_watermark.or(parent.preview_watermark),
+      lang: self.lang.or(parent.lang),
     };
   }
 }
@@ -51,6 +106,10 @@ impl Config {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::{
+    bandwidth::Bandwidth,
+    sort::{SortKey, SortOrder},
+  };
   use pretty_assertions::assert_eq;
   use unindent::Unindent;
 
@@ -59,7 +118,16 @@ mod tests {
     assert_eq!(
       Config {
         paid: None,
-        base_price: None
+        base_price: None,
+        bandwidth_limit: BandwidthLimitConfig {
+          free: BandwidthLimits::default(),
+          paid: BandwidthLimits::default(),
+        },
+        sort: SortConfig::default(),
+        page_size: None,
+        view: None,
+        preview_watermark: None,
+        lang: None,
       },
       Config::default()
     );
@@ -81,7 +149,8 @@ mod tests {
       config,
       Config {
         paid: Some(true),
-        base_price: None
+        base_price: None,
+        ..Config::default()
       }
     );
   }
@@ -169,7 +238,8 @@ mod tests {
       config,
       Config {
         paid: Some(true),
-        base_price: Some(Piconero::new(1_500_000_000_000))
+        base_price: Some(Piconero::new(1_500_000_000_000)),
+        ..Config::default()
       }
     );
   }
@@ -189,7 +259,8 @@ mod tests {
       config,
       Config {
         paid: Some(false),
-        base_price: Some(Piconero::ONE_XMR)
+        base_price: Some(Piconero::ONE_XMR),
+        ..Config::default()
       }
     );
   }
@@ -209,11 +280,142 @@ mod tests {
       config,
       Config {
         paid: Some(true),
-        base_price: Some(Piconero::ONE_XMR)
+        base_price: Some(Piconero::ONE_XMR),
+        ..Config::default()
+      }
+    );
+  }
+
+  #[test]
+  fn parses_bandwidth_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let yaml = "
+      bandwidth-limit:
+        free:
+          connection: 100 KiB
+          global: 1 MiB
+        paid:
+          client: 10 MiB
+    "
+    .unindent();
+    fs::write(temp_dir.path().join(".opuza.yaml"), yaml).unwrap();
+    let config = Config::for_dir(temp_dir.path(), temp_dir.path()).unwrap();
+    assert_eq!(
+      config.bandwidth_limit,
+      BandwidthLimitConfig {
+        free: BandwidthLimits {
+          connection: Some(Bandwidth::new(100 * 1024)),
+          client: None,
+          global: Some(Bandwidth::new(1024 * 1024)),
+        },
+        paid: BandwidthLimits {
+          connection: None,
+          client: Some(Bandwidth::new(10 * 1024 * 1024)),
+          global: None,
+        },
+      }
+    );
+  }
+
+  #[test]
+  fn inherits_bandwidth_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+      temp_dir.path().join(".opuza.yaml"),
+      "bandwidth-limit: {free: {connection: 1 MiB, global: 10 MiB}}",
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("dir")).unwrap();
+    fs::write(
+      temp_dir.path().join("dir/.opuza.yaml"),
+      "bandwidth-limit: {free: {connection: 2 MiB}}",
+    )
+    .unwrap();
+    let config = Config::for_dir(temp_dir.path(), &temp_dir.path().join("dir")).unwrap();
+    assert_eq!(
+      config.bandwidth_limit.free,
+      BandwidthLimits {
+        connection: Some(Bandwidth::new(2 * 1024 * 1024)),
+        client: None,
+        global: Some(Bandwidth::new(10 * 1024 * 1024)),
       }
     );
   }
 
+  #[test]
+  fn parses_and_inherits_sort() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+      temp_dir.path().join(".opuza.yaml"),
+      "sort: {by: mtime, order: desc}",
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("dir")).unwrap();
+    fs::write(temp_dir.path().join("dir/.opuza.yaml"), "sort: {by: size}").unwrap();
+    let config = Config::for_dir(temp_dir.path(), &temp_dir.path().join("dir")).unwrap();
+    assert_eq!(
+      config.sort,
+      SortConfig {
+        by: Some(SortKey::Size),
+        order: Some(SortOrder::Desc),
+      }
+    );
+  }
+
+  #[test]
+  fn parses_and_inherits_page_size() {
+    let temp_dir = TempDir::new().unwrap();
+    assert_eq!(
+      Config::for_dir(temp_dir.path(), temp_dir.path())
+        .unwrap()
+        .page_size(),
+      1000
+    );
+    fs::write(temp_dir.path().join(".opuza.yaml"), "page-size: 50").unwrap();
+    fs::create_dir(temp_dir.path().join("dir")).unwrap();
+    let config = Config::for_dir(temp_dir.path(), &temp_dir.path().join("dir")).unwrap();
+    assert_eq!(config.page_size(), 50);
+  }
+
+  #[test]
+  fn parses_and_inherits_view() {
+    let temp_dir = TempDir::new().unwrap();
+    assert_eq!(
+      Config::for_dir(temp_dir.path(), temp_dir.path())
+        .unwrap()
+        .view(),
+      View::List
+    );
+    fs::write(
+      temp_dir.path().join(".opuza.yaml"),
+      "{view: gallery, preview-watermark: true}",
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("dir")).unwrap();
+    let config = Config::for_dir(temp_dir.path(), &temp_dir.path().join("dir")).unwrap();
+    assert_eq!(config.view(), View::Gallery);
+    assert!(config.preview_watermark());
+  }
+
+  #[test]
+  fn parses_and_inherits_lang() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".opuza.yaml"), "lang: de").unwrap();
+    fs::create_dir(temp_dir.path().join("dir")).unwrap();
+    let config = Config::for_dir(temp_dir.path(), &temp_dir.path().join("dir")).unwrap();
+    assert_eq!(config.lang, Some(Locale::De));
+  }
+
+  #[test]
+  fn page_size_must_not_be_zero() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".opuza.yaml"), "page-size: 0").unwrap();
+    assert_matches!(
+      Config::for_dir(temp_dir.path(), temp_dir.path()),
+      Err(Error::ConfigDeserialize { .. })
+    );
+  }
+
   #[test]
   fn does_not_read_configs_in_subdirectories() {
     let temp_dir = TempDir::new().unwrap();
@@ -233,7 +435,8 @@ mod tests {
       config,
       Config {
         paid: Some(true),
-        base_price: Some(Piconero::new(1_000_000_000))
+        base_price: Some(Piconero::new(1_000_000_000)),
+        ..Config::default()
       }
     );
   }
@@ -258,7 +461,8 @@ mod tests {
       config,
       Config {
         paid: Some(true),
-        base_price: Some(Piconero::ONE_XMR)
+        base_price: Some(Piconero::ONE_XMR),
+        ..Config::default()
       }
     );
   }
@@ -279,7 +483,8 @@ mod tests {
       config,
       Config {
         paid: None,
-        base_price: None
+        base_price: None,
+        ..Config::default()
       }
     );
     let config = Config::for_dir(
@@ -291,7 +496,8 @@ mod tests {
       config,
       Config {
         paid: None,
-        base_price: None
+        base_price: None,
+        ..Config::default()
       }
     );
   }
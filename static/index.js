@@ -3,5 +3,8 @@ if (location.protocol === 'https:') {
 
   for (let element of elements) {
     element.classList.add("enabled");
+    element.addEventListener("click", () => {
+      navigator.clipboard.writeText(element.dataset.clipboardText);
+    });
   }
 }
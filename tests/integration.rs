@@ -1,3 +1,5 @@
+#![allow(clippy::diverging_sub_expression)]
+
 use {
   executable_path::executable_path,
   guard::guard_unwrap,
@@ -7,12 +9,14 @@ use {
   scraper::{ElementRef, Html, Selector},
   std::{
     fs,
-    io::{Read, Write},
+    io::{BufRead, BufReader, Read, Write},
     net::TcpListener,
     path::{Path, MAIN_SEPARATOR},
-    process::Command,
-    str, thread,
-    time::Duration,
+    process::{Child, Command, Stdio},
+    str,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime},
   },
 };
 
@@ -72,6 +76,125 @@ fn server_listens_on_all_ip_addresses_https() {
   )));
 }
 
+#[test]
+fn server_listens_on_every_address() {
+  let context = OpuzaTestContext::builder()
+    .address(None)
+    .args(&["--address=127.0.0.1", "--address=::1"])
+    .build();
+  let port = context.port();
+  for host in ["127.0.0.1", "[::1]"] {
+    let response = reqwest::blocking::get(format!("http://{}:{}/files/", host, port)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+  let stderr = context.kill();
+  assert_contains(
+    &stderr,
+    &format!("Listening for HTTP connections on `127.0.0.1:{}`", port),
+  );
+  assert_contains(
+    &stderr,
+    &format!("Listening for HTTP connections on `[::1]:{}`", port),
+  );
+}
+
+/// Send an HTTP/1.0 request, so that the response isn't chunked, over the
+/// Unix domain socket at `socket`, retrying until the server listens on it.
+#[cfg(unix)]
+fn unix_socket_get(socket: &Path, uri: &str) -> String {
+  let mut attempts = 0;
+  let mut stream = loop {
+    match std::os::unix::net::UnixStream::connect(socket) {
+      Ok(stream) => break stream,
+      Err(error) if attempts < 100 => {
+        attempts += 1;
+        eprintln!("Failed to connect to {}: {}", socket.display(), error);
+        thread::sleep(Duration::from_millis(50));
+      }
+      Err(error) => panic!("Failed to connect to {}: {}", socket.display(), error),
+    }
+  };
+  write!(stream, "GET {} HTTP/1.0\r\nhost: localhost\r\n\r\n", uri).unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).unwrap();
+  response
+}
+
+#[test]
+#[cfg(unix)]
+fn server_listens_on_unix_socket() {
+  use std::os::unix::fs::PermissionsExt;
+
+  let tempdir = tempfile::tempdir().unwrap();
+  let socket = tempdir.path().join("opuza.sock");
+  let context = OpuzaTestContext::builder()
+    .args(&[
+      &format!("--http-socket={}", socket.display()),
+      "--socket-mode=600",
+    ])
+    .build();
+  context.write("foo", "bar");
+
+  let response = unix_socket_get(&socket, "/files/foo");
+  assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+  assert!(response.ends_with("\r\n\r\nbar"), "{}", response);
+  assert_eq!(
+    fs::metadata(&socket).unwrap().permissions().mode() & 0o777,
+    0o600
+  );
+
+  let (status, stderr) = terminate(context);
+  assert!(status.success(), "{}", stderr);
+  assert_contains(
+    &stderr,
+    &format!("Listening for HTTP connections on `{}`", socket.display()),
+  );
+  assert!(!socket.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn stale_unix_sockets_are_replaced() {
+  let tempdir = tempfile::tempdir().unwrap();
+  let socket = tempdir.path().join("opuza.sock");
+  drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+  let context = OpuzaTestContext::builder()
+    .args(&[&format!("--http-socket={}", socket.display())])
+    .build();
+
+  let response = unix_socket_get(&socket, "/files/");
+  assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+  context.kill();
+}
+
+#[test]
+#[cfg(unix)]
+fn unix_sockets_in_use_are_not_replaced() {
+  let tempdir = tempfile::tempdir().unwrap();
+  let socket = tempdir.path().join("opuza.sock");
+  let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+  let output = Command::new(executable_path("opuza"))
+    .arg("--directory")
+    .arg(tempdir.path())
+    .arg("--http-socket")
+    .arg(&socket)
+    .env("OPUZA_SUPPRESS_BACKTRACE", "")
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert_contains(
+    str::from_utf8(&output.stderr).unwrap(),
+    &format!(
+      "Unix domain socket `{}` is already in use by another process",
+      socket.display()
+    ),
+  );
+  assert!(socket.exists());
+}
+
 #[test]
 fn index_route_status_code_is_200() {
   let context = OpuzaTestContext::builder().build();
@@ -647,10 +770,68 @@ fn paid_files_dont_have_download_button() {
   context.write("foo", "foo");
   let html = context.html("files/");
   guard_unwrap!(let &[] = css_select(&html, ".listing a[download]").as_slice());
-  guard_unwrap!(let &[link] = css_select(&html, ".listing a:not([download])").as_slice());
+  guard_unwrap!(let &[link] = css_select(&html, ".listing a.view").as_slice());
   assert_eq!(link.inner_html(), "foo");
 }
 
+#[test]
+fn paid_files_show_price_badge_and_buy_link() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "{paid: true, base-price: 10 XMR}");
+  context.write("foo", "foo");
+  let html = context.html("files/");
+  guard_unwrap!(let &[price] = css_select(&html, ".listing .price").as_slice());
+  assert_eq!(price.inner_html(), "10 XMR");
+  guard_unwrap!(let &[badge] = css_select(&html, ".listing .paid").as_slice());
+  assert_eq!(badge.inner_html(), "paid");
+  guard_unwrap!(let &[buy] = css_select(&html, ".listing a.buy").as_slice());
+  assert_eq!(buy.value().attr("href").unwrap(), "foo");
+}
+
+#[test]
+fn free_files_have_no_price_badge_or_buy_link() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo", "foo");
+  context.create_dir_all("bar");
+  let html = context.html("files/");
+  assert!(css_select(&html, ".listing .price").is_empty());
+  assert!(css_select(&html, ".listing .paid").is_empty());
+  assert!(css_select(&html, ".listing a.buy").is_empty());
+}
+
+#[test]
+fn paid_file_names_link_to_preview_page() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "{paid: true, base-price: 10 XMR}");
+  context.write("foo.txt", "foo");
+  let html = context.html("files/");
+  guard_unwrap!(let &[link] = css_select(&html, ".listing a.view").as_slice());
+  assert_eq!(link.value().attr("href").unwrap(), "foo.txt?preview");
+
+  // No invoice is created for the preview page, so it works without a wallet.
+  let html = context.html("files/foo.txt?preview");
+  guard_unwrap!(let &[price] = css_select(&html, ".preview dd.price").as_slice());
+  assert_contains(&price.inner_html(), "10 XMR");
+  guard_unwrap!(let &[size] = css_select(&html, ".preview dd.filesize").as_slice());
+  assert_eq!(size.inner_html(), "3 B");
+  guard_unwrap!(let &[mime_type] = css_select(&html, ".preview dd.mime-type").as_slice());
+  assert_eq!(mime_type.inner_html(), "text/plain");
+  guard_unwrap!(let &[buy] = css_select(&html, ".preview a.buy").as_slice());
+  assert_eq!(buy.value().attr("href").unwrap(), "foo.txt");
+  assert!(css_select(&html, ".preview a[download]").is_empty());
+}
+
+#[test]
+fn free_files_preview_page_has_download_link() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo.txt", "foo");
+  let html = context.html("files/foo.txt?preview");
+  assert!(css_select(&html, ".preview .price").is_empty());
+  assert!(css_select(&html, ".preview a.buy").is_empty());
+  guard_unwrap!(let &[download] = css_select(&html, ".preview a[download]").as_slice());
+  assert_eq!(download.value().attr("href").unwrap(), "foo.txt");
+}
+
 #[test]
 fn filenames_with_percent_encoded_characters() {
   let context = OpuzaTestContext::builder().build();
@@ -872,6 +1053,377 @@ fn listing_does_not_render_directory_file_sizes() {
   assert_not_contains(&li.inner_html(), "B");
 }
 
+fn listing_names(context: &OpuzaTestContext, url: &str) -> Vec<String> {
+  let html = context.html(url);
+  css_select(&html, ".listing a.view")
+    .into_iter()
+    .map(|a| a.inner_html())
+    .collect()
+}
+
+fn set_modified(path: &Path, seconds: u64) {
+  fs::File::options()
+    .write(true)
+    .open(path)
+    .unwrap()
+    .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+    .unwrap();
+}
+
+#[test]
+fn listing_sorts_directories_first_and_names_naturally() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("file10", "");
+  context.write("File2", "");
+  context.write("a.txt", "");
+  context.create_dir_all("b");
+  assert_eq!(
+    listing_names(&context, "files/"),
+    ["b/", "a.txt", "File2", "file10"]
+  );
+  assert_eq!(
+    listing_names(&context, "files/?order=desc"),
+    ["b/", "file10", "File2", "a.txt"]
+  );
+}
+
+#[test]
+fn listing_can_be_sorted_by_size() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("a", "aaa");
+  context.write("b", "b");
+  context.write("c", "cc");
+  assert_eq!(
+    listing_names(&context, "files/?sort=size&order=desc"),
+    ["a", "c", "b"]
+  );
+  assert_eq!(listing_names(&context, "files/?sort=size"), ["b", "c", "a"]);
+}
+
+#[test]
+fn listing_can_be_sorted_by_modification_time() {
+  let context = OpuzaTestContext::builder().build();
+  set_modified(&context.write("a", ""), 300);
+  set_modified(&context.write("b", ""), 100);
+  set_modified(&context.write("c", ""), 200);
+  assert_eq!(
+    listing_names(&context, "files/?sort=mtime"),
+    ["b", "c", "a"]
+  );
+  assert_eq!(
+    listing_names(&context, "files/?sort=mtime&order=desc"),
+    ["a", "c", "b"]
+  );
+}
+
+#[test]
+fn listing_uses_sort_order_from_config() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "sort: {by: size, order: desc}");
+  context.write("a", "a");
+  context.write("b", "bb");
+  assert_eq!(listing_names(&context, "files/"), ["b", "a"]);
+  assert_eq!(listing_names(&context, "files/?sort=name"), ["b", "a"]);
+  assert_eq!(
+    listing_names(&context, "files/?sort=name&order=asc"),
+    ["a", "b"]
+  );
+}
+
+#[test]
+fn invalid_sort_parameters_are_rejected() {
+  let context = OpuzaTestContext::builder().build();
+  assert_eq!(context.status("files/?sort=color"), StatusCode::BAD_REQUEST);
+  assert_eq!(context.status("files/?order=up"), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn listing_links_to_sort_orders() {
+  let context = OpuzaTestContext::builder().build();
+  let html = context.html("files/?sort=size");
+  let links = css_select(&html, ".sort a")
+    .into_iter()
+    .map(|a| a.value().attr("href").unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    links,
+    [
+      "?sort=name&order=asc",
+      "?sort=size&order=desc",
+      "?sort=mtime&order=asc",
+      "?sort=price&order=asc",
+    ]
+  );
+  guard_unwrap!(let &[active] = css_select(&html, ".sort a.active").as_slice());
+  assert_eq!(active.inner_html(), "Size ↑");
+}
+
+#[test]
+fn listing_renders_modification_times() {
+  let context = OpuzaTestContext::builder().build();
+  set_modified(&context.write("foo", ""), 1_700_000_000);
+  let html = context.html("files/");
+  guard_unwrap!(let &[modified] = css_select(&html, ".listing .modified").as_slice());
+  assert_eq!(modified.inner_html(), "2023-11-14 22:13");
+}
+
+fn page_link(html: &Html, rel: &str) -> Option<String> {
+  css_select(html, &format!(".pages a[rel={}]", rel))
+    .first()
+    .map(|a| a.value().attr("href").unwrap().to_owned())
+}
+
+#[test]
+fn listings_are_paginated() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "page-size: 2");
+  for name in ["a", "b", "c", "d", "e"] {
+    context.write(name, "");
+  }
+
+  let html = context.html("files/");
+  assert_eq!(page_link(&html, "prev"), None);
+  let next = page_link(&html, "next").unwrap();
+  assert_eq!(next, "?after=b");
+  assert_eq!(listing_names(&context, "files/"), ["a", "b"]);
+
+  let html = context.html(format!("files/{}", next));
+  assert_eq!(
+    listing_names(&context, &format!("files/{}", next)),
+    ["c", "d"]
+  );
+  let previous = page_link(&html, "prev").unwrap();
+  assert_eq!(previous, "?before=c");
+  let next = page_link(&html, "next").unwrap();
+  assert_eq!(next, "?after=d");
+
+  assert_eq!(
+    listing_names(&context, &format!("files/{}", previous)),
+    ["a", "b"]
+  );
+
+  let html = context.html(format!("files/{}", next));
+  assert_eq!(listing_names(&context, &format!("files/{}", next)), ["e"]);
+  assert_eq!(page_link(&html, "next"), None);
+  assert_eq!(page_link(&html, "prev").unwrap(), "?before=e");
+}
+
+#[test]
+fn pagination_follows_sort_order() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "page-size: 2");
+  context.write("a", "aaa");
+  context.write("b", "b");
+  context.write("c", "cc");
+  let html = context.html("files/?sort=size&order=desc");
+  let next = page_link(&html, "next").unwrap();
+  assert_eq!(next, "?sort=size&order=desc&after=c");
+  assert_eq!(listing_names(&context, &format!("files/{}", next)), ["b"]);
+}
+
+#[test]
+fn unpaginated_listings_have_no_page_links() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("a", "");
+  let html = context.html("files/");
+  assert!(css_select(&html, ".pages").is_empty());
+}
+
+#[test]
+fn json_listings_are_paginated() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "page-size: 1");
+  context.write("a", "");
+  context.write("b", "");
+  let listing = json_listing(&context, "files/?format=json&after=a");
+  assert_eq!(listing["entries"].as_array().unwrap().len(), 1);
+  assert_eq!(listing["entries"][0]["name"], "b");
+  assert_eq!(listing["previous"], "?format=json&before=b");
+  assert_eq!(listing["next"], serde_json::Value::Null);
+}
+
+fn search_results(context: &OpuzaTestContext, query: &str) -> Vec<String> {
+  let html = context.html(format!("search?{}", query));
+  css_select(&html, ".listing a.view")
+    .into_iter()
+    .map(|a| a.inner_html())
+    .collect()
+}
+
+#[test]
+fn search_matches_file_names_and_path_segments() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("music/song.mp3", "");
+  context.write("music/live/encore.mp3", "");
+  context.write("docs/song-lyrics.txt", "");
+  assert_eq!(
+    search_results(&context, "q=song"),
+    ["/docs/song-lyrics.txt", "/music/song.mp3"]
+  );
+  assert_eq!(
+    search_results(&context, "q=MUSIC+mp3"),
+    ["/music/live/encore.mp3", "/music/song.mp3"]
+  );
+  assert_eq!(
+    search_results(&context, "q=live"),
+    ["/music/live/", "/music/live/encore.mp3"]
+  );
+  assert!(search_results(&context, "q=nothing").is_empty());
+  assert!(search_results(&context, "q=").is_empty());
+}
+
+#[test]
+fn search_results_link_to_files_and_show_size_and_price() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("paid/.opuza.yaml", "{paid: true, base-price: 3 XMR}");
+  context.write("paid/foo bar.txt", "abc");
+  let html = context.html("search?q=foo");
+  guard_unwrap!(let &[li] = css_select(&html, ".listing li").as_slice());
+  guard_unwrap!(let &[link] = css_select(&html, ".listing a.view").as_slice());
+  assert_eq!(
+    link.value().attr("href").unwrap(),
+    "/files/paid/foo%20bar.txt"
+  );
+  assert_contains(&li.inner_html(), "3 B");
+  assert_contains(&li.inner_html(), "3 XMR");
+}
+
+#[test]
+fn search_skips_hidden_files_and_escaping_symlinks() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".hidden-foo", "");
+  context.write(".hidden/foo", "");
+  context.write("foo", "");
+  context.write("../foo-outside", "");
+  symlink("../foo-outside", context.files_directory().join("foo-link"));
+  assert_eq!(search_results(&context, "q=foo"), ["/foo"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn search_skips_dangling_symlinks() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("sub/a.txt", "");
+  symlink("missing", context.files_directory().join("dangling"));
+  assert_eq!(search_results(&context, "q=a"), ["/sub/a.txt"]);
+}
+
+#[test]
+fn search_optionally_matches_index_pages() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("albums/.index.md", "# Greatest Hits");
+  context.write("albums/one.mp3", "");
+  assert!(search_results(&context, "q=greatest").is_empty());
+  assert_eq!(
+    search_results(&context, "q=greatest&index=on"),
+    ["/albums/"]
+  );
+}
+
+#[test]
+fn search_index_is_refreshed_on_changes() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo", "");
+  assert_eq!(search_results(&context, "q=foo"), ["/foo"]);
+  context.write("foobar", "");
+  fs::remove_file(context.files_directory().join("foo")).unwrap();
+  for _ in 0..100 {
+    if search_results(&context, "q=foo") == ["/foobar"] {
+      return;
+    }
+    thread::sleep(Duration::from_millis(50));
+  }
+  panic!("search index was not refreshed");
+}
+
+#[test]
+fn listing_has_search_form() {
+  let context = OpuzaTestContext::builder().build();
+  let html = context.html("files/");
+  guard_unwrap!(let &[form] = css_select(&html, "form.search").as_slice());
+  assert_eq!(form.value().attr("action").unwrap(), "/search");
+}
+
+fn json_listing(context: &OpuzaTestContext, url: &str) -> serde_json::Value {
+  let response = context.get(url);
+  assert_eq!(
+    response.headers().get(header::CONTENT_TYPE).unwrap(),
+    "application/json"
+  );
+  serde_json::from_str(&response.text().unwrap()).unwrap()
+}
+
+#[test]
+fn listing_is_served_as_json_with_format_parameter() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo.txt", "abc");
+  context.create_dir_all("bar");
+  let listing = json_listing(&context, "files/?format=json");
+  assert_eq!(listing["path"], "/");
+  assert_eq!(listing["index"], serde_json::Value::Null);
+
+  let entries = listing["entries"].as_array().unwrap();
+  assert_eq!(entries.len(), 2);
+
+  assert_eq!(entries[0]["name"], "bar");
+  assert_eq!(entries[0]["type"], "directory");
+  assert_eq!(entries[0]["size"], serde_json::Value::Null);
+  assert_eq!(entries[0]["mime_type"], serde_json::Value::Null);
+
+  assert_eq!(entries[1]["name"], "foo.txt");
+  assert_eq!(entries[1]["type"], "file");
+  assert_eq!(entries[1]["size"], 3);
+  assert_eq!(entries[1]["mime_type"], "text/plain");
+  assert_eq!(entries[1]["paid"], false);
+  assert_eq!(entries[1]["price"], serde_json::Value::Null);
+  assert!(entries[1]["modified"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn listing_is_served_as_json_when_accepted() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo", "");
+  let response = reqwest::blocking::Client::new()
+    .get(context.files_url().clone())
+    .header(header::ACCEPT, "text/plain;q=0.5, application/json")
+    .send()
+    .unwrap();
+  assert_eq!(response.status(), StatusCode::OK);
+  assert_eq!(
+    response.headers().get(header::CONTENT_TYPE).unwrap(),
+    "application/json"
+  );
+  assert_eq!(response.headers().get(header::VARY).unwrap(), "accept");
+  let listing: serde_json::Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+  assert_eq!(listing["entries"][0]["name"], "foo");
+}
+
+#[test]
+fn format_parameter_overrides_accept_header() {
+  let context = OpuzaTestContext::builder().build();
+  let response = reqwest::blocking::Client::new()
+    .get(context.files_url().join("?format=html").unwrap())
+    .header(header::ACCEPT, "application/json")
+    .send()
+    .unwrap();
+  assert_eq!(
+    response.headers().get(header::CONTENT_TYPE).unwrap(),
+    "text/html"
+  );
+}
+
+#[test]
+fn json_listing_includes_prices_and_rendered_index() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "{paid: true, base-price: 2 XMR}");
+  context.write(".index.md", "# Hello");
+  context.write("foo", "");
+  let listing = json_listing(&context, "files/?format=json");
+  assert_eq!(listing["index"], "<h1>Hello</h1>\n");
+  assert_eq!(listing["entries"][0]["paid"], true);
+  assert_eq!(listing["entries"][0]["price"], 2_000_000_000_000_u64);
+}
+
 #[test]
 fn configure_files_directory() {
   let context = OpuzaTestContext::builder()
@@ -908,6 +1460,69 @@ fn configure_port() {
   );
 }
 
+/// The lines `child` writes to stderr, read on another thread so that
+/// waiting for them can time out.
+fn stderr_lines(child: &mut Child) -> mpsc::Receiver<String> {
+  let stderr = child.stderr.take().unwrap();
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    for line in BufReader::new(stderr).lines() {
+      if sender.send(line.unwrap()).is_err() {
+        break;
+      }
+    }
+  });
+  receiver
+}
+
+/// Wait for a line that contains `needle`, giving up after ten seconds.
+fn wait_for_line(lines: &mpsc::Receiver<String>, needle: &str) -> bool {
+  let deadline = Instant::now() + Duration::from_secs(10);
+  loop {
+    match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+      Ok(line) if line.contains(needle) => return true,
+      Ok(_) => {}
+      Err(_) => return false,
+    }
+  }
+}
+
+#[test]
+fn acme_errors_are_logged() {
+  let tempdir = tempfile::tempdir().unwrap();
+  let unused_port = TcpListener::bind("127.0.0.1:0")
+    .unwrap()
+    .local_addr()
+    .unwrap()
+    .port();
+
+  let mut child = Command::new(executable_path("opuza"))
+    .arg("--directory")
+    .arg(tempdir.path())
+    .arg("--https-port=0")
+    .arg("--acme-cache-directory")
+    .arg(tempdir.path().join("cache"))
+    .arg("--acme-domain=localhost")
+    .arg(format!(
+      "--acme-directory-url=https://127.0.0.1:{}/directory",
+      unused_port
+    ))
+    .env("RUST_LOG", "error")
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+
+  let logged = wait_for_line(
+    &stderr_lines(&mut child),
+    "ACME certificate management for localhost failed",
+  );
+
+  child.kill().unwrap();
+  child.wait().unwrap();
+
+  assert!(logged);
+}
+
 #[test]
 fn creates_cert_cache_directory_if_it_doesnt_exist() {
   let context = OpuzaTestContext::builder()
@@ -964,3 +1579,574 @@ fn errors_printed_in_red_and_bold() {
 
   assert_contains(stderr, "\u{1b}[31merror\u{1b}[0m\u{1b}[1m: ");
 }
+
+#[cfg(unix)]
+fn terminate(context: OpuzaTestContext) -> (std::process::ExitStatus, String) {
+  use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+  };
+
+  kill(Pid::from_raw(context.pid() as i32), Signal::SIGTERM).unwrap();
+  context.wait()
+}
+
+#[test]
+#[cfg(unix)]
+fn idle_server_exits_cleanly_on_sigterm() {
+  let context = OpuzaTestContext::builder().build();
+  let (status, stderr) = terminate(context);
+  assert!(status.success(), "{}", stderr);
+  assert_contains(
+    &stderr,
+    "Shutting down, waiting up to 30 seconds for downloads to finish",
+  );
+}
+
+#[test]
+#[cfg(unix)]
+fn downloads_in_progress_finish_after_sigterm() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(
+    ".opuza.yaml",
+    "bandwidth-limit:\n  free:\n    connection: 16 KiB\n",
+  );
+  context.write("foo", &"x".repeat(48 * 1024));
+
+  let url = context.files_url().join("foo").unwrap();
+  let download = thread::spawn(move || reqwest::blocking::get(url).unwrap().text().unwrap());
+
+  thread::sleep(Duration::from_millis(500));
+
+  let (status, stderr) = terminate(context);
+  assert!(status.success(), "{}", stderr);
+  assert_eq!(download.join().unwrap().len(), 48 * 1024);
+}
+
+#[test]
+#[cfg(unix)]
+fn downloads_are_cut_off_after_shutdown_timeout() {
+  let context = OpuzaTestContext::builder()
+    .args(&["--shutdown-timeout=1"])
+    .build();
+  context.write(
+    ".opuza.yaml",
+    "bandwidth-limit:\n  free:\n    connection: 16 KiB\n",
+  );
+  context.write("foo", &"x".repeat(1024 * 1024));
+
+  let url = context.files_url().join("foo").unwrap();
+  let download = thread::spawn(move || reqwest::blocking::get(url).unwrap().text());
+
+  thread::sleep(Duration::from_millis(500));
+
+  let start = std::time::Instant::now();
+  let (status, stderr) = terminate(context);
+  assert!(status.success(), "{}", stderr);
+  assert!(start.elapsed() < Duration::from_secs(10));
+  assert_contains(
+    &stderr,
+    "Shutdown timeout elapsed, closing remaining connections",
+  );
+  assert!(download.join().unwrap().is_err());
+}
+
+fn write_image(context: &OpuzaTestContext, path: &str, width: u32, height: u32) {
+  // A checkerboard of 4×4 pixel squares, which blurs to gray when reduced
+  // to a low resolution.
+  image::RgbImage::from_fn(width, height, |x, y| {
+    if (x / 4 + y / 4) % 2 == 0 {
+      image::Rgb([0, 0, 0])
+    } else {
+      image::Rgb([255, 255, 255])
+    }
+  })
+  .save(context.files_directory().join(path))
+  .unwrap();
+}
+
+fn thumbnail(context: &OpuzaTestContext, url: &str) -> image::RgbImage {
+  let response = context.get(url);
+  assert_eq!(
+    response.headers().get(header::CONTENT_TYPE).unwrap(),
+    "image/jpeg"
+  );
+  image::load_from_memory(&response.bytes().unwrap())
+    .unwrap()
+    .to_rgb8()
+}
+
+#[test]
+fn gallery_view_shows_thumbnails_and_icons() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "view: gallery");
+  write_image(&context, "photo one.png", 16, 16);
+  context.write("notes.txt", "");
+  context.create_dir_all("album");
+
+  let html = context.html("files/");
+  assert!(css_select(&html, ".listing").is_empty());
+  let images = css_select(&html, ".gallery a.view img");
+  assert_eq!(images.len(), 1);
+  assert_eq!(
+    images[0].value().attr("src").unwrap(),
+    "photo%20one.png?thumbnail"
+  );
+  assert_eq!(images[0].value().attr("loading").unwrap(), "lazy");
+  assert_eq!(css_select(&html, ".gallery a.view .icon").len(), 2);
+  assert_eq!(
+    css_select(&html, ".gallery .filename")
+      .into_iter()
+      .map(|span| span.inner_html())
+      .collect::<Vec<String>>(),
+    ["album/", "notes.txt", "photo one.png"]
+  );
+}
+
+#[test]
+fn thumbnails_are_scaled_down() {
+  let context = OpuzaTestContext::builder().build();
+  write_image(&context, "wide.png", 1024, 512);
+  write_image(&context, "small.png", 16, 8);
+  let wide = thumbnail(&context, "files/wide.png?thumbnail");
+  assert_eq!(wide.dimensions(), (256, 128));
+  assert!(wide.pixels().any(|pixel| pixel.0[0] < 64));
+  let small = thumbnail(&context, "files/small.png?thumbnail");
+  assert_eq!(small.dimensions(), (16, 8));
+}
+
+#[test]
+fn paid_images_only_get_low_resolution_thumbnails() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(".opuza.yaml", "{paid: true, base-price: 10 XMR}");
+  write_image(&context, "photo.png", 256, 256);
+
+  let preview = thumbnail(&context, "files/photo.png?thumbnail");
+  assert_eq!(preview.dimensions(), (256, 256));
+  assert!(
+    preview
+      .pixels()
+      .all(|pixel| (64..192).contains(&pixel.0[0])),
+    "checkerboard should be blurred to gray"
+  );
+
+  let html = context.html("files/photo.png?preview");
+  guard_unwrap!(let &[img] = css_select(&html, ".preview img.thumbnail").as_slice());
+  assert_eq!(img.value().attr("src").unwrap(), "photo.png?thumbnail");
+}
+
+#[test]
+fn paid_image_thumbnails_can_be_watermarked() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(
+    ".opuza.yaml",
+    "{paid: true, base-price: 10 XMR, preview-watermark: true}",
+  );
+  write_image(&context, "photo.png", 256, 256);
+  let preview = thumbnail(&context, "files/photo.png?thumbnail");
+  assert!(preview.pixels().any(|pixel| pixel.0[0] > 192));
+}
+
+#[test]
+fn thumbnails_of_other_files_are_not_found() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("notes.txt", "");
+  assert_eq!(
+    context.status("files/notes.txt?thumbnail"),
+    StatusCode::NOT_FOUND
+  );
+}
+
+#[test]
+fn thumbnails_are_cached() {
+  let context = OpuzaTestContext::builder()
+    .args(&["--thumbnail-cache-directory=thumbnails"])
+    .build();
+  write_image(&context, "photo.png", 64, 64);
+  let first = context.get("files/photo.png?thumbnail").bytes().unwrap();
+  let cached = fs::read_dir(context.current_dir().join("thumbnails"))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .collect::<Vec<_>>();
+  assert_eq!(cached.len(), 1);
+  assert_eq!(fs::read(&cached[0]).unwrap(), first);
+  assert_eq!(
+    context.get("files/photo.png?thumbnail").bytes().unwrap(),
+    first
+  );
+}
+
+#[test]
+fn thumbnail_cache_directory_may_not_be_inside_served_directory() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  fs::create_dir(tempdir.path().join("www")).unwrap();
+  let output = Command::new(executable_path("opuza"))
+    .arg("--directory=www")
+    .arg("--http-port=0")
+    .arg("--thumbnail-cache-directory=www/thumbnails")
+    .current_dir(tempdir.path())
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert_contains(
+    str::from_utf8(&output.stderr).unwrap(),
+    "Thumbnail cache directory `",
+  );
+}
+
+#[test]
+fn markdown_files_are_rendered() {
+  let context = OpuzaTestContext::builder().build();
+  context.write(
+    "docs/notes.md",
+    "# Notes\n\n~~old~~ [next](next%20page.md) ![diagram](images/diagram.png)",
+  );
+  let html = context.html("files/docs/notes.md");
+  guard_unwrap!(let &[h1] = css_select(&html, "article.markdown h1").as_slice());
+  assert_eq!(h1.inner_html(), "Notes");
+  assert_eq!(css_select(&html, "article.markdown del").len(), 1);
+  guard_unwrap!(let &[a] = css_select(&html, "article.markdown a").as_slice());
+  assert_eq!(a.value().attr("href").unwrap(), "next%20page.md");
+  guard_unwrap!(let &[img] = css_select(&html, "article.markdown img").as_slice());
+  assert_eq!(img.value().attr("src").unwrap(), "images/diagram.png");
+  guard_unwrap!(let &[download] = css_select(&html, ".links a[download]").as_slice());
+  assert_eq!(download.value().attr("href").unwrap(), "notes.md?raw");
+}
+
+#[test]
+fn raw_markdown_is_served_with_raw_parameter() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("notes.md", "# Notes");
+  assert_eq!(context.text("files/notes.md?raw"), "# Notes");
+  let html = context.html("files/");
+  guard_unwrap!(let &[download] = css_select(&html, ".listing a[download]").as_slice());
+  assert_eq!(download.value().attr("href").unwrap(), "notes.md?raw");
+}
+
+fn breadcrumbs(html: &Html) -> Vec<(String, Option<String>)> {
+  css_select(html, ".breadcrumbs > :not(.parent)")
+    .into_iter()
+    .map(|element| {
+      (
+        element.inner_html(),
+        element.value().attr("href").map(str::to_owned),
+      )
+    })
+    .collect()
+}
+
+#[test]
+fn listings_have_breadcrumbs() {
+  let context = OpuzaTestContext::builder().build();
+  context.create_dir_all("music/spa ce/album");
+  assert_eq!(
+    breadcrumbs(&context.html("files/music/spa%20ce/album/")),
+    [
+      ("/".into(), Some("/files/".into())),
+      ("music/".into(), Some("/files/music/".into())),
+      ("spa ce/".into(), Some("/files/music/spa%20ce/".into())),
+      ("album/".into(), None),
+    ]
+  );
+  assert_eq!(breadcrumbs(&context.html("files/")), [("/".into(), None)]);
+}
+
+#[test]
+fn breadcrumbs_link_to_parent_directory() {
+  let context = OpuzaTestContext::builder().build();
+  context.create_dir_all("foo/bar");
+  let html = context.html("files/foo/bar/");
+  guard_unwrap!(let &[parent] = css_select(&html, ".breadcrumbs a.parent").as_slice());
+  assert_eq!(parent.value().attr("href").unwrap(), "/files/foo/");
+  let html = context.html("files/foo/");
+  guard_unwrap!(let &[parent] = css_select(&html, ".breadcrumbs a.parent").as_slice());
+  assert_eq!(parent.value().attr("href").unwrap(), "/files/");
+  assert!(css_select(&context.html("files/"), ".breadcrumbs a.parent").is_empty());
+}
+
+#[test]
+fn markdown_pages_have_breadcrumbs() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("docs/notes.md", "# Notes");
+  assert_eq!(
+    breadcrumbs(&context.html("files/docs/notes.md")),
+    [
+      ("/".into(), Some("/files/".into())),
+      ("docs/".into(), Some("/files/docs/".into())),
+      ("notes.md".into(), None),
+    ]
+  );
+}
+
+#[test]
+fn theme_static_files_override_embedded_assets() {
+  let context = OpuzaTestContext::builder()
+    .write("theme/static/index.css", "body { color: red; }")
+    .write("theme/static/extra.txt", "extra")
+    .args(&["--theme-directory=theme"])
+    .build();
+  assert_eq!(context.text("static/index.css"), "body { color: red; }");
+  assert_eq!(context.text("static/extra.txt"), "extra");
+  assert_eq!(context.status("static/index.js"), StatusCode::OK);
+  assert_eq!(
+    context.status("static/..%2Fheader.html"),
+    StatusCode::NOT_FOUND
+  );
+}
+
+#[test]
+fn theme_logo_is_shown_in_header() {
+  let context = OpuzaTestContext::builder()
+    .write("theme/static/logo.svg", "<svg></svg>")
+    .args(&["--theme-directory=theme"])
+    .build();
+  let html = context.html("files/");
+  guard_unwrap!(let &[logo] = css_select(&html, "header img.logo").as_slice());
+  assert_eq!(logo.value().attr("src").unwrap(), "/static/logo.svg");
+}
+
+#[test]
+fn theme_footer_and_header_templates() {
+  let context = OpuzaTestContext::builder()
+    .write(
+      "theme/header.html",
+      "<span class=shop>Shop: {{ title }}</span>",
+    )
+    .write("theme/footer.html", "<span class=imprint>Imprint</span>")
+    .args(&["--theme-directory=theme"])
+    .build();
+  context.write("<b>/foo", "");
+  let html = context.html("files/%3Cb%3E/");
+  guard_unwrap!(let &[shop] = css_select(&html, "header .shop").as_slice());
+  assert_eq!(shop.inner_html(), "Shop: /&lt;b&gt;/");
+  assert_eq!(css_select(&html, "footer .imprint").len(), 1);
+  assert_not_contains(&html.root_element().html(), "Powered by");
+}
+
+#[test]
+fn theme_page_template_replaces_layout() {
+  let context = OpuzaTestContext::builder()
+    .write(
+      "theme/page.html",
+      "<html><head>{{ head }}<title>{{ title }} · Shop</title></head>\
+       <body><div class=content>{{ body }}</div><div class=bottom>{{ footer }}</div></body></html>",
+    )
+    .args(&["--theme-directory=theme"])
+    .build();
+  context.write("foo", "");
+  let html = context.html("files/");
+  guard_unwrap!(let &[title] = css_select(&html, "title").as_slice());
+  assert_eq!(title.inner_html(), "/ · Shop");
+  assert_eq!(css_select(&html, "head link[rel=stylesheet]").len(), 1);
+  assert_eq!(css_select(&html, ".content .listing").len(), 1);
+  assert_contains(&html.root_element().html(), "Powered by");
+  assert!(css_select(&html, "main").is_empty());
+}
+
+#[test]
+fn invalid_theme_templates_abort_startup() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  fs::create_dir(tempdir.path().join("www")).unwrap();
+  fs::create_dir(tempdir.path().join("theme")).unwrap();
+  fs::write(tempdir.path().join("theme/footer.html"), "{{ body }}").unwrap();
+  let output = Command::new(executable_path("opuza"))
+    .arg("--directory=www")
+    .arg("--http-port=0")
+    .arg("--theme-directory=theme")
+    .current_dir(tempdir.path())
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert_contains(
+    str::from_utf8(&output.stderr).unwrap(),
+    "unknown variable `body`, expected one of `title`",
+  );
+}
+
+fn get_with_language(context: &OpuzaTestContext, path: &str, language: &str) -> (String, String) {
+  let response = reqwest::blocking::Client::new()
+    .get(context.base_url().join(path).unwrap())
+    .header(header::ACCEPT_LANGUAGE, language)
+    .send()
+    .unwrap();
+  let vary = response
+    .headers()
+    .get_all(header::VARY)
+    .iter()
+    .map(|value| value.to_str().unwrap().to_owned())
+    .collect::<Vec<String>>()
+    .join(", ");
+  (vary, response.text().unwrap())
+}
+
+#[test]
+fn listings_follow_accept_language() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("paid/.opuza.yaml", "{paid: true, base-price: 1000.5 XMR}");
+  context.write("paid/foo", &"x".repeat(1536));
+  let (vary, body) = get_with_language(&context, "files/paid/", "fr, de-DE;q=0.9, en;q=0.5");
+  assert_contains(&vary, "accept-language");
+  let html = Html::parse_document(&body);
+  guard_unwrap!(let &[root] = css_select(&html, "html").as_slice());
+  assert_eq!(root.value().attr("lang").unwrap(), "de");
+  assert_contains(&body, "Sortieren nach");
+  assert_contains(&body, "Kaufen");
+  assert_contains(&body, "1,5 KiB");
+  assert_contains(&body, "1.000,5 XMR");
+  assert_not_contains(&body, "Sort by");
+}
+
+#[test]
+fn lang_parameter_overrides_accept_language() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("foo.md", "# Hola");
+  let (_, body) = get_with_language(&context, "files/foo.md?lang=es", "de");
+  assert_contains(&body, "Ver código fuente");
+  assert_contains(&body, "Descargar");
+}
+
+#[test]
+fn directories_can_set_a_default_language() {
+  let context = OpuzaTestContext::builder().build();
+  context.write("tienda/.opuza.yaml", "lang: es");
+  context.write("tienda/foo", "");
+  assert_contains(&context.text("files/tienda/"), "Ordenar por");
+  assert_contains(&context.text("files/"), "Sort by");
+  let (_, body) = get_with_language(&context, "files/tienda/", "en");
+  assert_contains(&body, "Sort by");
+}
+
+#[test]
+fn truncated_search_results_are_localized() {
+  let context = OpuzaTestContext::builder().build();
+  for i in 0..101 {
+    context.write(&format!("foo{}", i), "");
+  }
+  let (_, body) = get_with_language(&context, "search?q=foo", "de");
+  let html = Html::parse_document(&body);
+  guard_unwrap!(let &[summary] = css_select(&html, ".search-summary").as_slice());
+  assert_eq!(
+    summary.inner_html(),
+    "Es werden die ersten 100 Ergebnisse angezeigt."
+  );
+}
+
+#[test]
+fn error_pages_follow_accept_language() {
+  let context = OpuzaTestContext::builder().build();
+  let (_, body) = get_with_language(&context, "files/missing", "es");
+  assert_contains(&body, "404 No encontrado");
+}
+
+#[test]
+fn tls_key_must_match_certificate() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  fs::create_dir(tempdir.path().join("www")).unwrap();
+  let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+  let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+  fs::write(
+    tempdir.path().join("cert.pem"),
+    certificate.serialize_pem().unwrap(),
+  )
+  .unwrap();
+  fs::write(
+    tempdir.path().join("key.pem"),
+    other.serialize_private_key_pem(),
+  )
+  .unwrap();
+  let output = Command::new(executable_path("opuza"))
+    .arg("--directory=www")
+    .arg("--https-port=0")
+    .arg("--tls-certificate=cert.pem")
+    .arg("--tls-key=key.pem")
+    .current_dir(tempdir.path())
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert_contains(
+    str::from_utf8(&output.stderr).unwrap(),
+    &format!(
+      "TLS key `{}` does not belong to the first certificate in `{}`",
+      tempdir.path().join("key.pem").display(),
+      tempdir.path().join("cert.pem").display(),
+    ),
+  );
+}
+
+#[test]
+#[cfg(unix)]
+fn sighup_reloads_tls_certificate() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  fs::create_dir(tempdir.path().join("www")).unwrap();
+  let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+  fs::write(
+    tempdir.path().join("cert.pem"),
+    certificate.serialize_pem().unwrap(),
+  )
+  .unwrap();
+  fs::write(
+    tempdir.path().join("key.pem"),
+    certificate.serialize_private_key_pem(),
+  )
+  .unwrap();
+
+  let mut child = Command::new(executable_path("opuza"))
+    .arg("--directory=www")
+    .arg("--https-port=0")
+    .arg("--tls-certificate=cert.pem")
+    .arg("--tls-key=key.pem")
+    .current_dir(tempdir.path())
+    .env("RUST_LOG", "info")
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+
+  let lines = stderr_lines(&mut child);
+
+  let listening = wait_for_line(&lines, "Listening for HTTPS connections");
+  if listening {
+    nix::sys::signal::kill(
+      nix::unistd::Pid::from_raw(child.id() as i32),
+      nix::sys::signal::Signal::SIGHUP,
+    )
+    .unwrap();
+  }
+
+  let reloaded = listening && wait_for_line(&lines, "Reloaded TLS certificate");
+  let running = child.try_wait().unwrap().is_none();
+
+  child.kill().unwrap();
+  child.wait().unwrap();
+
+  assert!(listening);
+  assert!(reloaded);
+  assert!(running);
+}
+
+#[test]
+fn tls_certificate_file_must_contain_certificates() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  fs::create_dir(tempdir.path().join("www")).unwrap();
+  fs::write(tempdir.path().join("cert.pem"), "").unwrap();
+  fs::write(tempdir.path().join("key.pem"), "").unwrap();
+  let output = Command::new(executable_path("opuza"))
+    .arg("--directory=www")
+    .arg("--https-port=0")
+    .arg("--tls-certificate=cert.pem")
+    .arg("--tls-key=key.pem")
+    .current_dir(tempdir.path())
+    .output()
+    .unwrap();
+
+  assert!(!output.status.success());
+  assert_contains(
+    str::from_utf8(&output.stderr).unwrap(),
+    &format!(
+      "No certificates found in `{}`",
+      tempdir.path().join("cert.pem").display()
+    ),
+  );
+}